@@ -0,0 +1,69 @@
+// Which camera recorded a file. GoPros number their videos independently, so two cameras on the same
+// shoot can both record a video 0042. Telling them apart needs the camera's identity, which is read
+// from (in order):
+//
+// - CASN (camera serial number) in the GPMF box in udta, written by HERO8 and newer
+// - the CAME box in udta, a 16 byte camera ID written by HERO5 to HERO7
+// - MISC/version.txt on the card the file is on, which has the serial number of the camera that
+//   formatted it
+//
+// The rest of what's known about the camera is in metadata.rs.
+
+use std::fs;
+use std::path::Path;
+
+use crate::gpmf;
+use crate::mp4::Movie;
+use crate::telemetry::find_entry;
+
+/// The camera's serial number, or None if it can't be found
+pub fn read_camera_identity(path: &Path) -> Option<String> {
+    camera_identity(path, Movie::read(path).ok().as_ref())
+}
+
+/// Like read_camera_identity, for a file whose `moov` has already been read
+pub fn camera_identity(path: &Path, movie: Option<&Movie>) -> Option<String> {
+    let from_udta = movie.and_then(|movie| {
+        let udta_gpmf = movie
+            .moov
+            .find(&[b"udta", b"GPMF"])
+            .and_then(|gpmf| gpmf::parse(&gpmf.payload).ok())
+            .unwrap_or_default();
+        let serial = find_entry(&udta_gpmf, b"CASN")
+            .map(|casn| casn.as_string().trim().to_string())
+            .filter(|serial| !serial.is_empty());
+        serial.or_else(|| {
+            let came = movie.moov.find(&[b"udta", b"CAME"])?;
+            match came.payload.iter().all(|byte| *byte == 0) {
+                true => None,
+                // The full ID is 32 hex characters, which would make for unwieldy file names
+                false => Some(
+                    came.payload
+                        .iter()
+                        .take(4)
+                        .map(|b| format!("{:02X}", b))
+                        .collect(),
+                ),
+            }
+        })
+    });
+    from_udta.or_else(|| {
+        path.ancestors()
+            .skip(1)
+            .take(3)
+            .find_map(|dir| version_txt_serial(&dir.join("MISC").join("version.txt")))
+    })
+}
+
+/// The "camera serial number" from a card's version.txt. It's almost JSON, but some firmware
+/// versions leave a trailing comma, so the field is picked out by hand.
+fn version_txt_serial(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    let (_, rest) = contents.split_once("\"camera serial number\"")?;
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let serial = rest.strip_prefix('"')?.split('"').next()?.trim();
+    match serial.is_empty() {
+        true => None,
+        false => Some(serial.to_string()),
+    }
+}
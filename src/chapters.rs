@@ -0,0 +1,518 @@
+// Writes chapter markers into an MP4, in both of the forms players look for:
+//   - a QuickTime chapter track: a disabled text track with one sample per chapter, referenced from
+//     the video track by a `chap` track reference. QuickTime, Apple's players and ffmpeg read these.
+//   - a Nero `chpl` box in moov/udta, which VLC, mpv and most other players read.
+//
+// Only the moov is rewritten, plus a small mdat holding the chapter titles, so the audio and video
+// data isn't touched or moved.
+
+use std::io::Error;
+use std::path::Path;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::gopro::GoProChapteredVideoFile;
+use crate::mp4::{rewrite_moov, ByteReader, MoovLocation, Movie, Mp4Box, Track};
+
+// Chapter track samples are in milliseconds
+const CHAPTER_TIMESCALE: u32 = 1000;
+
+// chpl stores its entry count in a single byte
+const MAX_CHPL_ENTRIES: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChapterMarker {
+    /// Seconds from the start of the video
+    pub start: f64,
+    pub title: String,
+}
+
+/// One marker where each source chapter starts in the merged video, titled with its file name
+pub fn chapter_boundary_markers(
+    chapters: &[GoProChapteredVideoFile],
+) -> Result<Vec<ChapterMarker>, Error> {
+    let mut markers = Vec::new();
+    let mut start = 0.0;
+    for chapter in chapters {
+        markers.push(ChapterMarker {
+            start,
+            title: chapter
+                .abs_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        });
+        start += Movie::read(&chapter.abs_path)?.duration_secs();
+    }
+    Ok(markers)
+}
+
+/// Sorts markers, drops any outside the video, and adds a "Start" chapter if the first marker
+/// isn't at 0. Players expect the chapters to cover the whole video.
+fn normalize_markers(markers: &[ChapterMarker], duration: f64) -> Vec<ChapterMarker> {
+    let mut markers: Vec<ChapterMarker> = markers
+        .iter()
+        .filter(|m| m.start >= 0.0 && m.start < duration)
+        .cloned()
+        .collect();
+    markers.sort_by(|a, b| a.start.total_cmp(&b.start));
+    // Two markers in the same millisecond can't both be chapter track samples
+    markers.dedup_by(|later, earlier| (later.start - earlier.start).abs() < 0.001);
+    if markers.first().is_none_or(|m| m.start >= 0.001) {
+        markers.insert(
+            0,
+            ChapterMarker {
+                start: 0.0,
+                title: "Start".to_string(),
+            },
+        );
+    }
+    markers
+}
+
+/// Writes (or replaces) the chapter markers of an MP4 file
+pub fn write_chapter_markers(path: &Path, markers: &[ChapterMarker]) -> Result<(), Error> {
+    let location = MoovLocation::find(path)?;
+    let movie = Movie::read(path)?;
+    let markers = normalize_markers(markers, movie.duration_secs());
+    let mut moov = movie.moov.clone();
+    remove_existing_chapters(&mut moov);
+
+    // Nero chapters
+    let chpl = build_chpl(&markers);
+    match moov.child_mut(b"udta") {
+        Some(udta) => udta.children.push(chpl),
+        None => moov.children.push(Mp4Box::container(b"udta", vec![chpl])),
+    }
+
+    // QuickTime chapter track, with the titles as its samples
+    let mut sample_data = Vec::new();
+    let mut sample_sizes = Vec::new();
+    for marker in &markers {
+        let sample = text_sample(&marker.title);
+        sample_sizes.push(sample.len() as u32);
+        sample_data.extend_from_slice(&sample);
+    }
+    let track_id = take_next_track_id(&mut moov)?;
+    let duration_ms = (movie.duration_secs() * CHAPTER_TIMESCALE as f64).round() as u64;
+    let chapter_trak = build_chapter_trak(
+        track_id,
+        &markers,
+        &sample_sizes,
+        location.appended_data_offset(),
+        duration_ms,
+        movie.duration,
+    );
+
+    // Chapters belong to the video track. Without one, every track gets them.
+    let video_track_id = movie.video_track().and_then(|t| t.track_id());
+    for trak in moov.children.iter_mut().filter(|b| &b.typ == b"trak") {
+        let id = Track { trak }.track_id();
+        if video_track_id.is_none() || id == video_track_id {
+            add_chapter_reference(trak, track_id);
+        }
+    }
+    moov.children.push(chapter_trak);
+
+    rewrite_moov(path, &location, &moov, &sample_data)
+}
+
+/// Removes chapter tracks, the references to them and any chpl, so rewriting chapters doesn't
+/// stack a second set on top of the first
+fn remove_existing_chapters(moov: &mut Mp4Box) {
+    let mut chapter_track_ids = Vec::new();
+    for trak in moov.children.iter_mut().filter(|b| &b.typ == b"trak") {
+        if let Some(tref) = trak.child_mut(b"tref") {
+            for chap in tref.children_of_type(b"chap") {
+                chapter_track_ids.extend(
+                    chap.payload
+                        .chunks_exact(4)
+                        .map(|id| u32::from_be_bytes(id.try_into().unwrap())),
+                );
+            }
+            tref.children.retain(|c| &c.typ != b"chap");
+        }
+        trak.children
+            .retain(|c| !(&c.typ == b"tref" && c.children.is_empty()));
+    }
+    moov.children.retain(|b| {
+        &b.typ != b"trak"
+            || !Track { trak: b }
+                .track_id()
+                .is_some_and(|id| chapter_track_ids.contains(&id))
+    });
+    if let Some(udta) = moov.child_mut(b"udta") {
+        udta.children.retain(|c| &c.typ != b"chpl");
+    }
+}
+
+/// Returns mvhd's next track ID and bumps it. It's the last field of mvhd in both versions.
+fn take_next_track_id(moov: &mut Mp4Box) -> Result<u32, Error> {
+    let mvhd = moov
+        .child_mut(b"mvhd")
+        .filter(|mvhd| mvhd.payload.len() >= 4)
+        .ok_or_else(|| Error::other("No mvhd box"))?;
+    let field = mvhd.payload.len() - 4;
+    let next = u32::from_be_bytes(mvhd.payload[field..].try_into().unwrap()).max(1);
+    mvhd.payload[field..].copy_from_slice(&(next + 1).to_be_bytes());
+    Ok(next)
+}
+
+fn add_chapter_reference(trak: &mut Mp4Box, chapter_track_id: u32) {
+    let chap = Mp4Box::new(b"chap", chapter_track_id.to_be_bytes().to_vec());
+    match trak.child_mut(b"tref") {
+        Some(tref) => tref.children.push(chap),
+        None => {
+            // tref goes right after tkhd
+            let position = trak
+                .children
+                .iter()
+                .position(|c| &c.typ == b"tkhd")
+                .map_or(0, |i| i + 1);
+            trak.children
+                .insert(position, Mp4Box::container(b"tref", vec![chap]));
+        }
+    }
+}
+
+/// The markers that start in [start, end), moved so `start` is 0. The marker that was running at
+/// `start` becomes the first one.
+pub fn markers_in_range(markers: &[ChapterMarker], start: f64, end: f64) -> Vec<ChapterMarker> {
+    let mut in_range: Vec<ChapterMarker> = markers
+        .iter()
+        .filter(|m| m.start >= start && m.start < end)
+        .map(|m| ChapterMarker {
+            start: m.start - start,
+            title: m.title.clone(),
+        })
+        .collect();
+    if in_range.first().is_none_or(|m| m.start > 0.0) {
+        if let Some(running) = markers.iter().rfind(|m| m.start < start) {
+            in_range.insert(
+                0,
+                ChapterMarker {
+                    start: 0.0,
+                    title: running.title.clone(),
+                },
+            );
+        }
+    }
+    in_range
+}
+
+pub fn read_chpl(chpl: &Mp4Box) -> Vec<ChapterMarker> {
+    let mut reader = ByteReader::new(&chpl.payload);
+    let mut markers = Vec::new();
+    let header = reader.read_u8().and_then(|version| {
+        reader.skip(if version == 1 { 7 } else { 3 })?;
+        reader.read_u8()
+    });
+    for _ in 0..header.unwrap_or(0) {
+        let marker = (|| {
+            let start = reader.read_u64()? as f64 / 10_000_000.0;
+            let length = reader.read_u8()? as usize;
+            let title = String::from_utf8_lossy(reader.read_bytes(length)?).to_string();
+            Ok::<_, Error>(ChapterMarker { start, title })
+        })();
+        match marker {
+            Ok(marker) => markers.push(marker),
+            Err(_) => break,
+        }
+    }
+    markers
+}
+
+/// chpl version 1: a reserved u32, a u8 count, then a u64 start time in 100ns units and a
+/// length-prefixed title per chapter. Only the first 255 markers fit.
+pub fn build_chpl(markers: &[ChapterMarker]) -> Mp4Box {
+    if markers.len() > MAX_CHPL_ENTRIES {
+        warn!(
+            "Only the first {} of {} chapter markers fit in the Nero chapters VLC and mpv read, the \
+             QuickTime chapter track has all of them",
+            MAX_CHPL_ENTRIES,
+            markers.len()
+        );
+    }
+    let markers = &markers[..markers.len().min(MAX_CHPL_ENTRIES)];
+    let mut payload = vec![1, 0, 0, 0, 0, 0, 0, 0, markers.len() as u8];
+    for marker in markers {
+        let title = truncate_utf8(&marker.title, u8::MAX as usize);
+        payload.extend_from_slice(&((marker.start * 10_000_000.0).round() as u64).to_be_bytes());
+        payload.push(title.len() as u8);
+        payload.extend_from_slice(title.as_bytes());
+    }
+    Mp4Box::new(b"chpl", payload)
+}
+
+fn truncate_utf8(text: &str, max_len: usize) -> &str {
+    let mut end = text.len().min(max_len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// A QuickTime text sample: a length-prefixed string, followed by an `encd` box marking it UTF-8
+fn text_sample(title: &str) -> Vec<u8> {
+    let title = truncate_utf8(title, u16::MAX as usize);
+    let mut sample = (title.len() as u16).to_be_bytes().to_vec();
+    sample.extend_from_slice(title.as_bytes());
+    Mp4Box::new(b"encd", 0x0100u32.to_be_bytes().to_vec()).write_to(&mut sample);
+    sample
+}
+
+fn full_box(typ: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Mp4Box {
+    let mut payload = vec![version];
+    payload.extend_from_slice(&flags.to_be_bytes()[1..]);
+    payload.extend_from_slice(body);
+    Mp4Box::new(typ, payload)
+}
+
+fn be_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+const IDENTITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+fn build_chapter_trak(
+    track_id: u32,
+    markers: &[ChapterMarker],
+    sample_sizes: &[u32],
+    data_offset: u64,
+    duration_ms: u64,
+    movie_duration: u64,
+) -> Mp4Box {
+    // Left disabled (flags 0) so players don't render the titles over the video
+    let mut tkhd = be_bytes(&[
+        0,
+        0,
+        track_id,
+        0,
+        movie_duration.min(u32::MAX as u64) as u32,
+    ]);
+    tkhd.extend_from_slice(&[0; 16]); // reserved, layer, alternate group, volume, reserved
+    tkhd.extend_from_slice(&be_bytes(&IDENTITY_MATRIX));
+    tkhd.extend_from_slice(&[0; 8]); // width, height
+
+    let mut mdhd = be_bytes(&[
+        0,
+        0,
+        CHAPTER_TIMESCALE,
+        duration_ms.min(u32::MAX as u64) as u32,
+    ]);
+    mdhd.extend_from_slice(&[0x55, 0xc4, 0, 0]); // "und", quality
+
+    let mut hdlr = be_bytes(&[0]);
+    hdlr.extend_from_slice(b"text");
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(b"Chapters\0");
+
+    // Base media header: graphics mode, opcolor and balance, plus the `text` media info
+    let mut gmin = vec![0, 0x40];
+    gmin.extend_from_slice(&[0x80, 0, 0x80, 0, 0x80, 0, 0, 0, 0, 0]);
+    let gmhd = Mp4Box::container(
+        b"gmhd",
+        vec![
+            full_box(b"gmin", 0, 0, &gmin),
+            Mp4Box::new(b"text", be_bytes(&IDENTITY_MATRIX)),
+        ],
+    );
+
+    // Sample data lives in this file, not behind a URL
+    let mut dref = full_box(b"dref", 0, 0, &be_bytes(&[1]));
+    dref.children.push(full_box(b"url ", 0, 1, &[]));
+
+    // Text sample description: reserved and data reference index, then display flags,
+    // justification, colors, text box, font and a pascal string font name, all left empty
+    let mut text_entry = vec![0, 0, 0, 0, 0, 0, 0, 1];
+    text_entry.extend_from_slice(&[0; 44]);
+    let mut stsd = full_box(b"stsd", 0, 0, &be_bytes(&[1]));
+    stsd.children.push(Mp4Box::new(b"text", text_entry));
+
+    // Each chapter lasts until the next one starts, and the last until the end of the video
+    let starts_ms: Vec<u64> = markers
+        .iter()
+        .map(|m| (m.start * CHAPTER_TIMESCALE as f64).round() as u64)
+        .collect();
+    let mut stts = be_bytes(&[markers.len() as u32]);
+    for (i, start) in starts_ms.iter().enumerate() {
+        let end = starts_ms.get(i + 1).copied().unwrap_or(duration_ms);
+        stts.extend_from_slice(&be_bytes(&[1, end.saturating_sub(*start).max(1) as u32]));
+    }
+
+    // One sample per chunk, with the chunks back to back in the appended mdat
+    let mut stsz = be_bytes(&[0, sample_sizes.len() as u32]);
+    stsz.extend_from_slice(&be_bytes(sample_sizes));
+    let mut offsets = Vec::new();
+    let mut offset = data_offset;
+    for size in sample_sizes {
+        offsets.push(offset);
+        offset += *size as u64;
+    }
+    let chunk_offsets = match offset > u32::MAX as u64 {
+        true => {
+            let mut co64 = be_bytes(&[offsets.len() as u32]);
+            co64.extend(offsets.iter().flat_map(|o| o.to_be_bytes()));
+            full_box(b"co64", 0, 0, &co64)
+        }
+        false => {
+            let offsets: Vec<u32> = offsets.iter().map(|&o| o as u32).collect();
+            let mut stco = be_bytes(&[offsets.len() as u32]);
+            stco.extend_from_slice(&be_bytes(&offsets));
+            full_box(b"stco", 0, 0, &stco)
+        }
+    };
+
+    let stbl = Mp4Box::container(
+        b"stbl",
+        vec![
+            stsd,
+            full_box(b"stts", 0, 0, &stts),
+            full_box(b"stsc", 0, 0, &be_bytes(&[1, 1, 1, 1])),
+            full_box(b"stsz", 0, 0, &stsz),
+            chunk_offsets,
+        ],
+    );
+    let minf = Mp4Box::container(
+        b"minf",
+        vec![gmhd, Mp4Box::container(b"dinf", vec![dref]), stbl],
+    );
+    let mdia = Mp4Box::container(
+        b"mdia",
+        vec![
+            full_box(b"mdhd", 0, 0, &mdhd),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            minf,
+        ],
+    );
+    Mp4Box::container(b"trak", vec![full_box(b"tkhd", 0, 0, &tkhd), mdia])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{read_sample, read_top_level_boxes};
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    fn marker(start: f64, title: &str) -> ChapterMarker {
+        ChapterMarker {
+            start,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn chpl_round_trips() {
+        let markers = vec![marker(0.0, "Start"), marker(90.5, "GH021234.MP4")];
+        assert_eq!(read_chpl(&build_chpl(&markers)), markers);
+    }
+
+    #[test]
+    fn chpl_keeps_the_first_255_markers() {
+        let markers: Vec<ChapterMarker> = (0..300).map(|i| marker(i as f64, "HiLight")).collect();
+        let chpl = build_chpl(&markers);
+        assert_eq!(chpl.payload[8], 255);
+        assert_eq!(read_chpl(&chpl), markers[..255]);
+    }
+
+    /// The titles in the chapter track, read through its sample table
+    fn chapter_track_titles(path: &Path) -> Vec<String> {
+        let movie = Movie::read(path).unwrap();
+        let tracks = movie.tracks();
+        let chapter_tracks: Vec<_> = tracks
+            .iter()
+            .filter(|t| t.handler_type() == Some(*b"text"))
+            .collect();
+        assert_eq!(chapter_tracks.len(), 1);
+        let mut reader = File::open(path).unwrap();
+        chapter_tracks[0]
+            .samples()
+            .unwrap()
+            .iter()
+            .map(|sample| {
+                let data = read_sample(&mut reader, sample).unwrap();
+                let length = u16::from_be_bytes([data[0], data[1]]) as usize;
+                String::from_utf8(data[2..2 + length].to_vec()).unwrap()
+            })
+            .collect()
+    }
+
+    /// Every video frame still reads back as the frame number it was written with
+    fn assert_video_intact(path: &Path) {
+        let movie = Movie::read(path).unwrap();
+        let samples = movie.video_track().unwrap().samples().unwrap();
+        let mut reader = File::open(path).unwrap();
+        for (index, sample) in samples.iter().enumerate() {
+            assert_eq!(
+                read_sample(&mut reader, sample).unwrap(),
+                [index as u8; 100]
+            );
+        }
+    }
+
+    #[test]
+    fn writes_and_replaces_chapter_markers() {
+        let dir = TempDir::new();
+        let path = dir.join("GoPro_1234.mp4");
+        write_mp4(&path, &[FixtureTrack::video(90, 30)], Vec::new());
+
+        write_chapter_markers(&path, &[marker(2.5, "HiLight 2"), marker(1.0, "HiLight 1")])
+            .unwrap();
+        assert_eq!(
+            chapter_track_titles(&path),
+            ["Start", "HiLight 1", "HiLight 2"]
+        );
+        assert_video_intact(&path);
+
+        // Rewriting replaces the markers rather than adding a second set
+        write_chapter_markers(
+            &path,
+            &[marker(0.0, "GH011234.MP4"), marker(9.0, "Past the end")],
+        )
+        .unwrap();
+        assert_eq!(chapter_track_titles(&path), ["GH011234.MP4"]);
+        let movie = Movie::read(&path).unwrap();
+        let chpl = movie.moov.find(&[b"udta", b"chpl"]).unwrap();
+        assert_eq!(read_chpl(chpl), [marker(0.0, "GH011234.MP4")]);
+        let video = movie.video_track().unwrap();
+        let chap = video.trak.find(&[b"tref", b"chap"]).unwrap();
+        let chapter_track = movie
+            .tracks()
+            .into_iter()
+            .find(|t| t.handler_type() == Some(*b"text"))
+            .unwrap();
+        assert_eq!(
+            chap.payload,
+            chapter_track.track_id().unwrap().to_be_bytes()
+        );
+        assert_video_intact(&path);
+    }
+
+    #[test]
+    fn moov_before_other_boxes_is_freed_rather_than_overwritten() {
+        let dir = TempDir::new();
+        let path = dir.join("GoPro_1234.mp4");
+        write_mp4(&path, &[FixtureTrack::video(90, 30)], Vec::new());
+        let mut trailing = Vec::new();
+        Mp4Box::new(b"free", vec![0; 16]).write_to(&mut trailing);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&trailing)
+            .unwrap();
+
+        write_chapter_markers(&path, &[marker(1.0, "HiLight")]).unwrap();
+        let boxes = read_top_level_boxes(&mut File::open(&path).unwrap()).unwrap();
+        let types: Vec<_> = boxes.iter().map(|b| &b.typ).collect();
+        assert_eq!(
+            types,
+            [b"ftyp", b"mdat", b"free", b"free", b"mdat", b"moov"]
+        );
+        assert_eq!(chapter_track_titles(&path), ["Start", "HiLight"]);
+        assert_video_intact(&path);
+    }
+}
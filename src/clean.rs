@@ -0,0 +1,250 @@
+// `clean` frees up space on a card by deleting the chapters that have already been assembled. The
+// import history (see history.rs) says which those are. A video's chapters are only deleted if every
+// one of them was imported and what they were assembled into is still there, unchanged, so footage
+// that only exists on the card is never touched.
+//
+// "Unchanged" means the output still has the size and BLAKE3 it had when it was written. GoPro
+// reuses video numbers, so GoPro_N.mp4 may since have been overwritten by another card's video N.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use indicatif::HumanBytes;
+use log::{error, info, warn};
+
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::history::{ImportHistory, ImportedChapter};
+use crate::manifest::hash_file;
+use crate::printing::get_confirmation_before_proceeeding;
+
+/// Why an imported video's chapters have to stay, or None if they can be deleted. Outputs that have been
+/// hashed are remembered in `verified_outputs`, since every chapter of a merged video shares one.
+fn reason_to_keep(
+    chapters: &[GoProChapteredVideoFile],
+    imported_chapters: &[&ImportedChapter],
+    verified_outputs: &mut HashMap<PathBuf, bool>,
+) -> Option<String> {
+    for (chapter, imported) in chapters.iter().zip(imported_chapters) {
+        let output = &imported.output;
+        if imported.trimmed {
+            return Some(format!(
+                "it was trimmed into {}, which doesn't hold all of it",
+                output.display()
+            ));
+        }
+        // A symlinked output would be left pointing at nothing
+        if !output.exists() || output.is_symlink() || *output == chapter.abs_path {
+            return Some(format!(
+                "{} (what it was assembled into) is missing or links to it",
+                output.display()
+            ));
+        }
+        let (Some(size), Some(blake3)) = (imported.output_size, &imported.output_blake3) else {
+            return Some(format!(
+                "the import history has no hash of {}, so it can't be checked",
+                output.display()
+            ));
+        };
+        let unchanged = *verified_outputs
+            .entry(output.clone())
+            .or_insert_with(|| output_matches(output, size, blake3));
+        if !unchanged {
+            return Some(format!(
+                "{} has changed since it was assembled",
+                output.display()
+            ));
+        }
+    }
+    None
+}
+
+/// Checks the size first, so a different file is usually caught without hashing it
+fn output_matches(output: &Path, size: u64, blake3: &str) -> bool {
+    if output.metadata().map(|metadata| metadata.len()).ok() != Some(size) {
+        return false;
+    }
+    matches!(hash_file(output), Ok((_, hash)) if hash == blake3)
+}
+
+/// Deletes the chapters of already imported videos. Returns false if anything couldn't be deleted.
+pub fn clean_imported_chapters(
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    dry_run: bool,
+    auto_confirm_yes: bool,
+) -> bool {
+    let history = match ImportHistory::load() {
+        Ok(history) => history,
+        Err(e) => {
+            error!("{} {}", "Could not load import history:".red().bold(), e);
+            return false;
+        }
+    };
+
+    let mut video_ids: Vec<&VideoId> = videos.keys().collect();
+    video_ids.sort();
+    let mut removable: Vec<PathBuf> = Vec::new();
+    let mut verified_outputs = HashMap::new();
+    for video in video_ids {
+        let chapters = &videos[video];
+        // Videos that were never imported aren't worth a warning
+        let Some(imported_chapters) = chapters
+            .iter()
+            .map(|chapter| history.imported_chapter(chapter))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        if let Some(reason) = reason_to_keep(chapters, &imported_chapters, &mut verified_outputs) {
+            warn!("Keeping {}, since {}", video.to_string().yellow(), reason);
+            continue;
+        }
+        removable.extend(chapters.iter().map(|chapter| chapter.abs_path.clone()));
+    }
+
+    if removable.is_empty() {
+        info!("Nothing to clean up");
+        return true;
+    }
+    let bytes: u64 = removable
+        .iter()
+        .filter_map(|path| path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    info!(
+        "{} chapter(s) have already been imported, deleting them frees {}",
+        removable.len().to_string().blue().bold(),
+        HumanBytes(bytes).to_string().blue().bold()
+    );
+    for path in &removable {
+        info!("  {}", path.display());
+    }
+    if dry_run {
+        info!("Dry run, skipping delete!");
+        return true;
+    }
+    if !get_confirmation_before_proceeeding(auto_confirm_yes) {
+        info!("Exiting...");
+        return true;
+    }
+
+    let mut all_deleted = true;
+    for path in removable {
+        match fs::remove_file(&path) {
+            Ok(()) => info!("Deleted {}", path.to_string_lossy().green()),
+            Err(e) => {
+                error!(
+                    "{} {}: {}",
+                    "Failed to delete".red().bold(),
+                    path.display(),
+                    e
+                );
+                all_deleted = false;
+            }
+        }
+    }
+    all_deleted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+
+    struct Card {
+        // Removed when the card is dropped
+        _dir: TempDir,
+        chapter: GoProChapteredVideoFile,
+        output: PathBuf,
+    }
+
+    /// A one chapter video and the copy it was assembled into
+    fn imported_card() -> Card {
+        let dir = TempDir::new();
+        let chapter_path = dir.join("GH011234.MP4");
+        let output = dir.join("GoPro_1234.mp4");
+        fs::write(&chapter_path, b"chapter contents").unwrap();
+        fs::copy(&chapter_path, &output).unwrap();
+        let chapter = GoProChapteredVideoFile {
+            abs_path: chapter_path,
+            video_number: 1234,
+            chapter: 1,
+            camera: None,
+            session: None,
+            metadata: None,
+        };
+        Card {
+            _dir: dir,
+            chapter,
+            output,
+        }
+    }
+
+    fn history_for(card: &Card, trimmed: bool) -> ImportHistory {
+        let mut history = ImportHistory::default();
+        let hash = hash_file(&card.output).unwrap();
+        history
+            .record(
+                std::slice::from_ref(&card.chapter),
+                &card.output,
+                hash,
+                trimmed,
+            )
+            .unwrap();
+        history
+    }
+
+    fn reason(card: &Card, history: &ImportHistory) -> Option<String> {
+        let imported = history.imported_chapter(&card.chapter).unwrap();
+        reason_to_keep(
+            std::slice::from_ref(&card.chapter),
+            &[imported],
+            &mut HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn unchanged_output_can_be_cleaned() {
+        let card = imported_card();
+        assert_eq!(reason(&card, &history_for(&card, false)), None);
+    }
+
+    #[test]
+    fn overwritten_output_is_kept() {
+        let card = imported_card();
+        let history = history_for(&card, false);
+        // Another card's video with the same number, and the same size
+        fs::write(&card.output, b"another videos!!").unwrap();
+        assert!(reason(&card, &history).unwrap().contains("has changed"));
+        fs::write(&card.output, b"shorter").unwrap();
+        assert!(reason(&card, &history).unwrap().contains("has changed"));
+    }
+
+    #[test]
+    fn trimmed_output_is_kept() {
+        let card = imported_card();
+        assert!(reason(&card, &history_for(&card, true))
+            .unwrap()
+            .contains("trimmed"));
+    }
+
+    #[test]
+    fn missing_output_is_kept() {
+        let card = imported_card();
+        let history = history_for(&card, false);
+        fs::remove_file(&card.output).unwrap();
+        assert!(reason(&card, &history).unwrap().contains("missing"));
+    }
+
+    #[test]
+    fn history_without_hashes_is_kept() {
+        let card = imported_card();
+        let mut history = history_for(&card, false);
+        for imported in history.chapters.values_mut() {
+            imported.output_size = None;
+            imported.output_blake3 = None;
+        }
+        assert!(reason(&card, &history).unwrap().contains("no hash"));
+    }
+}
@@ -1,35 +1,642 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use time::macros::format_description;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::organize::validate_layout;
 
 #[derive(Parser, Clone, Debug)]
 #[clap(
     author = "Aaron Lichtman",
     version,
-    about = "Assembles all chaptered GoPro video files in a directory into 'complete' files.\nWritten by: Aaron Lichtman\nSource: https://github.com/alichtman/gopro-chaptered-video-assembler"
+    about = "Assembles all chaptered GoPro video files in a directory into 'complete' files.\nWritten by: Aaron Lichtman\nSource: https://github.com/alichtman/gopro-chaptered-video-assembler",
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
 )]
 pub struct CliArgs {
-    /// Directory to parse video files from
-    #[arg(short, long, value_name = "DIRECTORY", required = true)]
-    pub input: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Running without a subcommand is the same as `assemble`, which is how the tool was always run
+    #[command(flatten)]
+    pub assemble: AssembleArgs,
+
+    /// Logs more detail. Pass twice for even more
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only logs warnings. Pass twice to only log errors
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub quiet: u8,
+
+    /// Writes the log to FILE instead of a new timestamped file in the cache directory
+    #[arg(long, value_name = "FILE", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Doesn't write a log file, e.g. if the home directory is read-only
+    #[arg(
+        long = "no-log-file",
+        default_value = "false",
+        global = true,
+        conflicts_with = "log_file"
+    )]
+    pub no_log_file: bool,
+}
+
+/// Where to find videos, and which of them to work on
+#[derive(Args, Clone, Debug, Default)]
+pub struct ScanArgs {
+    /// Directories to parse video files from. Can be given more than once, or as a glob like 'cards/*'
+    #[arg(short, long, value_name = "DIRECTORY", required = true, num_args = 1..)]
+    pub input: Vec<PathBuf>,
+
+    /// Only process these videos, e.g. 7322,7329
+    #[arg(long = "video", value_name = "VIDEO_NUMBER", value_delimiter = ',')]
+    pub videos: Vec<u16>,
+
+    /// Only process videos recorded on or after this date, e.g. 2024-06-01, "2024-06-01 14:30" or
+    /// yesterday
+    #[arg(long, value_name = "DATE", value_parser = parse_since)]
+    pub since: Option<PrimitiveDateTime>,
+
+    /// Only process videos recorded on or before this date. A date without a time includes that
+    /// whole day.
+    #[arg(long, value_name = "DATE", value_parser = parse_until)]
+    pub until: Option<PrimitiveDateTime>,
+
+    /// Only process videos recorded with this codec
+    #[arg(long, value_enum)]
+    pub codec: Option<Codec>,
+
+    /// Only process videos at least this long, e.g. 10m or 00:10:00
+    #[arg(long = "min-duration", value_name = "DURATION", value_parser = parse_duration)]
+    pub min_duration: Option<f64>,
+}
+
+/// What happens to each video and where it ends up, shared by `plan` and `assemble`
+#[derive(Args, Clone, Debug, Default)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
 
     /// Directory to output video files to
     #[arg(short, long, value_name = "DIRECTORY", required = true)]
     pub output: Option<PathBuf>,
 
+    /// What to do with single chapter videos, which don't need merging
+    #[arg(long = "single-chapter-mode", value_enum, value_name = "MODE", default_value_t = SingleChapterMode::Rename)]
+    pub single_chapter_mode: SingleChapterMode,
+
+    /// Same as --single-chapter-mode copy
+    #[arg(
+        short = 'c',
+        long = "copy-single-chapter-instead-of-rename",
+        default_value = "false",
+        conflicts_with = "single_chapter_mode"
+    )]
+    pub copy_single_chapter_instead_of_renaming: bool,
+
+    /// Process videos even if the import history says they were already assembled
+    #[arg(short, long, default_value = "false")]
+    pub force: bool,
+
+    /// Trim a video to a start and end time, at the nearest keyframes, e.g. 7322=90s..44m.
+    /// Either time can be left out. Can be given once per video. When more than one camera or
+    /// recording used the number, name the video as its output is named instead, e.g.
+    /// GoPro_7322_C3441325012345=90s..
+    #[arg(long, value_name = "VIDEO=START..END", value_parser = parse_video_trim)]
+    pub trim: Vec<VideoTrim>,
+
+    /// Put each output in subdirectories of the output directory, e.g.
+    /// '{year}/{year}-{month}-{day}/{camera}/'. Placeholders are {year}, {month}, {day}, {hour} and
+    /// {minute} of the recording, and the {camera} model and {serial} number.
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_layout)]
+    pub organize: Option<String>,
+
+    /// Writes newline-delimited events to stdout (or --events-file) for scripts and dashboards
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub events: Option<EventFormat>,
+
+    /// Writes the events to FILE instead of stdout
+    #[arg(long = "events-file", value_name = "FILE", requires = "events")]
+    pub events_file: Option<PathBuf>,
+}
+
+impl PlanArgs {
+    /// --single-chapter-mode, or copy with -c
+    pub fn single_chapter_mode(&self) -> SingleChapterMode {
+        match self.copy_single_chapter_instead_of_renaming {
+            true => SingleChapterMode::Copy,
+            false => self.single_chapter_mode,
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct AssembleArgs {
+    #[command(flatten)]
+    pub plan: PlanArgs,
+
     /// Dry run. Does not write any files.
     #[arg(short, long, default_value = "false")]
     pub dry_run: bool,
 
+    /// Set by `plan`, to stop once the plan is printed
+    #[arg(skip)]
+    pub plan_only: bool,
+
     /// Auto-confirm yes to all prompts
     #[arg(short = 'y', long = "yes", default_value = "false")]
     pub auto_confirm_yes: bool,
 
-    /// Skips renaming single chapter videos
-    #[arg(
-        short = 'c',
-        long = "copy-single-chapter-instead-of-rename",
-        default_value = "false"
-    )]
-    pub copy_single_chapter_instead_of_renaming: bool,
+    /// Pick which videos to assemble, what to do with each and what to name them, in a terminal UI
+    #[arg(long, default_value = "false", conflicts_with = "auto_confirm_yes")]
+    pub interactive: bool,
+
+    /// Write a Gyroflow project (.gyroflow) next to each output video
+    #[arg(long, default_value = "false")]
+    pub gyroflow: bool,
+
+    /// Write HiLight tags from every chapter as chapter markers in merged videos
+    #[arg(long = "hilight-chapters", default_value = "false")]
+    pub hilight_chapters: bool,
+
+    /// Add a chapter marker to merged videos where each source chapter starts, titled with its file name
+    #[arg(long = "boundary-chapters", default_value = "false")]
+    pub boundary_chapters: bool,
+
+    /// Write the HiLight tags of each merged video to GoPro_N.hilights.json
+    #[arg(long = "hilight-sidecar", default_value = "false")]
+    pub hilight_sidecar: bool,
+
+    /// Tool used to merge chapters. ffmpeg must be installed to use it.
+    #[arg(long, value_enum, default_value_t = MergeBackendKind::Mp4Merge)]
+    pub backend: MergeBackendKind,
+
+    /// Instead of merging, write a timeline per video that references the original chapters back to
+    /// back. No video data is written.
+    #[arg(long = "export-timeline", value_enum, value_name = "FORMAT")]
+    pub export_timeline: Option<TimelineFormat>,
+
+    /// Skips writing the BLAKE3 manifest to the output directory
+    #[arg(long = "no-manifest", default_value = "false")]
+    pub no_manifest: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct GpsExportArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    /// Directory to write GPS tracks to
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: PathBuf,
+
+    /// Track file format
+    #[arg(short, long, value_enum, default_value_t = GpsFormat::Gpx)]
+    pub format: GpsFormat,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ImuExportArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    /// Directory to write CSV files to
+    #[arg(short, long, value_name = "DIRECTORY")]
+    pub output: PathBuf,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Merge multichapter videos and rename or copy single chapter videos into the output directory.
+    /// This is what runs without a subcommand.
+    Assemble(AssembleArgs),
+
+    /// Print what `assemble` would do with the same options, without writing anything
+    Plan(PlanArgs),
+
+    /// Report each video's duration, encoding, size, recording time and camera, without writing anything
+    Inspect {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// Report format
+        #[arg(short, long, value_enum, default_value_t = InspectFormat::Table)]
+        format: InspectFormat,
+    },
+
+    /// Re-hash every file listed in a directory's manifest and report any that changed
+    #[command(alias = "verify-manifest")]
+    Verify {
+        /// Directory containing the manifest to verify
+        #[arg(value_name = "DIRECTORY")]
+        directory: PathBuf,
+    },
+
+    /// Delete chapters from the input directories that the import history says were already
+    /// assembled, as long as what they were assembled into still exists
+    Clean {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// List what would be deleted without deleting it
+        #[arg(short, long, default_value = "false")]
+        dry_run: bool,
+
+        /// Auto-confirm yes to all prompts
+        #[arg(short = 'y', long = "yes", default_value = "false")]
+        auto_confirm_yes: bool,
+    },
+
+    /// Export telemetry from each video, aligned to the assembled video's timeline
+    #[command(subcommand)]
+    Export(ExportCommand),
+
+    /// Losslessly split videos at keyframes into standalone parts, e.g. to fit on a FAT32 drive
+    #[command(group(ArgGroup::new("limit").required(true).multiple(true)))]
+    Split {
+        /// Videos to split
+        #[arg(value_name = "FILE", required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Directory to write parts to. Defaults to the directory each video is in.
+        #[arg(short, long, value_name = "DIRECTORY")]
+        output: Option<PathBuf>,
+
+        /// Maximum size of each part, e.g. 3.9G or 700M
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, group = "limit")]
+        max_size: Option<u64>,
+
+        /// Maximum duration of each part, e.g. 30m, 90s or 01:30:00
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, group = "limit")]
+        max_duration: Option<f64>,
+    },
+
+    /// Losslessly trim a video to a start and end time, at the nearest keyframes
+    #[command(group(ArgGroup::new("range").required(true).multiple(true)))]
+    Trim {
+        /// Video to trim
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+
+        /// File to write the trimmed video to. Defaults to {name}_trimmed.mp4 next to the video.
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// Time to start at, e.g. 90s or 00:01:30
+        #[arg(long, value_name = "TIME", value_parser = parse_duration, group = "range")]
+        start: Option<f64>,
+
+        /// Time to end at, e.g. 44m or 00:44:00
+        #[arg(long, value_name = "TIME", value_parser = parse_duration, group = "range")]
+        end: Option<f64>,
+    },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum ExportCommand {
+    /// Export the GPS track of each video as GPX or KML
+    Gps(GpsExportArgs),
+
+    /// Export accelerometer, gyroscope, gravity and orientation telemetry as CSV, one file per stream
+    Imu(ImuExportArgs),
+}
+
+impl CliArgs {
+    /// The subcommand to run. Without one, the top level flags are `assemble`'s.
+    pub fn command(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or_else(|| Command::Assemble(self.assemble.clone()))
+    }
+
+    /// Whether stdout is for machine-readable output, so human output should go to stderr
+    pub fn json_on_stdout(&self) -> bool {
+        matches!(
+            self.command,
+            Some(Command::Inspect {
+                format: InspectFormat::Json,
+                ..
+            })
+        )
+    }
+}
+
+/// Start and end times in seconds. Either can be left open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrimRange {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoTrim {
+    pub video_number: u16,
+    /// The video's full name, e.g. GoPro_7322_C3441325012345, or None if only the number was given
+    pub name: Option<String>,
+    pub range: TrimRange,
+}
+
+/// Parses VIDEO=START..END, where VIDEO is a video number or name and START and END are optional
+/// durations
+fn parse_video_trim(trim: &str) -> Result<VideoTrim, String> {
+    let invalid = || format!("invalid trim '{}', expected e.g. 7322=1m30s..44m", trim);
+    let (video, range) = trim.split_once('=').ok_or_else(invalid)?;
+    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+    let parse_time = |time: &str| match time.trim() {
+        "" => Ok(None),
+        time => parse_duration(time).map(Some),
+    };
+    let range = TrimRange {
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    };
+    if range.start.is_none() && range.end.is_none() {
+        return Err(invalid());
+    }
+    if let (Some(start), Some(end)) = (range.start, range.end) {
+        if end <= start {
+            return Err(format!(
+                "invalid trim '{}', the end is before the start",
+                trim
+            ));
+        }
+    }
+    let video = video.trim();
+    let (video_number, name) = match video.strip_prefix("GoPro_") {
+        Some(name) => (name.split('_').next().unwrap_or_default(), Some(video)),
+        None => (video, None),
+    };
+    Ok(VideoTrim {
+        video_number: video_number.parse().map_err(|_| invalid())?,
+        name: name.map(str::to_string),
+        range,
+    })
+}
+
+/// Checks an --organize template, see organize.rs
+fn parse_layout(template: &str) -> Result<String, String> {
+    validate_layout(template).map(|()| template.to_string())
+}
+
+/// Parses a size like 3.9G, 700M or 1048576. Suffixes are powers of 1024.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let lowercase = size.to_lowercase();
+    let number = lowercase.trim_end_matches("ib").trim_end_matches('b');
+    let (number, multiplier) = match number.chars().last() {
+        Some('k') => (&number[..number.len() - 1], 1u64 << 10),
+        Some('m') => (&number[..number.len() - 1], 1 << 20),
+        Some('g') => (&number[..number.len() - 1], 1 << 30),
+        Some('t') => (&number[..number.len() - 1], 1 << 40),
+        _ => (number, 1),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(number) if number > 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(format!("invalid size '{}', expected e.g. 3.9G", size)),
+    }
+}
+
+/// Parses a duration in seconds from e.g. 90, 90s, 30m, 1.5h or 01:30:00
+fn parse_duration(duration: &str) -> Result<f64, String> {
+    let invalid = || {
+        format!(
+            "invalid duration '{}', expected e.g. 30m or 00:30:00",
+            duration
+        )
+    };
+    let duration = duration.trim();
+    let seconds = if duration.contains(':') {
+        duration.split(':').try_fold(0.0, |total, field| {
+            field.parse::<f64>().map(|field| total * 60.0 + field)
+        })
+    } else {
+        let (number, multiplier) = match duration.chars().last() {
+            Some('s') => (&duration[..duration.len() - 1], 1.0),
+            Some('m') => (&duration[..duration.len() - 1], 60.0),
+            Some('h') => (&duration[..duration.len() - 1], 3600.0),
+            _ => (duration, 1.0),
+        };
+        number.parse::<f64>().map(|number| number * multiplier)
+    };
+    match seconds {
+        Ok(seconds) if seconds > 0.0 => Ok(seconds),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a date for --since/--until. Returns the date, and the time if one was given.
+fn parse_date(date: &str) -> Result<(Date, Option<Time>), String> {
+    let date = date.trim();
+    let today = OffsetDateTime::now_local()
+        .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        .date();
+    match date {
+        "today" => return Ok((today, None)),
+        "yesterday" => return Ok((today - Duration::days(1), None)),
+        _ => (),
+    }
+    let invalid = || {
+        format!(
+            "invalid date '{}', expected e.g. 2024-06-01, \"2024-06-01 14:30\" or yesterday",
+            date
+        )
+    };
+    let (day, time) = match date.split_once([' ', 'T']) {
+        Some((day, time)) => (day, Some(time)),
+        None => (date, None),
+    };
+    let day =
+        Date::parse(day, format_description!("[year]-[month]-[day]")).map_err(|_| invalid())?;
+    let time = match time {
+        Some(time) => Some(
+            Time::parse(time, format_description!("[hour]:[minute]:[second]"))
+                .or_else(|_| Time::parse(time, format_description!("[hour]:[minute]")))
+                .map_err(|_| invalid())?,
+        ),
+        None => None,
+    };
+    Ok((day, time))
+}
+
+fn parse_since(date: &str) -> Result<PrimitiveDateTime, String> {
+    let (day, time) = parse_date(date)?;
+    Ok(PrimitiveDateTime::new(day, time.unwrap_or(Time::MIDNIGHT)))
+}
+
+fn parse_until(date: &str) -> Result<PrimitiveDateTime, String> {
+    let (day, time) = parse_date(date)?;
+    let end_of_day = Time::from_hms(23, 59, 59).expect("Valid time");
+    Ok(PrimitiveDateTime::new(day, time.unwrap_or(end_of_day)))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpsFormat {
+    Gpx,
+    Kml,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InspectFormat {
+    /// One row per video
+    Table,
+    /// An array with one object per video, including each chapter's size
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SingleChapterMode {
+    /// Move the video to the output directory
+    #[default]
+    Rename,
+    /// Copy the video, leaving the card as it was
+    Copy,
+    /// Hard link the video. The output directory has to be on the same filesystem.
+    Hardlink,
+    /// Symlink to the video where it is
+    Symlink,
+    /// Copy-on-write copy (btrfs, XFS), which is instant and takes no extra space. Falls back to a
+    /// copy where that isn't supported.
+    Reflink,
+}
+
+impl SingleChapterMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SingleChapterMode::Rename => "rename",
+            SingleChapterMode::Copy => "copy",
+            SingleChapterMode::Hardlink => "hardlink",
+            SingleChapterMode::Symlink => "symlink",
+            SingleChapterMode::Reflink => "reflink",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergeBackendKind {
+    #[default]
+    Mp4Merge,
+    Ffmpeg,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimelineFormat {
+    /// Final Cut Pro XML, also read by DaVinci Resolve
+    Fcpxml,
+    /// CMX 3600 edit decision list
+    Edl,
+    /// OpenTimelineIO
+    Otio,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// H.265 (HEVC)
+    Hevc,
+    /// H.264 (AVC)
+    Avc,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventFormat {
+    /// One JSON object per line
+    Json,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{date, datetime, time};
+
+    #[test]
+    fn parses_video_trims() {
+        assert_eq!(
+            parse_video_trim("7322=90s..44m"),
+            Ok(VideoTrim {
+                video_number: 7322,
+                name: None,
+                range: TrimRange {
+                    start: Some(90.0),
+                    end: Some(2640.0),
+                },
+            })
+        );
+        assert_eq!(
+            parse_video_trim("GoPro_7322_C3441325012345=..1m"),
+            Ok(VideoTrim {
+                video_number: 7322,
+                name: Some("GoPro_7322_C3441325012345".to_string()),
+                range: TrimRange {
+                    start: None,
+                    end: Some(60.0),
+                },
+            })
+        );
+        assert!(parse_video_trim("7322=..").is_err());
+        assert!(parse_video_trim("7322=2m..1m").is_err());
+        assert!(parse_video_trim("7322").is_err());
+        assert!(parse_video_trim("GoPro_x=1m..").is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1 << 20));
+        assert_eq!(parse_size("700M"), Ok(700 << 20));
+        assert_eq!(parse_size("700mb"), Ok(700 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size("3.5k"), Ok(3584));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("-1G").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(90.0));
+        assert_eq!(parse_duration("90s"), Ok(90.0));
+        assert_eq!(parse_duration("30m"), Ok(1800.0));
+        assert_eq!(parse_duration("1.5h"), Ok(5400.0));
+        assert_eq!(parse_duration("01:30:00"), Ok(5400.0));
+        assert_eq!(parse_duration("1:30"), Ok(90.0));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("-5m").is_err());
+        assert!(parse_duration("1:xx").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("2024-06-01"), Ok((date!(2024 - 06 - 01), None)));
+        assert_eq!(
+            parse_date("2024-06-01 14:30"),
+            Ok((date!(2024 - 06 - 01), Some(time!(14:30))))
+        );
+        assert_eq!(
+            parse_date("2024-06-01T14:30:05"),
+            Ok((date!(2024 - 06 - 01), Some(time!(14:30:05))))
+        );
+        let (today, _) = parse_date("today").unwrap();
+        let (yesterday, _) = parse_date("yesterday").unwrap();
+        assert_eq!(today - yesterday, Duration::days(1));
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("2024-06-01 25:00").is_err());
+        assert!(parse_date("June 1st").is_err());
+
+        // A date on its own covers the whole day
+        assert_eq!(
+            parse_since("2024-06-01"),
+            Ok(datetime!(2024-06-01 00:00:00))
+        );
+        assert_eq!(
+            parse_until("2024-06-01"),
+            Ok(datetime!(2024-06-01 23:59:59))
+        );
+        assert_eq!(
+            parse_until("2024-06-01 12:00"),
+            Ok(datetime!(2024-06-01 12:00))
+        );
+    }
 }
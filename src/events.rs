@@ -0,0 +1,205 @@
+// A machine-readable event stream, for tracking runs without scraping the coloured log output. With
+// `--events json`, every event is written as one line of JSON to stdout or `--events-file`. Field
+// names are part of the interface, so only add fields, never rename or remove them.
+//
+// When the events go to stdout, everything meant for humans goes to stderr instead.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::cli::EventFormat;
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::metadata::VideoMetadata;
+
+static EVENTS: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+static EVENTS_ON_STDOUT: OnceLock<bool> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedVideo {
+    pub video_number: u16,
+    /// Set when it's needed to tell videos with the same number apart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    /// Set when it's needed to tell recordings with the same number apart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    pub chapters: Vec<PathBuf>,
+    pub output: PathBuf,
+    /// "merge", or --single-chapter-mode for single chapter videos
+    pub action: &'static str,
+    /// The camera and its settings, from the first chapter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<VideoMetadata>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PlanCreated {
+        multichapter_videos: Vec<PlannedVideo>,
+        single_chapter_videos: Vec<PlannedVideo>,
+        /// "rename", "copy", "hardlink", "symlink" or "reflink"
+        single_chapter_action: &'static str,
+        total_bytes: u64,
+        dry_run: bool,
+    },
+    GroupStarted {
+        video_number: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        camera: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        chapters: Vec<PathBuf>,
+        output: PathBuf,
+    },
+    Progress {
+        current: String,
+        /// How far into the current video, from 0 to 1
+        fraction: f64,
+        bytes_done: u64,
+        total_bytes: u64,
+    },
+    GroupFinished {
+        video_number: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        camera: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        output: PathBuf,
+        bytes: u64,
+        elapsed_secs: f64,
+    },
+    FileCopied {
+        video_number: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        camera: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        source: PathBuf,
+        destination: PathBuf,
+        /// "rename", "copy", "hardlink", "symlink", "reflink" or "trim". A reflink that fell back to
+        /// a copy is "copy".
+        operation: &'static str,
+        bytes: u64,
+    },
+    Error {
+        video_number: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        camera: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        path: Option<PathBuf>,
+        message: String,
+    },
+    RunSummary {
+        multichapter_videos: usize,
+        single_chapter_videos: usize,
+        total_bytes: u64,
+        elapsed_secs: f64,
+        dry_run: bool,
+        /// Videos by outcome, see summary.rs
+        succeeded: usize,
+        skipped: usize,
+        failed: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Starts writing events to `file`, or stdout if there's no file
+pub fn initialize_events(format: EventFormat, file: Option<&Path>) -> io::Result<()> {
+    let writer: Box<dyn Write + Send> = match (format, file) {
+        (EventFormat::Json, Some(file)) => Box::new(BufWriter::new(File::create(file)?)),
+        (EventFormat::Json, None) => Box::new(io::stdout()),
+    };
+    let _ = EVENTS_ON_STDOUT.set(file.is_none());
+    let _ = EVENTS.set(Mutex::new(writer));
+    Ok(())
+}
+
+/// Whether stdout is taken by the event stream, so human output should go to stderr
+pub fn events_on_stdout() -> bool {
+    EVENTS.get().is_some() && EVENTS_ON_STDOUT.get().copied().unwrap_or(false)
+}
+
+pub fn emit(event: Event) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    let Ok(line) = serde_json::to_string(&Envelope {
+        timestamp,
+        event: &event,
+    }) else {
+        return;
+    };
+    let mut writer = events.lock().unwrap();
+    // Flushing every line lets consumers follow along as it happens
+    let _ = writeln!(writer, "{}", line).and_then(|_| writer.flush());
+}
+
+/// The videos of a plan in order, with where they'll be written and what happens to them
+pub fn planned_videos(
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_paths: &HashMap<VideoId, PathBuf>,
+    action: impl Fn(&VideoId) -> &'static str,
+) -> Vec<PlannedVideo> {
+    let mut planned: Vec<PlannedVideo> = videos
+        .iter()
+        .map(|(video, chapters)| PlannedVideo {
+            video_number: video.video_number,
+            camera: video.camera.clone(),
+            session: video.session.clone(),
+            chapters: chapters.iter().map(|c| c.abs_path.clone()).collect(),
+            output: output_paths[video].clone(),
+            action: action(video),
+            metadata: chapters.first().and_then(|c| c.metadata.clone()),
+        })
+        .collect();
+    planned.sort_by(|a, b| {
+        (a.video_number, &a.camera, &a.session).cmp(&(b.video_number, &b.camera, &b.session))
+    });
+    planned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn videos_keep_their_video_number_field() {
+        let event = Event::GroupFinished {
+            video_number: 7322,
+            camera: None,
+            session: Some("20240601-143000".to_string()),
+            output: PathBuf::from("/videos/GoPro_7322_20240601-143000.mp4"),
+            bytes: 1024,
+            elapsed_secs: 1.5,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "group_finished",
+                "video_number": 7322,
+                "session": "20240601-143000",
+                "output": "/videos/GoPro_7322_20240601-143000.mp4",
+                "bytes": 1024,
+                "elapsed_secs": 1.5,
+            })
+        );
+    }
+}
@@ -3,53 +3,161 @@ extern crate colored;
 use colored::*;
 use log::info;
 use normpath::PathExt;
-use std::fs::create_dir_all;
-use std::path::PathBuf;
-use std::process;
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
-pub fn get_files_in_directory(path: &str) -> Vec<PathBuf> {
+pub fn get_files_in_directory(path: &Path) -> std::io::Result<Vec<PathBuf>> {
     let mut files: Vec<PathBuf> = Vec::new();
-    let directory = PathBuf::from(path).read_dir();
-    if directory.is_err() {
-        eprintln!("{} {}", "Directory not found:".red().bold(), path);
-        process::exit(1);
+    for entry in path.read_dir()? {
+        files.push(entry?.path());
     }
-    for entry in directory.unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        files.push(path);
-    }
-    files
+    Ok(files)
 }
 
-pub fn normalize_and_create_if_needed(path: PathBuf) -> PathBuf {
-    let mut normalized_path = match path.clone().normalize() {
-        Ok(path) => path,
+pub fn normalize_and_create_if_needed(path: PathBuf) -> std::io::Result<PathBuf> {
+    let normalized_path = match path.normalize() {
+        Ok(path) => {
+            info!(
+                "{} directory exists, using it...",
+                path.as_path().to_string_lossy().blue().bold()
+            );
+            path
+        }
         Err(_) => {
             info!(
                 "{} directory does not exist, attempting to create it now...",
                 path.to_string_lossy().blue().bold()
             );
-            let path = path;
-            let normalized_path = create_dir(path.clone().to_path_buf()).normalize().unwrap();
-            normalized_path
+            create_dir(path)?.normalize()?
         }
     };
+    Ok(normalized_path.into_path_buf())
+}
 
-    if normalized_path.exists() {
-        info!(
-            "{} directory exists, using it...",
-            normalized_path.as_path().to_string_lossy().blue().bold()
-        );
-        normalized_path = normalized_path
-            .clone()
-            .normalize()
-            .expect("Could not canonicalize output dir path");
+pub fn create_dir(path: PathBuf) -> std::io::Result<PathBuf> {
+    create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Creates `path` for writing, failing if anything is already there rather than truncating it
+pub fn create_new(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Like `std::fs::copy`, but calls `progress` with the fraction copied so far. Never overwrites
+/// `to`, and removes the partial copy if it fails.
+pub fn copy_with_progress(from: &Path, to: &Path, progress: &dyn Fn(f64)) -> std::io::Result<u64> {
+    let mut source = File::open(from)?;
+    let metadata = source.metadata()?;
+    let mut destination = create_new(to)?;
+    let mut copy = || {
+        let mut buffer = vec![0; 8 * 1024 * 1024];
+        let mut copied = 0;
+        loop {
+            let read = source.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            destination.write_all(&buffer[..read])?;
+            copied += read as u64;
+            if metadata.len() > 0 {
+                progress(copied as f64 / metadata.len() as f64);
+            }
+        }
+        destination.set_permissions(metadata.permissions())?;
+        Ok(copied)
+    };
+    let copied = copy();
+    if copied.is_err() {
+        let _ = remove_file(to);
+    }
+    copied
+}
+
+/// Makes `to` a copy-on-write clone of `from`, which shares its data until either is changed. Only
+/// some filesystems (btrfs, XFS) support this, and only within the same filesystem. Like
+/// copy_with_progress, never overwrites `to` and leaves nothing behind if it fails.
+#[cfg(target_os = "linux")]
+pub fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = File::open(from)?;
+    let destination = create_new(to)?;
+    // SAFETY: both file descriptors are open for as long as the call takes
+    let cloned =
+        match unsafe { libc::ioctl(destination.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } {
+            0 => source
+                .metadata()
+                .and_then(|metadata| destination.set_permissions(metadata.permissions())),
+            _ => Err(std::io::Error::last_os_error()),
+        };
+    if cloned.is_err() {
+        let _ = remove_file(to);
+    }
+    cloned
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Like `std::fs::rename`, but fails with AlreadyExists instead of replacing `to`
+pub fn rename_new(from: &Path, to: &Path) -> std::io::Result<()> {
+    // symlink_metadata so a dangling symlink counts as being there too
+    if to.symlink_metadata().is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "the output already exists",
+        ));
     }
-    normalized_path.into_path_buf()
+    rename(from, to)
 }
 
-pub fn create_dir(path: PathBuf) -> PathBuf {
-    create_dir_all(path.clone()).expect("Failed to create dir");
-    path
+/// Makes `to` a symlink to `from`
+pub fn symlink_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(from, to);
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(from, to);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+    use std::fs;
+
+    #[test]
+    fn copy_with_progress_copies_and_reports_progress() {
+        let dir = TempDir::new();
+        let (from, to) = (dir.join("GH011234.MP4"), dir.join("GoPro_1234.mp4"));
+        fs::write(&from, b"chapter").unwrap();
+        let last_fraction = std::cell::Cell::new(0.0);
+        let copied = copy_with_progress(&from, &to, &|fraction| last_fraction.set(fraction));
+        assert_eq!(copied.unwrap(), 7);
+        assert_eq!(last_fraction.get(), 1.0);
+        assert_eq!(fs::read(&to).unwrap(), b"chapter");
+    }
+
+    #[test]
+    fn existing_outputs_are_never_overwritten() {
+        let dir = TempDir::new();
+        let (from, to) = (dir.join("GH011234.MP4"), dir.join("GoPro_1234.mp4"));
+        fs::write(&from, b"chapter").unwrap();
+        fs::write(&to, b"another card's video").unwrap();
+        assert!(copy_with_progress(&from, &to, &|_| ()).is_err());
+        assert!(reflink(&from, &to).is_err());
+        let renamed = rename_new(&from, &to);
+        assert_eq!(
+            renamed.unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::read(&to).unwrap(), b"another card's video");
+        assert!(from.exists());
+    }
 }
@@ -0,0 +1,220 @@
+// Narrows a run down to the videos asked for with --video, --since/--until, --codec and
+// --min-duration. Videos that are filtered out are returned with the reason, so they can be
+// reported as skipped instead of silently disappearing.
+//
+// The dates and codec come from the first chapter's `moov`, and the duration from every chapter's.
+// Files are only read if a filter needs them.
+
+use std::collections::HashMap;
+use std::io::Error;
+
+use time::macros::format_description;
+
+use crate::cli::{Codec, ScanArgs};
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::mp4::Movie;
+
+fn has_filters(args: &ScanArgs) -> bool {
+    !args.videos.is_empty() || has_file_filters(args)
+}
+
+/// Whether any filter needs to read the videos, which --video doesn't
+fn has_file_filters(args: &ScanArgs) -> bool {
+    args.since.is_some()
+        || args.until.is_some()
+        || args.codec.is_some()
+        || args.min_duration.is_some()
+}
+
+fn codec_of(movie: &Movie) -> Option<Codec> {
+    match &movie.video_track()?.sample_format()? {
+        b"hvc1" | b"hev1" => Some(Codec::Hevc),
+        b"avc1" | b"avc3" => Some(Codec::Avc),
+        _ => None,
+    }
+}
+
+/// Why a video doesn't match the filters, or None if it does
+fn rejection(
+    chapters: &[GoProChapteredVideoFile],
+    args: &ScanArgs,
+) -> Result<Option<String>, Error> {
+    let Some(first_chapter) = chapters.first() else {
+        return Ok(Some("no chapters".to_string()));
+    };
+    if !args.videos.is_empty() && !args.videos.contains(&first_chapter.video_number) {
+        return Ok(Some("not in --video".to_string()));
+    }
+    if !has_file_filters(args) {
+        return Ok(None);
+    }
+
+    let movie = Movie::read(&first_chapter.abs_path)?;
+    if args.since.is_some() || args.until.is_some() {
+        let Some(recorded) = movie.creation_time() else {
+            return Ok(Some("no recording date".to_string()));
+        };
+        let recorded_text = recorded
+            .format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second]"
+            ))
+            .unwrap_or_default();
+        if args.since.is_some_and(|since| recorded < since) {
+            return Ok(Some(format!("recorded before --since ({})", recorded_text)));
+        }
+        if args.until.is_some_and(|until| recorded > until) {
+            return Ok(Some(format!("recorded after --until ({})", recorded_text)));
+        }
+    }
+    if let Some(codec) = args.codec {
+        if codec_of(&movie) != Some(codec) {
+            return Ok(Some(match codec {
+                Codec::Hevc => "not HEVC".to_string(),
+                Codec::Avc => "not AVC".to_string(),
+            }));
+        }
+    }
+    if let Some(min_duration) = args.min_duration {
+        let mut duration = movie.duration_secs();
+        for chapter in &chapters[1..] {
+            duration += Movie::read(&chapter.abs_path)?.duration_secs();
+        }
+        if duration < min_duration {
+            return Ok(Some(format!(
+                "shorter than --min-duration ({:.0}s)",
+                duration
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Removes the videos that don't match the filters. Returns the removed videos' chapters and why
+/// each was removed.
+pub fn filter_videos(
+    videos: &mut HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    args: &ScanArgs,
+) -> Vec<(Vec<GoProChapteredVideoFile>, String)> {
+    if !has_filters(args) {
+        return Vec::new();
+    }
+    let mut filtered_out = Vec::new();
+    let mut video_ids: Vec<VideoId> = videos.keys().cloned().collect();
+    video_ids.sort();
+    for video in video_ids {
+        let reason = match rejection(&videos[&video], args) {
+            Ok(None) => continue,
+            Ok(Some(reason)) => reason,
+            Err(e) => format!("could not read it to filter: {}", e),
+        };
+        if let Some(chapters) = videos.remove(&video) {
+            filtered_out.push((chapters, reason));
+        }
+    }
+    filtered_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+    use time::macros::datetime;
+
+    fn chapter(
+        dir: &TempDir,
+        video_number: u16,
+        chapter: u16,
+        frames: usize,
+    ) -> GoProChapteredVideoFile {
+        let abs_path = dir.join(&format!("GH{:02}{:04}.MP4", chapter, video_number));
+        write_mp4(&abs_path, &[FixtureTrack::video(frames, 30)], Vec::new());
+        GoProChapteredVideoFile {
+            abs_path,
+            video_number,
+            chapter,
+            camera: None,
+            session: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn video_numbers_are_matched_without_reading_the_files() {
+        let chapters = [GoProChapteredVideoFile {
+            abs_path: "/nonexistent/GH011234.MP4".into(),
+            video_number: 1234,
+            chapter: 1,
+            camera: None,
+            session: None,
+            metadata: None,
+        }];
+        let mut args = ScanArgs {
+            videos: vec![1234],
+            ..ScanArgs::default()
+        };
+        assert_eq!(rejection(&chapters, &args).unwrap(), None);
+        args.videos = vec![7322];
+        assert_eq!(
+            rejection(&chapters, &args).unwrap().as_deref(),
+            Some("not in --video")
+        );
+    }
+
+    #[test]
+    fn dates_are_compared_with_the_recording_date() {
+        let dir = TempDir::new();
+        // Recorded 2024-06-01 00:00:00
+        let chapters = [chapter(&dir, 1234, 1, 30)];
+        let mut args = ScanArgs {
+            since: Some(datetime!(2024-05-31 0:00)),
+            until: Some(datetime!(2024-06-01 23:59:59)),
+            ..ScanArgs::default()
+        };
+        assert_eq!(rejection(&chapters, &args).unwrap(), None);
+
+        args.since = Some(datetime!(2024-06-01 0:00:01));
+        assert_eq!(
+            rejection(&chapters, &args).unwrap().as_deref(),
+            Some("recorded before --since (2024-06-01 00:00:00)")
+        );
+        args.since = None;
+        args.until = Some(datetime!(2024-05-31 23:59:59));
+        assert_eq!(
+            rejection(&chapters, &args).unwrap().as_deref(),
+            Some("recorded after --until (2024-06-01 00:00:00)")
+        );
+    }
+
+    #[test]
+    fn codecs_come_from_the_video_track() {
+        let dir = TempDir::new();
+        let chapters = [chapter(&dir, 1234, 1, 30)];
+        let mut args = ScanArgs {
+            codec: Some(Codec::Avc),
+            ..ScanArgs::default()
+        };
+        assert_eq!(rejection(&chapters, &args).unwrap(), None);
+        args.codec = Some(Codec::Hevc);
+        assert_eq!(
+            rejection(&chapters, &args).unwrap().as_deref(),
+            Some("not HEVC")
+        );
+    }
+
+    #[test]
+    fn min_duration_counts_every_chapter() {
+        let dir = TempDir::new();
+        // Two 2s chapters
+        let chapters = [chapter(&dir, 1234, 1, 60), chapter(&dir, 1234, 2, 60)];
+        let mut args = ScanArgs {
+            min_duration: Some(4.0),
+            ..ScanArgs::default()
+        };
+        assert_eq!(rejection(&chapters, &args).unwrap(), None);
+        args.min_duration = Some(4.5);
+        assert_eq!(
+            rejection(&chapters, &args).unwrap().as_deref(),
+            Some("shorter than --min-duration (4s)")
+        );
+    }
+}
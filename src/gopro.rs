@@ -19,12 +19,30 @@
 //                  GH021234.mp4 (second video)
 //                  ...
 // Chaptered videos require concatenation of... all chapters
+//
+// Video numbers are only unique per camera. When footage from several cameras is assembled together
+// and two of them recorded the same video number, the camera's identity (see camera.rs) keeps their
+// chapters apart.
+//
+// A camera can also reuse its own video numbers after a firmware update or card format resets its
+// counter. Chapters whose creation times aren't contiguous are split into separate recordings.
 
 use std::collections::HashMap;
 use std::io::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use colored::Colorize;
 use log::warn;
+use serde::Serialize;
+use time::macros::format_description;
+use time::{Duration, PrimitiveDateTime};
+
+use crate::camera::read_camera_identity;
+use crate::metadata::VideoMetadata;
+use crate::mp4::MovieHeader;
+
+/// A chapter that starts this long after the previous one ended belongs to another recording
+const RECORDING_GAP: Duration = Duration::minutes(1);
 
 /// This struct represents a chaptered GoPro video file (what the camera writes to disk)
 #[derive(Debug, Clone)]
@@ -32,6 +50,51 @@ pub struct GoProChapteredVideoFile {
     pub abs_path: PathBuf,
     pub video_number: u16,
     pub chapter: u16,
+    /// The camera that recorded this. Only set when another camera recorded a video with the same
+    /// number, since that's the only time it's needed.
+    pub camera: Option<String>,
+    /// When the recording this is part of started, e.g. 20240601-143000. Only set when the camera
+    /// reused this video number for another recording.
+    pub session: Option<String>,
+    /// What the camera recorded about itself and its settings. Only read for the first chapter of
+    /// each video, since the rest of the chapters share it.
+    pub metadata: Option<VideoMetadata>,
+}
+
+impl GoProChapteredVideoFile {
+    pub fn video_id(&self) -> VideoId {
+        VideoId {
+            video_number: self.video_number,
+            camera: self.camera.clone(),
+            session: self.session.clone(),
+        }
+    }
+}
+
+/// Identifies a video: its number, plus the camera and recording session when they're needed to
+/// tell apart videos with the same number
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct VideoId {
+    pub video_number: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+}
+
+impl std::fmt::Display for VideoId {
+    /// The name outputs are given, e.g. GoPro_7322, or GoPro_7322_C3441325012345_20240601-143000
+    /// to tell cameras and recordings apart
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "GoPro_{}", self.video_number)?;
+        if let Some(camera) = &self.camera {
+            write!(f, "_{}", camera)?;
+        }
+        if let Some(session) = &self.session {
+            write!(f, "_{}", session)?;
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for GoProChapteredVideoFile {
@@ -48,7 +111,12 @@ impl std::fmt::Display for GoProChapteredVideoFile {
 
 pub fn parse_gopro_file(path: PathBuf) -> Result<GoProChapteredVideoFile, Error> {
     // println!("\n\nParsing file: {:?}", path);
-    let filename = path.as_path().file_name().unwrap().to_str().unwrap();
+    let invalid = |message: String| Error::new(std::io::ErrorKind::InvalidData, message);
+    let filename = path
+        .as_path()
+        .file_name()
+        .and_then(|filename| filename.to_str())
+        .ok_or_else(|| invalid(format!("Invalid file name: {}", path.display())))?;
     if path.is_dir() {
         return Err(Error::new(
             std::io::ErrorKind::InvalidData,
@@ -58,11 +126,10 @@ pub fn parse_gopro_file(path: PathBuf) -> Result<GoProChapteredVideoFile, Error>
     let extension = path
         .as_path()
         .extension()
-        .unwrap()
-        .to_str()
-        .unwrap()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
         .to_lowercase();
-    let prefix = filename.get(0..2).unwrap();
+    let prefix = filename.get(0..2).unwrap_or_default();
 
     if extension == "jpg" && (prefix == "GO" || prefix == "G0") {
         return Err(Error::new(
@@ -76,7 +143,7 @@ pub fn parse_gopro_file(path: PathBuf) -> Result<GoProChapteredVideoFile, Error>
             format!("Invalid file extension or prefix: {}", filename),
         ));
     }
-    let video_number: u16 = match filename.get(4..8).unwrap().parse() {
+    let video_number: u16 = match filename.get(4..8).unwrap_or_default().parse() {
         Ok(v) => v,
         Err(e) => {
             return Err(Error::new(
@@ -88,7 +155,7 @@ pub fn parse_gopro_file(path: PathBuf) -> Result<GoProChapteredVideoFile, Error>
             ));
         }
     };
-    let chapter: u16 = match filename.get(2..4).unwrap().parse() {
+    let chapter: u16 = match filename.get(2..4).unwrap_or_default().parse() {
         Ok(v) => v,
         Err(e) => {
             return Err(Error::new(
@@ -99,9 +166,12 @@ pub fn parse_gopro_file(path: PathBuf) -> Result<GoProChapteredVideoFile, Error>
     };
 
     Ok(GoProChapteredVideoFile {
-        abs_path: path.canonicalize().unwrap(),
+        abs_path: path.canonicalize()?,
         video_number,
         chapter,
+        camera: None,
+        session: None,
+        metadata: None,
     })
 }
 
@@ -125,29 +195,349 @@ pub fn parse_gopro_files_directory(input_files: Vec<PathBuf>) -> Vec<GoProChapte
 
 pub fn sort_gopro_files(
     videos: Vec<GoProChapteredVideoFile>,
-) -> HashMap<u16, Vec<GoProChapteredVideoFile>> {
+) -> HashMap<VideoId, Vec<GoProChapteredVideoFile>> {
     let mut video_number_to_subvideos_mapping: HashMap<u16, Vec<GoProChapteredVideoFile>> =
         HashMap::new();
 
     for video in videos {
         video_number_to_subvideos_mapping
             .entry(video.video_number)
-            .or_insert(Vec::new())
+            .or_default()
             .push(video);
     }
 
+    let mut videos_by_camera: HashMap<VideoId, Vec<GoProChapteredVideoFile>> = HashMap::new();
+    for (video_number, chapters) in video_number_to_subvideos_mapping {
+        for chapter in separate_cameras(video_number, chapters) {
+            videos_by_camera
+                .entry(chapter.video_id())
+                .or_default()
+                .push(chapter);
+        }
+    }
+
+    let mut videos_by_id: HashMap<VideoId, Vec<GoProChapteredVideoFile>> = HashMap::new();
+    for (video, chapters) in videos_by_camera {
+        for chapter in separate_recordings(&video, chapters) {
+            videos_by_id
+                .entry(chapter.video_id())
+                .or_default()
+                .push(chapter);
+        }
+    }
+
     // Sort chapters within each video group by chapter number
-    for chapters in video_number_to_subvideos_mapping.values_mut() {
+    for chapters in videos_by_id.values_mut() {
         chapters.sort_by_key(|c| c.chapter);
+        if let Some(first_chapter) = chapters.first_mut() {
+            first_chapter.metadata = VideoMetadata::read(&first_chapter.abs_path).ok();
+        }
+    }
+
+    videos_by_id
+}
+
+/// Sets the camera on chapters of a video number that more than one camera recorded. Cameras are
+/// read whenever there's more than one chapter, since two cameras' chapters don't have to share a
+/// chapter number. Copies of the same chapter from one camera are left for
+/// remove_duplicate_chapters, as are chapters whose camera can't be identified when only one camera
+/// can be: they're most likely that camera's. With two or more cameras, they're kept apart from both.
+fn separate_cameras(
+    video_number: u16,
+    mut chapters: Vec<GoProChapteredVideoFile>,
+) -> Vec<GoProChapteredVideoFile> {
+    if chapters.len() < 2 {
+        return chapters;
+    }
+    let cameras: Vec<Option<String>> = chapters
+        .iter()
+        .map(|c| read_camera_identity(&c.abs_path))
+        .collect();
+    let mut distinct_cameras: Vec<&String> = cameras.iter().flatten().collect();
+    distinct_cameras.sort();
+    distinct_cameras.dedup();
+    if distinct_cameras.len() < 2 {
+        return chapters;
+    }
+    warn!(
+        "Video {} was recorded by {} different cameras, keeping them apart: {}",
+        video_number.to_string().yellow().bold(),
+        distinct_cameras.len(),
+        distinct_cameras
+            .iter()
+            .map(|camera| camera.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    for (chapter, camera) in chapters.iter_mut().zip(cameras) {
+        chapter.camera = camera;
+    }
+    chapters
+}
+
+/// Sets the session on chapters of one camera's video number that was used for more than one
+/// recording. Each chapter starts where the previous one ended, so a chapter that starts well after
+/// every earlier one ended is another recording. If any chapter's creation time or duration can't
+/// be read, the chapters are left together.
+fn separate_recordings(
+    video: &VideoId,
+    mut chapters: Vec<GoProChapteredVideoFile>,
+) -> Vec<GoProChapteredVideoFile> {
+    if chapters.len() < 2 {
+        return chapters;
     }
+    let Some(times) = chapters
+        .iter()
+        .map(|chapter| {
+            let header = MovieHeader::read(&chapter.abs_path).ok()?;
+            Some((header.creation_time()?, header.duration()?))
+        })
+        .collect::<Option<Vec<(PrimitiveDateTime, Duration)>>>()
+    else {
+        return chapters;
+    };
 
-    video_number_to_subvideos_mapping
+    let mut by_start: Vec<usize> = (0..chapters.len()).collect();
+    by_start.sort_by_key(|i| (times[*i].0, chapters[*i].chapter));
+    let mut recording_starts: Vec<PrimitiveDateTime> = Vec::new();
+    let mut recording_of_chapter = vec![0; chapters.len()];
+    let mut recording_end: Option<PrimitiveDateTime> = None;
+    for i in by_start {
+        let (start, duration) = times[i];
+        if recording_end.is_none_or(|end| {
+            end.checked_add(RECORDING_GAP)
+                .is_some_and(|end| start > end)
+        }) {
+            recording_starts.push(start);
+        }
+        let Some(end) = start.checked_add(duration) else {
+            return chapters;
+        };
+        recording_end = recording_end.max(Some(end));
+        recording_of_chapter[i] = recording_starts.len() - 1;
+    }
+    if recording_starts.len() < 2 {
+        return chapters;
+    }
+
+    warn!(
+        "{} is {} separate recordings (the camera's numbering was reset), keeping them apart: {}",
+        video.to_string().yellow().bold(),
+        recording_starts.len(),
+        recording_starts
+            .iter()
+            .map(|start| {
+                start
+                    .format(format_description!(
+                        "[year]-[month]-[day] [hour]:[minute]:[second]"
+                    ))
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    for (chapter, recording) in chapters.iter_mut().zip(recording_of_chapter) {
+        chapter.session = recording_starts[recording]
+            .format(format_description!(
+                "[year][month][day]-[hour][minute][second]"
+            ))
+            .ok();
+    }
+    chapters
 }
 
 // Assumes output_dir is a normalized directory path. Adds GoPro_{}.EXTENSION to the end of the path.
-pub fn gen_output_path(output_dir: &PathBuf, video_number: u16, extension: &str) -> PathBuf {
+pub fn gen_output_path(output_dir: &Path, video: &VideoId, extension: &str) -> PathBuf {
     let mut output_path = PathBuf::from(output_dir);
-    output_path.push(format!("GoPro_{}", video_number));
+    output_path.push(video.to_string());
     output_path.set_extension(extension);
     output_path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+    use std::fs;
+
+    /// Seconds from 1904-01-01 to 2024-06-01 00:00:00
+    const JUNE_2024: u64 = 3_800_044_800;
+
+    /// A file with nothing but a moov holding a version 1 mvhd, timescale 1000
+    fn write_chapter(dir: &Path, name: &str, creation_time: u64, duration_ms: u64) -> PathBuf {
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend_from_slice(&creation_time.to_be_bytes());
+        mvhd.extend_from_slice(&creation_time.to_be_bytes());
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&duration_ms.to_be_bytes());
+        let mut file = Vec::new();
+        file.extend_from_slice(&(16 + mvhd.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"moov");
+        file.extend_from_slice(&(8 + mvhd.len() as u32).to_be_bytes());
+        file.extend_from_slice(b"mvhd");
+        file.extend_from_slice(&mvhd);
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+        path
+    }
+
+    fn chapter(path: PathBuf, chapter: u16) -> GoProChapteredVideoFile {
+        GoProChapteredVideoFile {
+            abs_path: path,
+            video_number: 1234,
+            chapter,
+            camera: None,
+            session: None,
+            metadata: None,
+        }
+    }
+
+    fn sessions(chapters: &[GoProChapteredVideoFile]) -> Vec<Option<&str>> {
+        chapters.iter().map(|c| c.session.as_deref()).collect()
+    }
+
+    /// A card's MISC/version.txt, which identifies the camera for the chapters under it
+    fn write_version_txt(card: &Path, serial: &str) {
+        fs::create_dir_all(card.join("MISC")).unwrap();
+        fs::write(
+            card.join("MISC").join("version.txt"),
+            format!("{{\n\"camera serial number\":\"{}\",\n}}", serial),
+        )
+        .unwrap();
+    }
+
+    fn cameras(chapters: &[GoProChapteredVideoFile]) -> Vec<Option<&str>> {
+        chapters.iter().map(|c| c.camera.as_deref()).collect()
+    }
+
+    #[test]
+    fn two_cameras_are_kept_apart() {
+        let dir = TempDir::new();
+        write_version_txt(&dir.join("a"), "C1");
+        write_version_txt(&dir.join("b"), "C2");
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "a/DCIM/100GOPRO/GH011234.MP4", JUNE_2024, 1000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "b/DCIM/100GOPRO/GH011234.MP4", JUNE_2024, 1000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "c/DCIM/100GOPRO/GH021234.MP4", JUNE_2024, 1000),
+                2,
+            ),
+        ];
+        assert_eq!(
+            cameras(&separate_cameras(1234, chapters)),
+            [Some("C1"), Some("C2"), None]
+        );
+    }
+
+    #[test]
+    fn cameras_are_kept_apart_without_a_repeated_chapter() {
+        let dir = TempDir::new();
+        write_version_txt(&dir.join("a"), "C1");
+        write_version_txt(&dir.join("b"), "C2");
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "a/DCIM/100GOPRO/GH011234.MP4", JUNE_2024, 1000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "b/DCIM/100GOPRO/GH021234.MP4", JUNE_2024, 1000),
+                2,
+            ),
+        ];
+        assert_eq!(
+            cameras(&separate_cameras(1234, chapters)),
+            [Some("C1"), Some("C2")]
+        );
+    }
+
+    #[test]
+    fn unidentified_chapters_stay_with_the_only_identified_camera() {
+        let dir = TempDir::new();
+        write_version_txt(&dir.join("a"), "C1");
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "a/DCIM/100GOPRO/GH011234.MP4", JUNE_2024, 1000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "a/DCIM/100GOPRO/GH021234.MP4", JUNE_2024, 1000),
+                2,
+            ),
+            chapter(
+                write_chapter(dir.path(), "b/DCIM/100GOPRO/GH011234.MP4", JUNE_2024, 1000),
+                1,
+            ),
+        ];
+        assert_eq!(
+            cameras(&separate_cameras(1234, chapters)),
+            [None, None, None]
+        );
+    }
+
+    #[test]
+    fn back_to_back_chapters_are_one_recording() {
+        let dir = TempDir::new();
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "GH011234.MP4", JUNE_2024, 600_000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "GH021234.MP4", JUNE_2024 + 600, 60_000),
+                2,
+            ),
+        ];
+        let video = chapters[0].video_id();
+        assert_eq!(
+            sessions(&separate_recordings(&video, chapters)),
+            [None, None]
+        );
+    }
+
+    #[test]
+    fn reused_video_number_is_split_by_recording() {
+        let dir = TempDir::new();
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "a/GH011234.MP4", JUNE_2024, 60_000),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "b/GH011234.MP4", JUNE_2024 + 3600, 60_000),
+                1,
+            ),
+        ];
+        let video = chapters[0].video_id();
+        assert_eq!(
+            sessions(&separate_recordings(&video, chapters)),
+            [Some("20240601-000000"), Some("20240601-010000")]
+        );
+    }
+
+    #[test]
+    fn corrupt_duration_leaves_chapters_together() {
+        let dir = TempDir::new();
+        let chapters = vec![
+            chapter(
+                write_chapter(dir.path(), "GH011234.MP4", JUNE_2024, u64::MAX),
+                1,
+            ),
+            chapter(
+                write_chapter(dir.path(), "GH021234.MP4", JUNE_2024 + 3600, 60_000),
+                2,
+            ),
+        ];
+        let video = chapters[0].video_id();
+        assert_eq!(
+            sessions(&separate_recordings(&video, chapters)),
+            [None, None]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+// GoPro Metadata Format (GPMF) parsing
+//
+// https://github.com/gopro/gpmf-parser#gpmf-deeper-dive
+//
+// GPMF is a KLV (key, length, value) format. Every entry has an 8 byte header:
+//   - 4 byte FourCC key, e.g. DEVC, STRM, GPS5, ACCL
+//   - 1 byte type, e.g. 'l' for i32, 'c' for char, 0 for nested entries
+//   - 1 byte structure size (bytes per sample)
+//   - 2 byte repeat count (number of samples)
+// followed by the data, padded to a multiple of 4 bytes.
+//
+// A payload (one sample in the `gpmd` track, usually ~1 second) holds one DEVC per device, each
+// holding one STRM per sensor stream. A STRM holds metadata entries (STNM, SCAL, UNIT, TYPE, ...)
+// and then the sensor data itself under a key like GPS5 or ACCL.
+
+use std::io::Error;
+
+use crate::mp4::{ByteReader, FourCC};
+
+#[derive(Debug, Clone)]
+pub struct KlvEntry {
+    pub key: FourCC,
+    pub type_char: u8,
+    pub struct_size: u8,
+    pub repeat: u16,
+    pub data: Vec<u8>,
+    pub children: Vec<KlvEntry>,
+}
+
+/// Parses a GPMF payload. Trailing garbage (which some cameras write as zero padding) ends parsing
+/// rather than failing it.
+pub fn parse(data: &[u8]) -> Result<Vec<KlvEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut reader = ByteReader::new(data);
+    while reader.remaining() >= 8 {
+        let key = reader.read_fourcc()?;
+        if key == [0, 0, 0, 0] {
+            break;
+        }
+        let type_char = reader.read_u8()?;
+        let struct_size = reader.read_u8()?;
+        let repeat = reader.read_u16()?;
+        let length = struct_size as usize * repeat as usize;
+        let padded_length = length.div_ceil(4) * 4;
+        let value = reader.read_bytes(padded_length.min(reader.remaining()))?;
+        let value = &value[..length.min(value.len())];
+
+        let mut entry = KlvEntry {
+            key,
+            type_char,
+            struct_size,
+            repeat,
+            data: Vec::new(),
+            children: Vec::new(),
+        };
+        if type_char == 0 {
+            entry.children = parse(value)?;
+        } else {
+            entry.data = value.to_vec();
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Size in bytes of a single value of the given GPMF type
+fn type_size(type_char: u8) -> Option<usize> {
+    match type_char {
+        b'b' | b'B' | b'c' => Some(1),
+        b's' | b'S' => Some(2),
+        b'f' | b'F' | b'l' | b'L' | b'q' => Some(4),
+        b'd' | b'j' | b'J' | b'Q' => Some(8),
+        b'G' | b'U' => Some(16),
+        _ => None,
+    }
+}
+
+fn read_value(type_char: u8, bytes: &[u8]) -> Option<f64> {
+    Some(match type_char {
+        b'b' => bytes[0] as i8 as f64,
+        b'B' => bytes[0] as f64,
+        b's' => i16::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'S' => u16::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'l' => i32::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'L' => u32::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'f' => f32::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'd' => f64::from_be_bytes(bytes.try_into().ok()?),
+        b'j' => i64::from_be_bytes(bytes.try_into().ok()?) as f64,
+        b'J' => u64::from_be_bytes(bytes.try_into().ok()?) as f64,
+        // Q15.16 and Q31.32 fixed point
+        b'q' => i32::from_be_bytes(bytes.try_into().ok()?) as f64 / 65536.0,
+        b'Q' => i64::from_be_bytes(bytes.try_into().ok()?) as f64 / 4294967296.0,
+        _ => return None,
+    })
+}
+
+impl KlvEntry {
+    pub fn child(&self, key: &FourCC) -> Option<&KlvEntry> {
+        self.children.iter().find(|c| &c.key == key)
+    }
+
+    pub fn children_of_type<'a>(&'a self, key: &'a FourCC) -> impl Iterator<Item = &'a KlvEntry> {
+        self.children.iter().filter(move |c| &c.key == key)
+    }
+
+    /// The value as a string, for 'c' (char) and 'U' (UTC date) entries
+    pub fn as_string(&self) -> String {
+        String::from_utf8_lossy(&self.data)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string()
+    }
+
+    /// Decodes every sample into a row of numbers. Complex ('?') entries need the TYPE string from
+    /// their STRM, which describes the type of each element in a sample.
+    pub fn values(&self, complex_type: Option<&str>) -> Vec<Vec<f64>> {
+        let element_types: Vec<u8> = match (self.type_char, complex_type) {
+            (b'?', Some(complex_type)) => complex_type.bytes().collect(),
+            (b'?', None) => return Vec::new(),
+            (type_char, _) => match type_size(type_char) {
+                Some(size) if size > 0 => {
+                    vec![type_char; self.struct_size as usize / size]
+                }
+                _ => return Vec::new(),
+            },
+        };
+
+        let mut rows = Vec::with_capacity(self.repeat as usize);
+        for sample in self.data.chunks_exact(self.struct_size.max(1) as usize) {
+            let mut row = Vec::with_capacity(element_types.len());
+            let mut position = 0;
+            for &type_char in &element_types {
+                let size = match type_size(type_char) {
+                    Some(size) => size,
+                    None => break,
+                };
+                match sample
+                    .get(position..position + size)
+                    .and_then(|bytes| read_value(type_char, bytes))
+                {
+                    Some(value) => row.push(value),
+                    None => break,
+                }
+                position += size;
+            }
+            rows.push(row);
+        }
+        rows
+    }
+}
+
+/// A STRM entry, with the metadata needed to interpret its samples
+#[derive(Debug, Clone, Copy)]
+pub struct Stream<'a> {
+    pub strm: &'a KlvEntry,
+    pub data: &'a KlvEntry,
+}
+
+impl<'a> Stream<'a> {
+    /// Finds the stream that carries `key` (e.g. GPS5) among the DEVC entries of a payload
+    pub fn find(devices: &'a [KlvEntry], key: &FourCC) -> Option<Stream<'a>> {
+        devices
+            .iter()
+            .filter(|e| &e.key == b"DEVC")
+            .flat_map(|devc| devc.children_of_type(b"STRM"))
+            .find_map(|strm| strm.child(key).map(|data| Stream { strm, data }))
+    }
+
+    pub fn metadata(&self, key: &FourCC) -> Option<&'a KlvEntry> {
+        self.strm.child(key)
+    }
+
+    /// The stream's samples with SCAL applied. SCAL has either one divisor for every element, or
+    /// one per element.
+    pub fn scaled_values(&self) -> Vec<Vec<f64>> {
+        let complex_type = self.metadata(b"TYPE").map(|t| t.as_string());
+        let scales: Vec<f64> = self
+            .metadata(b"SCAL")
+            .map(|scal| scal.values(None).into_iter().flatten().collect())
+            .unwrap_or_default();
+        let mut rows = self.data.values(complex_type.as_deref());
+        for row in rows.iter_mut() {
+            for (i, value) in row.iter_mut().enumerate() {
+                let scale = match scales.len() {
+                    0 => 1.0,
+                    1 => scales[0],
+                    _ => scales.get(i).copied().unwrap_or(1.0),
+                };
+                if scale != 0.0 {
+                    *value /= scale;
+                }
+            }
+        }
+        rows
+    }
+
+    /// Units for each element of a sample, from SIUN (SI units) or UNIT (display units)
+    pub fn units(&self) -> Vec<String> {
+        let units = match self.metadata(b"SIUN").or_else(|| self.metadata(b"UNIT")) {
+            Some(units) => units,
+            None => return Vec::new(),
+        };
+        units
+            .data
+            .chunks(units.struct_size.max(1) as usize)
+            // Units are Latin-1, e.g. 0xB0 for the degree sign and 0xB2 for squared
+            .map(|unit| {
+                unit.iter()
+                    .map(|&c| c as char)
+                    .collect::<String>()
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{klv, klv_i32};
+
+    fn payload(strm: Vec<Vec<u8>>) -> Vec<KlvEntry> {
+        let strm = klv(b"STRM", 0, 1, &strm.concat());
+        parse(&klv(b"DEVC", 0, 1, &strm)).unwrap()
+    }
+
+    #[test]
+    fn parses_nested_entries_and_stops_at_padding() {
+        let mut data = klv(b"DEVC", 0, 1, &klv(b"DVNM", b'c', 1, b"Camera"));
+        data.extend_from_slice(&[0; 12]);
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 1);
+        let dvnm = entries[0].child(b"DVNM").unwrap();
+        assert_eq!(dvnm.as_string(), "Camera");
+        // "Camera" is padded to 8 bytes, but the padding isn't part of the value
+        assert_eq!(dvnm.data.len(), 6);
+    }
+
+    #[test]
+    fn truncated_entry_keeps_what_is_there() {
+        let mut data = klv_i32(b"ACCL", 3, &[1, 2, 3, 4, 5, 6]);
+        data.truncate(8 + 16);
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries[0].values(None), [vec![1.0, 2.0, 3.0]]);
+    }
+
+    #[test]
+    fn scales_each_element() {
+        let devices = payload(vec![
+            klv(b"STNM", b'c', 1, b"GPS (Lat., Long., Alt., 2D, 3D)"),
+            klv_i32(b"SCAL", 1, &[10_000_000, 10_000_000, 1000, 1000, 100]),
+            klv(b"UNIT", b'c', 3, b"degdegm\0\0m/sm/s"),
+            klv_i32(b"GPS5", 5, &[515_000_000, -1_250_000, 42_500, 3_000, 310]),
+        ]);
+        let stream = Stream::find(&devices, b"GPS5").unwrap();
+        assert_eq!(stream.scaled_values(), [vec![51.5, -0.125, 42.5, 3.0, 3.1]]);
+        assert_eq!(stream.units(), ["deg", "deg", "m", "m/s", "m/s"]);
+        assert!(Stream::find(&devices, b"ACCL").is_none());
+    }
+
+    #[test]
+    fn reads_complex_samples_by_their_type() {
+        let mut sample = 7i32.to_be_bytes().to_vec();
+        sample.extend_from_slice(&300u16.to_be_bytes());
+        sample.push(0xff);
+        let devices = payload(vec![
+            klv(b"TYPE", b'c', 1, b"lSb"),
+            klv_i32(b"SCAL", 1, &[1, 2, 1]),
+            klv(b"MIXD", b'?', 7, &sample),
+        ]);
+        let stream = Stream::find(&devices, b"MIXD").unwrap();
+        assert_eq!(stream.scaled_values(), [vec![7.0, 150.0, -1.0]]);
+    }
+}
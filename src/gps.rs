@@ -0,0 +1,360 @@
+// Exports the GPS track embedded in GPMF telemetry as GPX or KML, one track per assembled video.
+//
+// Older cameras write GPS5 (lat, lon, alt, 2D speed, 3D speed) with one GPSU timestamp and one GPSF
+// fix per payload. HERO11 and newer write GPS9, which timestamps and fixes every sample.
+//
+// Point times are aligned to the video timeline: the first GPS timestamp is used as an anchor, and
+// every point is placed at anchor + (its offset in the video - the anchor's offset in the video).
+// This keeps the track continuous across chapter boundaries.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::Path;
+
+use colored::Colorize;
+use log::{info, warn};
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::cli::GpsFormat;
+use crate::gopro::{gen_output_path, GoProChapteredVideoFile, VideoId};
+use crate::gpmf::Stream;
+use crate::telemetry::read_payloads;
+
+// GPSF values below this mean there's no 2D or 3D lock
+const MIN_GPS_FIX: f64 = 2.0;
+
+#[derive(Debug, Clone)]
+pub struct GpsPoint {
+    /// Seconds from the start of the assembled video
+    pub video_time: f64,
+    pub utc: Option<OffsetDateTime>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+}
+
+pub fn read_gps_track(chapters: &[GoProChapteredVideoFile]) -> Result<Vec<GpsPoint>, Error> {
+    let mut points = Vec::new();
+    let mut anchor: Option<(f64, OffsetDateTime)> = None;
+
+    for payload in read_payloads(chapters)? {
+        if let Some(stream) = Stream::find(&payload.entries, b"GPS9") {
+            // lat, lon, alt, 2D speed, 3D speed, days since 2000, seconds since midnight, DOP, fix
+            let rows = stream.scaled_values();
+            for (index, row) in rows.iter().enumerate() {
+                if row.len() < 9 || row[8] < MIN_GPS_FIX {
+                    continue;
+                }
+                let video_time = payload.sample_time(index, rows.len());
+                if anchor.is_none() {
+                    anchor = gps9_time(row[5], row[6]).map(|utc| (video_time, utc));
+                }
+                points.push(new_point(video_time, row));
+            }
+        } else if let Some(stream) = Stream::find(&payload.entries, b"GPS5") {
+            let fix = stream
+                .metadata(b"GPSF")
+                .and_then(|gpsf| gpsf.values(None).first()?.first().copied())
+                .unwrap_or(0.0);
+            if fix < MIN_GPS_FIX {
+                continue;
+            }
+            if anchor.is_none() {
+                anchor = stream
+                    .metadata(b"GPSU")
+                    .and_then(|gpsu| parse_gpsu(&gpsu.as_string()))
+                    .map(|utc| (payload.time, utc));
+            }
+            let rows = stream.scaled_values();
+            for (index, row) in rows.iter().enumerate() {
+                if row.len() >= 3 {
+                    points.push(new_point(payload.sample_time(index, rows.len()), row));
+                }
+            }
+        }
+    }
+
+    // A fix at (0, 0) is the camera reporting a lock before it has a position
+    points.retain(|p| p.latitude != 0.0 || p.longitude != 0.0);
+    if let Some((anchor_video_time, anchor_utc)) = anchor {
+        for point in points.iter_mut() {
+            point.utc = Duration::checked_seconds_f64(point.video_time - anchor_video_time)
+                .and_then(|offset| anchor_utc.checked_add(offset));
+        }
+    }
+    Ok(points)
+}
+
+fn new_point(video_time: f64, row: &[f64]) -> GpsPoint {
+    GpsPoint {
+        video_time,
+        utc: None,
+        latitude: row[0],
+        longitude: row[1],
+        altitude: row[2],
+    }
+}
+
+/// GPSU is a UTC timestamp formatted as yymmddhhmmss.sss
+fn parse_gpsu(gpsu: &str) -> Option<OffsetDateTime> {
+    let field = |range: std::ops::Range<usize>| gpsu.get(range)?.parse::<u8>().ok();
+    let date = Date::from_calendar_date(
+        2000 + field(0..2)? as i32,
+        Month::try_from(field(2..4)?).ok()?,
+        field(4..6)?,
+    )
+    .ok()?;
+    let seconds: f64 = gpsu.get(10..)?.parse().ok()?;
+    let time = Time::from_hms_milli(
+        field(6..8)?,
+        field(8..10)?,
+        seconds.trunc() as u8,
+        (seconds.fract() * 1000.0).round().min(999.0) as u16,
+    )
+    .ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_utc())
+}
+
+/// None if the values are out of range, which happens when a sample is corrupt
+fn gps9_time(days_since_2000: f64, seconds_since_midnight: f64) -> Option<OffsetDateTime> {
+    let epoch = PrimitiveDateTime::new(
+        Date::from_calendar_date(2000, Month::January, 1).ok()?,
+        Time::MIDNIGHT,
+    )
+    .assume_utc();
+    epoch
+        .checked_add(Duration::checked_seconds_f64(
+            days_since_2000.trunc() * 86_400.0,
+        )?)?
+        .checked_add(Duration::checked_seconds_f64(seconds_since_midnight)?)
+}
+
+// Millisecond precision is plenty for GPS, and keeps the files readable
+fn format_utc(utc: &OffsetDateTime) -> String {
+    utc.replace_millisecond(utc.millisecond())
+        .unwrap_or(*utc)
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+pub fn write_gpx(path: &Path, name: &str, points: &[GpsPoint]) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<gpx version="1.1" creator="{} {}" xmlns="http://www.topografix.com/GPX/1/1">"#,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(out, "  <trk>\n    <name>{}</name>\n    <trkseg>", name)?;
+    for point in points {
+        write!(
+            out,
+            r#"      <trkpt lat="{:.7}" lon="{:.7}"><ele>{:.3}</ele>"#,
+            point.latitude, point.longitude, point.altitude
+        )?;
+        if let Some(utc) = &point.utc {
+            write!(out, "<time>{}</time>", format_utc(utc))?;
+        }
+        writeln!(out, "</trkpt>")?;
+    }
+    writeln!(out, "    </trkseg>\n  </trk>\n</gpx>")?;
+    out.flush()
+}
+
+pub fn write_kml(path: &Path, name: &str, points: &[GpsPoint]) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<kml xmlns="http://www.opengis.net/kml/2.2" xmlns:gx="http://www.google.com/kml/ext/2.2">"#
+    )?;
+    writeln!(
+        out,
+        "  <Document>\n    <name>{}</name>\n    <Placemark>\n      <name>{}</name>",
+        name, name
+    )?;
+    // gx:Track needs a timestamp for every coordinate. Without one, fall back to a plain LineString.
+    if points.iter().all(|p| p.utc.is_some()) {
+        writeln!(
+            out,
+            "      <gx:Track>\n        <altitudeMode>absolute</altitudeMode>"
+        )?;
+        for point in points {
+            writeln!(
+                out,
+                "        <when>{}</when>",
+                format_utc(point.utc.as_ref().unwrap())
+            )?;
+        }
+        for point in points {
+            writeln!(
+                out,
+                "        <gx:coord>{:.7} {:.7} {:.3}</gx:coord>",
+                point.longitude, point.latitude, point.altitude
+            )?;
+        }
+        writeln!(out, "      </gx:Track>")?;
+    } else {
+        writeln!(
+            out,
+            "      <LineString>\n        <altitudeMode>absolute</altitudeMode>\n        <coordinates>"
+        )?;
+        for point in points {
+            writeln!(
+                out,
+                "          {:.7},{:.7},{:.3}",
+                point.longitude, point.latitude, point.altitude
+            )?;
+        }
+        writeln!(out, "        </coordinates>\n      </LineString>")?;
+    }
+    writeln!(out, "    </Placemark>\n  </Document>\n</kml>")?;
+    out.flush()
+}
+
+/// Writes a GoPro_{video_number}.gpx/.kml track for every video that has a GPS fix. Returns false
+/// if any video's telemetry couldn't be read or its track couldn't be written.
+pub fn export_gps_tracks(
+    videos: &std::collections::HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_dir: &Path,
+    format: GpsFormat,
+) -> bool {
+    let mut all_exported = true;
+    let mut video_ids: Vec<&VideoId> = videos.keys().collect();
+    video_ids.sort();
+    for video in video_ids {
+        let points = match read_gps_track(&videos[video]) {
+            Ok(points) => points,
+            Err(e) => {
+                warn!("Could not read telemetry for {}: {}", video, e);
+                all_exported = false;
+                continue;
+            }
+        };
+        if points.is_empty() {
+            warn!("{} has no GPS fix, skipping", video);
+            continue;
+        }
+
+        let extension = match format {
+            GpsFormat::Gpx => "gpx",
+            GpsFormat::Kml => "kml",
+        };
+        let output_path = gen_output_path(output_dir, video, extension);
+        let name = video.to_string();
+        let result = match format {
+            GpsFormat::Gpx => write_gpx(&output_path, &name, &points),
+            GpsFormat::Kml => write_kml(&output_path, &name, &points),
+        };
+        match result {
+            Ok(()) => info!(
+                "Wrote {} GPS points to {}",
+                points.len(),
+                output_path.to_string_lossy().blue().bold()
+            ),
+            Err(e) => {
+                warn!("Failed to write {}: {}", output_path.display(), e);
+                all_exported = false;
+            }
+        }
+    }
+    all_exported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{klv, klv_i32, write_mp4, FixtureTrack, TempDir};
+    use time::macros::datetime;
+
+    /// A GPS5 payload with one sample, `millidegrees` north and east
+    fn gps5_payload(gpsu: &str, fix: i32, millidegrees: i32) -> Vec<u8> {
+        let strm = [
+            klv_i32(b"GPSF", 1, &[fix]),
+            klv(b"GPSU", b'c', 16, gpsu.as_bytes()),
+            klv_i32(b"SCAL", 1, &[1000, 1000, 1, 1, 1]),
+            klv_i32(b"GPS5", 5, &[millidegrees, millidegrees, 10, 0, 0]),
+        ];
+        klv(b"DEVC", 0, 1, &klv(b"STRM", 0, 1, &strm.concat()))
+    }
+
+    fn chapter(path: std::path::PathBuf, chapter: u16) -> GoProChapteredVideoFile {
+        GoProChapteredVideoFile {
+            abs_path: path,
+            video_number: 1,
+            chapter,
+            camera: None,
+            session: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn parses_gpsu() {
+        assert_eq!(
+            parse_gpsu("240601143005.250"),
+            Some(datetime!(2024-06-01 14:30:05.250 UTC))
+        );
+        assert_eq!(
+            parse_gpsu("240601143005"),
+            Some(datetime!(2024-06-01 14:30:05 UTC))
+        );
+        assert_eq!(parse_gpsu("241301143005.000"), None);
+        assert_eq!(parse_gpsu(""), None);
+    }
+
+    #[test]
+    fn gps9_time_counts_from_2000() {
+        // 2024-06-01 is 8918 days after 2000-01-01
+        assert_eq!(
+            gps9_time(8918.0, 52205.25),
+            Some(datetime!(2024-06-01 14:30:05.250 UTC))
+        );
+    }
+
+    #[test]
+    fn corrupt_gps9_time_is_none() {
+        assert_eq!(gps9_time(f64::MAX, 0.0), None);
+        assert_eq!(gps9_time(0.0, f64::NAN), None);
+        assert_eq!(gps9_time(-1e12, 0.0), None);
+    }
+
+    #[test]
+    fn track_continues_across_chapters() {
+        let dir = TempDir::new();
+        let (first, second) = (dir.join("GH010001.MP4"), dir.join("GH020001.MP4"));
+        write_mp4(
+            &first,
+            &[FixtureTrack::telemetry(vec![
+                gps5_payload("240601143000.000", 3, 1000),
+                // No lock, so this second is left out
+                gps5_payload("240601143001.000", 0, 2000),
+            ])],
+            Vec::new(),
+        );
+        write_mp4(
+            &second,
+            &[FixtureTrack::telemetry(vec![gps5_payload(
+                "240601143002.000",
+                3,
+                3000,
+            )])],
+            Vec::new(),
+        );
+
+        let points = read_gps_track(&[chapter(first, 1), chapter(second, 2)]).unwrap();
+        let summary: Vec<_> = points
+            .iter()
+            .map(|p| (p.video_time, p.latitude, p.utc))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0.0, 1.0, Some(datetime!(2024-06-01 14:30:00 UTC))),
+                (2.0, 3.0, Some(datetime!(2024-06-01 14:30:02 UTC))),
+            ]
+        );
+    }
+}
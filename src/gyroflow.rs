@@ -0,0 +1,186 @@
+// Generates a Gyroflow project (https://gyroflow.xyz) next to an assembled video, so batch
+// stabilisation can start from the project instead of loading and analysing each file by hand.
+//
+// The telemetry is read back out of the assembled file itself rather than its chapters, so the gyro
+// timeline is exactly the one Gyroflow will see when it loads the video.
+//
+// Gyroflow expects gyro in deg/s and accelerometer in g. GoPro records rad/s and m/s².
+
+use std::fs::File;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::gpmf;
+use crate::metadata::lens_mode_name;
+use crate::mp4::Movie;
+use crate::telemetry::{collect_stream, find_entry, read_payloads_from_files, StreamSamples};
+
+const STANDARD_GRAVITY: f64 = 9.80665;
+
+/// Pairs every gyro sample with the accelerometer sample closest in time. GoPro records both at
+/// roughly the same rate, but not in lockstep.
+fn build_raw_imu(gyro: &StreamSamples, accl: &StreamSamples) -> Vec<serde_json::Value> {
+    let mut accl_index = 0;
+    gyro.samples
+        .iter()
+        .map(|(time, gyro_values)| {
+            while accl_index + 1 < accl.samples.len()
+                && (accl.samples[accl_index + 1].0 - time).abs()
+                    <= (accl.samples[accl_index].0 - time).abs()
+            {
+                accl_index += 1;
+            }
+            let accl_values: Option<Vec<f64>> = accl
+                .samples
+                .get(accl_index)
+                .map(|(_, values)| values.iter().map(|v| v / STANDARD_GRAVITY).collect());
+            json!({
+                "timestamp_ms": time * 1000.0,
+                "gyro": gyro_values.iter().map(|v| v.to_degrees()).collect::<Vec<f64>>(),
+                "accl": accl_values,
+            })
+        })
+        .collect()
+}
+
+/// Writes {video}.gyroflow next to the video and returns its path
+pub fn write_gyroflow_project(video_path: &Path) -> Result<PathBuf, Error> {
+    let movie = Movie::read(video_path)?;
+    let payloads = read_payloads_from_files(&[video_path])?;
+    let gyro = collect_stream(&payloads, b"GYRO");
+    if gyro.samples.is_empty() {
+        return Err(Error::other("no gyro data in the telemetry track"));
+    }
+    let accl = collect_stream(&payloads, b"ACCL");
+
+    let first_payload = payloads
+        .first()
+        .map(|p| p.entries.as_slice())
+        .unwrap_or(&[]);
+    // Newer cameras also write their settings to a GPMF box in udta
+    let udta_gpmf = movie
+        .moov
+        .find(&[b"udta", b"GPMF"])
+        .and_then(|gpmf| gpmf::parse(&gpmf.payload).ok())
+        .unwrap_or_default();
+    let camera_model = find_entry(first_payload, b"DVNM")
+        .or_else(|| find_entry(&udta_gpmf, b"MINF"))
+        .map(|e| e.as_string());
+    let lens_mode = find_entry(first_payload, b"VFOV")
+        .or_else(|| find_entry(&udta_gpmf, b"VFOV"))
+        .map(|e| lens_mode_name(&e.as_string()));
+
+    let video_track = movie.video_track();
+    let (width, height) = video_track.and_then(|t| t.dimensions()).unwrap_or_default();
+    let fps = match video_track {
+        Some(track) => track.frame_rate()?,
+        None => 0.0,
+    };
+
+    let project = json!({
+        "title": "Gyroflow data file",
+        "version": 2,
+        "generator": format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        "videofile": video_path,
+        "calibration_data": {
+            "camera_brand": "GoPro",
+            "camera_model": camera_model,
+            "lens_model": lens_mode,
+        },
+        "video_info": {
+            "width": width,
+            "height": height,
+            "rotation": 0,
+            "num_frames": (movie.duration_secs() * fps).round() as u64,
+            "fps": fps,
+            "duration_ms": movie.duration_secs() * 1000.0,
+        },
+        "gyro_source": {
+            "filepath": video_path,
+            "imu_orientation": gyro.orientation.clone().unwrap_or_else(|| "XYZ".to_string()),
+            "raw_imu": build_raw_imu(&gyro, &accl),
+        },
+    });
+
+    let project_path = video_path.with_extension("gyroflow");
+    serde_json::to_writer(File::create(&project_path)?, &project).map_err(Error::other)?;
+    Ok(project_path)
+}
+
+/// Writes a project for each video, skipping (with a warning) any without gyro data
+pub fn write_gyroflow_projects(videos: &[PathBuf]) -> Vec<PathBuf> {
+    let mut projects = Vec::new();
+    for video in videos {
+        match write_gyroflow_project(video) {
+            Ok(project) => {
+                info!(
+                    "Wrote Gyroflow project {}",
+                    project.to_string_lossy().blue().bold()
+                );
+                projects.push(project);
+            }
+            Err(e) => warn!(
+                "Could not write a Gyroflow project for {}: {}",
+                video.display(),
+                e
+            ),
+        }
+    }
+    projects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(samples: &[(f64, [f64; 3])]) -> StreamSamples {
+        StreamSamples {
+            samples: samples
+                .iter()
+                .map(|(time, values)| (*time, values.to_vec()))
+                .collect(),
+            ..StreamSamples::default()
+        }
+    }
+
+    #[test]
+    fn converts_to_the_units_gyroflow_expects() {
+        let gyro = stream(&[(
+            0.5,
+            [std::f64::consts::PI, -std::f64::consts::FRAC_PI_2, 0.0],
+        )]);
+        let accl = stream(&[(0.5, [STANDARD_GRAVITY, 0.0, -2.0 * STANDARD_GRAVITY])]);
+        assert_eq!(
+            build_raw_imu(&gyro, &accl),
+            [json!({
+                "timestamp_ms": 500.0,
+                "gyro": [180.0, -90.0, 0.0],
+                "accl": [1.0, 0.0, -2.0],
+            })]
+        );
+    }
+
+    #[test]
+    fn pairs_each_gyro_sample_with_the_nearest_accelerometer_sample() {
+        let gyro = stream(&[
+            (0.0, [0.0; 3]),
+            (0.1, [0.0; 3]),
+            (0.2, [0.0; 3]),
+            (0.3, [0.0; 3]),
+        ]);
+        let accl_g = |g: f64| [g * STANDARD_GRAVITY, 0.0, 0.0];
+        let accl = stream(&[(0.02, accl_g(1.0)), (0.14, accl_g(2.0)), (0.4, accl_g(3.0))]);
+        let paired: Vec<_> = build_raw_imu(&gyro, &accl)
+            .iter()
+            .map(|sample| sample["accl"][0].as_f64().unwrap())
+            .collect();
+        assert_eq!(paired, [1.0, 2.0, 2.0, 3.0]);
+
+        let unpaired = build_raw_imu(&gyro, &StreamSamples::default());
+        assert!(unpaired.iter().all(|sample| sample["accl"].is_null()));
+    }
+}
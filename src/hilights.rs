@@ -0,0 +1,196 @@
+// GoPro HiLight tags: moments marked while recording, by pressing the mode button, saying "GoPro
+// HiLight" or tapping the app. Each chapter only knows about its own, as milliseconds from the start
+// of that chapter, so they're rebased onto the assembled video's timeline here.
+//
+// HERO5 to HERO7 write them to an HMMT box in udta: a u32 count followed by a u32 per tag (the box
+// is zero padded to a fixed size). Newer cameras write an HLMT entry to the GPMF box in udta instead,
+// holding one MANL entry per tag.
+
+use std::fs::File;
+use std::io::Error;
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::chapters::ChapterMarker;
+use crate::gopro::GoProChapteredVideoFile;
+use crate::gpmf;
+use crate::mp4::{ByteReader, Movie, Mp4Box};
+use crate::telemetry::find_entry;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HiLight {
+    /// Seconds from the start of the assembled video
+    pub time: f64,
+    /// The chapter it was tagged in
+    pub chapter: String,
+    /// Seconds from the start of that chapter
+    pub chapter_time: f64,
+}
+
+impl HiLight {
+    pub fn to_chapter_marker(&self, number: usize) -> ChapterMarker {
+        ChapterMarker {
+            start: self.time,
+            title: format!("HiLight {}", number),
+        }
+    }
+}
+
+/// The HiLights in [start, end), moved so `start` is 0
+pub fn hilights_in_range(hilights: &[HiLight], start: f64, end: f64) -> Vec<HiLight> {
+    hilights
+        .iter()
+        .filter(|h| h.time >= start && h.time < end)
+        .map(|h| HiLight {
+            time: h.time - start,
+            ..h.clone()
+        })
+        .collect()
+}
+
+/// Every HiLight of a video, in order, on the assembled video's timeline
+pub fn read_hilights(chapters: &[GoProChapteredVideoFile]) -> Result<Vec<HiLight>, Error> {
+    let mut hilights = Vec::new();
+    let mut chapter_start = 0.0;
+    for chapter in chapters {
+        let movie = Movie::read(&chapter.abs_path)?;
+        let chapter_name = chapter
+            .abs_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut tags_ms = hilights_in_moov(&movie.moov);
+        tags_ms.sort();
+        for ms in tags_ms {
+            let chapter_time = ms as f64 / 1000.0;
+            hilights.push(HiLight {
+                time: chapter_start + chapter_time,
+                chapter: chapter_name.clone(),
+                chapter_time,
+            });
+        }
+        chapter_start += movie.duration_secs();
+    }
+    Ok(hilights)
+}
+
+/// HiLight times in milliseconds, from HMMT or from HLMT in the udta GPMF box
+fn hilights_in_moov(moov: &Mp4Box) -> Vec<u32> {
+    if let Some(hmmt) = moov.find(&[b"udta", b"HMMT"]) {
+        let mut reader = ByteReader::new(&hmmt.payload);
+        let count = reader.read_u32().unwrap_or(0);
+        return (0..count).map_while(|_| reader.read_u32().ok()).collect();
+    }
+    let udta_gpmf = moov
+        .find(&[b"udta", b"GPMF"])
+        .and_then(|gpmf| gpmf::parse(&gpmf.payload).ok())
+        .unwrap_or_default();
+    match find_entry(&udta_gpmf, b"HLMT") {
+        Some(hlmt) => hlmt
+            .children_of_type(b"MANL")
+            .filter_map(|manl| manl.values(None).first()?.first().copied())
+            .map(|ms| ms as u32)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Writes the HiLights of an assembled video as JSON, for editors that can import markers
+pub fn write_hilight_sidecar(
+    path: &Path,
+    video_path: &Path,
+    hilights: &[HiLight],
+) -> Result<(), Error> {
+    let sidecar = json!({
+        "video": video_path.file_name().map(|name| name.to_string_lossy()),
+        "hilights": hilights,
+    });
+    serde_json::to_writer_pretty(File::create(path)?, &sidecar).map_err(Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{klv, mp4_box, write_mp4, FixtureTrack, TempDir};
+
+    /// An HMMT box as HERO5 to HERO7 write it: a count, the tags, then zero padding
+    fn hmmt(tags_ms: &[u32]) -> Vec<u8> {
+        let mut payload = (tags_ms.len() as u32).to_be_bytes().to_vec();
+        payload.extend(tags_ms.iter().flat_map(|ms| ms.to_be_bytes()));
+        payload.resize(4 + 100 * 4, 0);
+        mp4_box(b"HMMT", &payload)
+    }
+
+    fn chapter(path: std::path::PathBuf, chapter: u16) -> GoProChapteredVideoFile {
+        GoProChapteredVideoFile {
+            abs_path: path,
+            video_number: 1234,
+            chapter,
+            camera: None,
+            session: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn reads_hmmt_up_to_its_count() {
+        let dir = TempDir::new();
+        let path = dir.join("GH011234.MP4");
+        write_mp4(
+            &path,
+            &[FixtureTrack::video(60, 30)],
+            vec![hmmt(&[1500, 62000])],
+        );
+        let movie = Movie::read(&path).unwrap();
+        assert_eq!(hilights_in_moov(&movie.moov), [1500, 62000]);
+    }
+
+    #[test]
+    fn reads_manl_entries_in_hlmt() {
+        let dir = TempDir::new();
+        let path = dir.join("GX011234.MP4");
+        let manl: Vec<u8> = [2500u32, 300]
+            .iter()
+            .flat_map(|ms| klv(b"MANL", b'L', 4, &ms.to_be_bytes()))
+            .collect();
+        let udta_gpmf = klv(b"HLMT", 0, 1, &manl);
+        write_mp4(
+            &path,
+            &[FixtureTrack::video(60, 30)],
+            vec![mp4_box(b"GPMF", &udta_gpmf)],
+        );
+        let movie = Movie::read(&path).unwrap();
+        assert_eq!(hilights_in_moov(&movie.moov), [2500, 300]);
+
+        write_mp4(&path, &[FixtureTrack::video(60, 30)], Vec::new());
+        assert!(hilights_in_moov(&Movie::read(&path).unwrap().moov).is_empty());
+    }
+
+    #[test]
+    fn later_chapters_are_rebased_onto_the_assembled_video() {
+        let dir = TempDir::new();
+        let (first, second) = (dir.join("GH011234.MP4"), dir.join("GH021234.MP4"));
+        write_mp4(&first, &[FixtureTrack::video(60, 30)], vec![hmmt(&[500])]);
+        write_mp4(
+            &second,
+            &[FixtureTrack::video(60, 30)],
+            vec![hmmt(&[1500, 1000])],
+        );
+
+        let hilights = read_hilights(&[chapter(first, 1), chapter(second, 2)]).unwrap();
+        let fields: Vec<_> = hilights
+            .iter()
+            .map(|h| (h.time, h.chapter.as_str(), h.chapter_time))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (0.5, "GH011234.MP4", 0.5),
+                (3.0, "GH021234.MP4", 1.0),
+                (3.5, "GH021234.MP4", 1.5),
+            ]
+        );
+    }
+}
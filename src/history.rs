@@ -0,0 +1,204 @@
+// Keeps a catalog of every chapter we've already assembled, so re-inserting a card that still holds
+// last week's footage doesn't reassemble all of it.
+//
+// Chapters are identified by a fingerprint of their size, first MiB and last MiB rather than a full
+// hash. Hashing every multi-GB chapter on every run would take longer than the merge itself, and
+// the start/end of an MP4 (header, moov, mdat edges) is unique enough to tell recordings apart.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+
+const HISTORY_FILE_NAME: &str = "import_history.json";
+const FINGERPRINT_WINDOW_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportedChapter {
+    pub file_name: String,
+    pub video_number: u16,
+    pub chapter: u16,
+    pub output: PathBuf,
+    /// Seconds since the UNIX epoch
+    pub imported_at: u64,
+    /// The output's size and BLAKE3 when it was written, so `clean` can tell it's still the same
+    /// file. Histories written by older versions don't have them.
+    pub output_size: Option<u64>,
+    pub output_blake3: Option<String>,
+    /// A trimmed output doesn't hold all of the chapter
+    #[serde(default)]
+    pub trimmed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportHistory {
+    /// Chapter fingerprint -> where it ended up
+    pub chapters: HashMap<String, ImportedChapter>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl ImportHistory {
+    /// Loads the history from the XDG data directory. A missing file is an empty history.
+    pub fn load() -> Result<Self, Error> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix(env!("CARGO_PKG_NAME"))?;
+        let path = xdg_dirs.place_data_file(HISTORY_FILE_NAME)?;
+        let mut history = match File::open(&path) {
+            Ok(file) => serde_json::from_reader::<_, ImportHistory>(BufReader::new(file))
+                .map_err(Error::other)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ImportHistory::default(),
+            Err(e) => return Err(e),
+        };
+        history.path = path;
+        Ok(history)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the history next to where it goes and then renames it into place, so a crash
+    /// partway through never leaves a half-written history behind. `clean` trusts it to decide
+    /// what's safe to delete.
+    pub fn save(&self) -> Result<(), Error> {
+        let temp_path = self.path.with_extension("json.tmp");
+        let mut file = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer_pretty(&mut file, self).map_err(Error::other)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+
+    /// A video counts as imported only if every one of its chapters has been imported before.
+    pub fn contains_all(&self, chapters: &[GoProChapteredVideoFile]) -> bool {
+        chapters.iter().all(|chapter| {
+            fingerprint_chapter(&chapter.abs_path)
+                .map(|fingerprint| self.chapters.contains_key(&fingerprint))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Where a chapter was imported to, or None if it hasn't been
+    pub fn imported_chapter(&self, chapter: &GoProChapteredVideoFile) -> Option<&ImportedChapter> {
+        let fingerprint = fingerprint_chapter(&chapter.abs_path).ok()?;
+        self.chapters.get(&fingerprint)
+    }
+
+    /// Splits off the videos that have already been imported, returning them.
+    pub fn remove_already_imported(
+        &self,
+        videos: &mut HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    ) -> HashMap<VideoId, Vec<GoProChapteredVideoFile>> {
+        let mut already_imported = videos.clone();
+        already_imported.retain(|_k, chapters| self.contains_all(chapters));
+        videos.retain(|k, _v| !already_imported.contains_key(k));
+        already_imported
+    }
+
+    /// `output_hash` is the output's size and BLAKE3, as in the manifest
+    pub fn record(
+        &mut self,
+        chapters: &[GoProChapteredVideoFile],
+        output: &Path,
+        output_hash: (u64, String),
+        trimmed: bool,
+    ) -> Result<(), Error> {
+        let imported_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        for chapter in chapters {
+            // Renamed chapters have moved to the output path, and still have the same fingerprint
+            let path = match chapter.abs_path.exists() {
+                true => chapter.abs_path.as_path(),
+                false => output,
+            };
+            let fingerprint = fingerprint_chapter(path)?;
+            self.chapters.insert(
+                fingerprint,
+                ImportedChapter {
+                    file_name: chapter
+                        .abs_path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string(),
+                    video_number: chapter.video_number,
+                    chapter: chapter.chapter,
+                    output: output.to_path_buf(),
+                    imported_at,
+                    output_size: Some(output_hash.0),
+                    output_blake3: Some(output_hash.1.clone()),
+                    trimmed,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+/// BLAKE3 over the file size, the first MiB, and the last MiB of the file
+pub fn fingerprint_chapter(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&size.to_le_bytes());
+
+    let mut buffer = Vec::new();
+    (&mut file)
+        .take(FINGERPRINT_WINDOW_BYTES)
+        .read_to_end(&mut buffer)?;
+    hasher.update(&buffer);
+
+    if size > FINGERPRINT_WINDOW_BYTES {
+        buffer.clear();
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(FINGERPRINT_WINDOW_BYTES)
+                .max(FINGERPRINT_WINDOW_BYTES),
+        ))?;
+        file.read_to_end(&mut buffer)?;
+        hasher.update(&buffer);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+
+    #[test]
+    fn save_replaces_the_history_without_leaving_a_temp_file() {
+        let dir = TempDir::new();
+        let path = dir.join(HISTORY_FILE_NAME);
+        fs::write(&path, "previous contents").unwrap();
+
+        let mut history = ImportHistory {
+            path: path.clone(),
+            ..Default::default()
+        };
+        history.chapters.insert(
+            "fingerprint".to_string(),
+            ImportedChapter {
+                file_name: "GH011234.MP4".to_string(),
+                video_number: 1234,
+                chapter: 1,
+                output: PathBuf::from("/out/GoPro_1234.mp4"),
+                imported_at: 0,
+                output_size: None,
+                output_blake3: None,
+                trimmed: false,
+            },
+        );
+        history.save().unwrap();
+
+        let saved: ImportHistory =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.chapters["fingerprint"].video_number, 1234);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
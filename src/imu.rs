@@ -0,0 +1,185 @@
+// Exports IMU telemetry (accelerometer, gyroscope, gravity vector, camera orientation) from GPMF as
+// CSV, one file per stream per video, with timestamps on the assembled video's timeline.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{info, warn};
+
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::mp4::FourCC;
+use crate::telemetry::{collect_stream, read_payloads, StreamSamples};
+
+/// The streams we export, with the column names to use when the camera doesn't write ORIN
+pub const IMU_STREAMS: [(&FourCC, &[&str]); 4] = [
+    (b"ACCL", &["x", "y", "z"]),
+    (b"GYRO", &["x", "y", "z"]),
+    (b"GRAV", &["x", "y", "z"]),
+    (b"CORI", &["w", "x", "y", "z"]),
+];
+
+/// Column headers like `z (m/s²)`. ORIN names the camera axis each element is measured on, and
+/// units are either one per element or one for the whole stream.
+fn column_headers(stream: &StreamSamples, default_columns: &[&str]) -> Vec<String> {
+    let width = stream
+        .samples
+        .first()
+        .map(|(_, values)| values.len())
+        .unwrap_or(default_columns.len());
+    let axes: Vec<String> = match &stream.orientation {
+        Some(orientation) if orientation.len() == width => orientation
+            .chars()
+            .map(|c| c.to_lowercase().to_string())
+            .collect(),
+        _ => (0..width)
+            .map(|i| match default_columns.get(i) {
+                Some(column) => column.to_string(),
+                None => format!("value{}", i),
+            })
+            .collect(),
+    };
+    axes.into_iter()
+        .enumerate()
+        .map(|(i, axis)| {
+            let unit = match stream.units.len() {
+                0 => None,
+                1 => stream.units.first(),
+                _ => stream.units.get(i),
+            };
+            match unit {
+                Some(unit) if !unit.is_empty() => format!("{} ({})", axis, unit),
+                _ => axis,
+            }
+        })
+        .collect()
+}
+
+pub fn write_csv(
+    path: &Path,
+    stream: &StreamSamples,
+    default_columns: &[&str],
+) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(
+        out,
+        "time (s),{}",
+        column_headers(stream, default_columns).join(",")
+    )?;
+    for (time, values) in &stream.samples {
+        let values: Vec<String> = values.iter().map(|v| format!("{:.6}", v)).collect();
+        writeln!(out, "{:.6},{}", time, values.join(","))?;
+    }
+    out.flush()
+}
+
+fn gen_csv_path(output_dir: &Path, video: &VideoId, key: &FourCC) -> PathBuf {
+    output_dir.join(format!("{}_{}.csv", video, String::from_utf8_lossy(key)))
+}
+
+/// Writes GoPro_{video_number}_{STREAM}.csv for every IMU stream each video has. Returns false if
+/// any video's telemetry couldn't be read or a CSV couldn't be written.
+pub fn export_imu_telemetry(
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_dir: &Path,
+) -> bool {
+    let mut all_exported = true;
+    let mut video_ids: Vec<&VideoId> = videos.keys().collect();
+    video_ids.sort();
+    for video in video_ids {
+        let payloads = match read_payloads(&videos[video]) {
+            Ok(payloads) => payloads,
+            Err(e) => {
+                warn!("Could not read telemetry for {}: {}", video, e);
+                all_exported = false;
+                continue;
+            }
+        };
+
+        for (key, default_columns) in IMU_STREAMS {
+            let stream = collect_stream(&payloads, key);
+            if stream.samples.is_empty() {
+                continue;
+            }
+            let output_path = gen_csv_path(output_dir, video, key);
+            match write_csv(&output_path, &stream, default_columns) {
+                Ok(()) => info!(
+                    "Wrote {} {} samples to {}",
+                    stream.samples.len(),
+                    stream
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| String::from_utf8_lossy(key).to_string()),
+                    output_path.to_string_lossy().blue().bold()
+                ),
+                Err(e) => {
+                    warn!("Failed to write {}: {}", output_path.display(), e);
+                    all_exported = false;
+                }
+            }
+        }
+    }
+    all_exported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+
+    fn stream(orientation: Option<&str>, units: &[&str], width: usize) -> StreamSamples {
+        StreamSamples {
+            name: None,
+            orientation: orientation.map(str::to_string),
+            units: units.iter().map(|unit| unit.to_string()).collect(),
+            samples: vec![(0.0, vec![0.0; width])],
+        }
+    }
+
+    #[test]
+    fn headers_name_the_camera_axes_from_orin() {
+        let accl = stream(Some("ZXY"), &["m/s²"], 3);
+        assert_eq!(
+            column_headers(&accl, &["x", "y", "z"]),
+            ["z (m/s²)", "x (m/s²)", "y (m/s²)"]
+        );
+        // ORIN that doesn't match the sample width is ignored
+        let cori = stream(Some("ZXY"), &[], 4);
+        assert_eq!(
+            column_headers(&cori, &["w", "x", "y", "z"]),
+            ["w", "x", "y", "z"]
+        );
+    }
+
+    #[test]
+    fn headers_take_units_per_element_or_for_the_stream() {
+        let per_element = stream(None, &["rad/s", "", "deg"], 4);
+        assert_eq!(
+            column_headers(&per_element, &["x", "y", "z"]),
+            ["x (rad/s)", "y", "z (deg)", "value3"]
+        );
+    }
+
+    #[test]
+    fn writes_a_row_per_sample() {
+        let dir = TempDir::new();
+        let path = dir.join("GoPro_1234_GYRO.csv");
+        let gyro = StreamSamples {
+            units: vec!["rad/s".to_string()],
+            samples: vec![
+                (0.0, vec![0.5, -1.0, 2.0]),
+                (1.0 / 3.0, vec![0.25, 0.0, -0.125]),
+            ],
+            ..StreamSamples::default()
+        };
+        write_csv(&path, &gyro, &["x", "y", "z"]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "time (s),x (rad/s),y (rad/s),z (rad/s)\n\
+             0.000000,0.500000,-1.000000,2.000000\n\
+             0.333333,0.250000,0.000000,-0.125000\n"
+        );
+    }
+}
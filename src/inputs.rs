@@ -0,0 +1,197 @@
+// Handling for more than one --input: expanding globs into directories, and reconciling chapters
+// that were found in more than one of them, e.g. when the same card was dumped twice.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use colored::Colorize;
+use log::warn;
+
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::history::fingerprint_chapter;
+
+/// Expands globs and canonicalizes every input, dropping directories given more than once
+pub fn resolve_input_dirs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut input_dirs = Vec::new();
+    for input in inputs {
+        let pattern = input.to_string_lossy();
+        let matches: Vec<PathBuf> = match pattern.contains(['*', '?', '[']) {
+            true => glob::glob(&pattern)
+                .map_err(|e| format!("Invalid glob {}: {}", pattern, e))?
+                .filter_map(|path| path.ok())
+                .filter(|path| path.is_dir())
+                .collect(),
+            false => vec![input.clone()],
+        };
+        if matches.is_empty() {
+            return Err(format!("No directories match {}", pattern));
+        }
+        for input_dir in matches {
+            let input_dir = input_dir.canonicalize().map_err(|e| {
+                format!(
+                    "Could not find input directory {}: {}",
+                    input_dir.display(),
+                    e
+                )
+            })?;
+            if !input_dirs.contains(&input_dir) {
+                input_dirs.push(input_dir);
+            }
+        }
+    }
+    Ok(input_dirs)
+}
+
+/// Finds chapters that were found more than once. Identical copies are dropped, keeping the first.
+/// Videos with copies that differ are removed, since there's no telling which chapters belong
+/// together, and are returned with the reason.
+pub fn remove_duplicate_chapters(
+    videos: &mut HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+) -> Vec<(Vec<GoProChapteredVideoFile>, String)> {
+    let mut conflicting = Vec::new();
+    let mut video_ids: Vec<VideoId> = videos.keys().cloned().collect();
+    video_ids.sort();
+    for video in video_ids {
+        let Some(chapters) = videos.get_mut(&video) else {
+            continue;
+        };
+        match dedup_chapters(chapters) {
+            Ok(()) => (),
+            Err(reason) => {
+                if let Some(chapters) = videos.remove(&video) {
+                    conflicting.push((chapters, reason));
+                }
+            }
+        }
+    }
+    conflicting
+}
+
+/// Drops identical copies from chapters sorted by chapter number, or returns why they conflict
+fn dedup_chapters(chapters: &mut Vec<GoProChapteredVideoFile>) -> Result<(), String> {
+    let mut duplicates = Vec::new();
+    for (index, pair) in chapters.windows(2).enumerate() {
+        let (original, chapter) = (&pair[0], &pair[1]);
+        if original.chapter != chapter.chapter {
+            continue;
+        }
+        // Nested input directories find the same file twice. Otherwise, copies are compared the same
+        // way the import history recognizes chapters.
+        if original.abs_path != chapter.abs_path {
+            let same = match (
+                fingerprint_chapter(&original.abs_path),
+                fingerprint_chapter(&chapter.abs_path),
+            ) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            };
+            if !same {
+                return Err(format!(
+                    "chapter {} differs between {} and {}",
+                    chapter.chapter,
+                    original.abs_path.display(),
+                    chapter.abs_path.display()
+                ));
+            }
+            warn!(
+                "{} is a copy of {}, ignoring it",
+                chapter.abs_path.to_string_lossy().yellow(),
+                original.abs_path.display()
+            );
+        }
+        duplicates.push(index + 1);
+    }
+    for index in duplicates.into_iter().rev() {
+        chapters.remove(index);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+    use std::fs;
+
+    fn chapter(path: PathBuf, video_number: u16, chapter: u16) -> GoProChapteredVideoFile {
+        GoProChapteredVideoFile {
+            abs_path: path,
+            video_number,
+            chapter,
+            camera: None,
+            session: None,
+            metadata: None,
+        }
+    }
+
+    fn video(video_number: u16) -> VideoId {
+        VideoId {
+            video_number,
+            camera: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn identical_copies_are_merged() {
+        let dir = TempDir::new();
+        for card in ["card1", "card2"] {
+            fs::create_dir(dir.join(card)).unwrap();
+            fs::write(dir.join(card).join("GH011234.MP4"), b"chapter 1").unwrap();
+        }
+        fs::write(dir.join("card1/GH021234.MP4"), b"chapter 2").unwrap();
+        let mut videos = HashMap::from([(
+            video(1234),
+            vec![
+                chapter(dir.join("card1/GH011234.MP4"), 1234, 1),
+                chapter(dir.join("card2/GH011234.MP4"), 1234, 1),
+                chapter(dir.join("card1/GH021234.MP4"), 1234, 2),
+                // The same file, found through nested inputs
+                chapter(dir.join("card1/GH021234.MP4"), 1234, 2),
+            ],
+        )]);
+
+        assert!(remove_duplicate_chapters(&mut videos).is_empty());
+        let paths: Vec<_> = videos[&video(1234)]
+            .iter()
+            .map(|c| c.abs_path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                dir.join("card1/GH011234.MP4"),
+                dir.join("card1/GH021234.MP4")
+            ]
+        );
+    }
+
+    #[test]
+    fn videos_with_differing_copies_are_skipped() {
+        let dir = TempDir::new();
+        for (card, contents) in [("card1", "one recording"), ("card2", "another recording")] {
+            fs::create_dir(dir.join(card)).unwrap();
+            fs::write(dir.join(card).join("GH011234.MP4"), contents).unwrap();
+        }
+        fs::write(dir.join("card1/GH017322.MP4"), b"chapter 1").unwrap();
+        let mut videos = HashMap::from([
+            (
+                video(1234),
+                vec![
+                    chapter(dir.join("card1/GH011234.MP4"), 1234, 1),
+                    chapter(dir.join("card2/GH011234.MP4"), 1234, 1),
+                ],
+            ),
+            (
+                video(7322),
+                vec![chapter(dir.join("card1/GH017322.MP4"), 7322, 1)],
+            ),
+        ]);
+
+        let conflicting = remove_duplicate_chapters(&mut videos);
+        assert_eq!(conflicting.len(), 1);
+        let (chapters, reason) = &conflicting[0];
+        assert_eq!(chapters.len(), 2);
+        assert!(reason.starts_with("chapter 1 differs between"));
+        assert_eq!(videos.keys().collect::<Vec<_>>(), [&video(7322)]);
+    }
+}
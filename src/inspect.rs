@@ -0,0 +1,296 @@
+// `inspect` reports what's on a card before anything is assembled: for every video, how long it is,
+// how it was encoded, how big it is and when it was recorded. Nothing is written.
+//
+// Everything comes from the chapters' `moov` boxes. Encoding details come from the first chapter,
+// since a camera can't change settings partway through a recording.
+
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::PathBuf;
+
+use indicatif::HumanBytes;
+use log::error;
+use serde::Serialize;
+use time::macros::format_description;
+use time::{Duration, PrimitiveDateTime};
+
+use crate::cli::InspectFormat;
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::mp4::{fourcc_to_string, FourCC, Movie};
+use crate::printing::format_duration;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterReport {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioReport {
+    pub codec: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoReport {
+    #[serde(flatten)]
+    pub video: VideoId,
+    pub chapters: Vec<ChapterReport>,
+    pub duration_secs: f64,
+    pub bytes: u64,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub frame_rate: Option<f64>,
+    pub codec: Option<String>,
+    /// Average over the whole video, audio and telemetry included
+    pub bits_per_second: Option<u64>,
+    pub audio: Option<AudioReport>,
+    /// Camera wall clock time, like the rest of the tool
+    pub recording_start: Option<String>,
+    pub recording_end: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+fn codec_name(format: &FourCC) -> String {
+    match format {
+        b"hvc1" | b"hev1" => "HEVC".to_string(),
+        b"avc1" | b"avc3" => "H.264".to_string(),
+        b"mp4a" => "AAC".to_string(),
+        other => fourcc_to_string(other),
+    }
+}
+
+fn format_time(time: PrimitiveDateTime) -> String {
+    time.format(format_description!(
+        "[year]-[month]-[day] [hour]:[minute]:[second]"
+    ))
+    .unwrap_or_default()
+}
+
+pub fn inspect_video(
+    video: &VideoId,
+    chapters: &[GoProChapteredVideoFile],
+) -> Result<VideoReport, Error> {
+    let mut chapter_reports = Vec::new();
+    let mut first_movie = None;
+    for chapter in chapters {
+        let movie = Movie::read(&chapter.abs_path)?;
+        chapter_reports.push(ChapterReport {
+            path: chapter.abs_path.clone(),
+            bytes: chapter.abs_path.metadata()?.len(),
+            duration_secs: movie.duration_secs(),
+        });
+        first_movie.get_or_insert(movie);
+    }
+    let Some(movie) = first_movie else {
+        return Err(Error::other(format!("{} has no chapters", video)));
+    };
+    let duration_secs: f64 = chapter_reports.iter().map(|c| c.duration_secs).sum();
+    let bytes: u64 = chapter_reports.iter().map(|c| c.bytes).sum();
+
+    let video_track = movie.video_track();
+    let (width, height) = video_track
+        .and_then(|track| track.dimensions())
+        .map_or((None, None), |(w, h)| (Some(w), Some(h)));
+    let audio = movie
+        .tracks()
+        .into_iter()
+        .find(|t| t.handler_type().as_ref() == Some(b"soun"))
+        .and_then(|track| {
+            let (channels, sample_rate) = track.audio_config()?;
+            Some(AudioReport {
+                codec: codec_name(&track.sample_format()?),
+                channels,
+                sample_rate,
+            })
+        });
+    let start = movie.creation_time();
+    let end =
+        start.and_then(|start| start.checked_add(Duration::checked_seconds_f64(duration_secs)?));
+
+    Ok(VideoReport {
+        video: video.clone(),
+        chapters: chapter_reports,
+        duration_secs,
+        bytes,
+        width,
+        height,
+        frame_rate: video_track.and_then(|track| track.frame_rate().ok()),
+        codec: video_track
+            .and_then(|track| track.sample_format())
+            .map(|format| codec_name(&format)),
+        bits_per_second: (duration_secs > 0.0).then(|| (bytes as f64 * 8.0 / duration_secs) as u64),
+        audio,
+        recording_start: start.map(format_time),
+        recording_end: end.map(format_time),
+        camera_model: chapters
+            .first()
+            .and_then(|c| c.metadata.as_ref())
+            .and_then(|metadata| metadata.model.clone()),
+    })
+}
+
+/// Prints a report for every video, in video order. Returns false if any video couldn't be read.
+pub fn print_inspection(
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    format: InspectFormat,
+) -> bool {
+    let mut videos: Vec<_> = videos.iter().collect();
+    videos.sort_by_key(|(video, _)| *video);
+    let mut all_read = true;
+    let mut reports = Vec::new();
+    for (video, chapters) in videos {
+        match inspect_video(video, chapters) {
+            Ok(report) => reports.push(report),
+            Err(e) => {
+                error!("Could not read {}: {}", video, e);
+                all_read = false;
+            }
+        }
+    }
+    match format {
+        InspectFormat::Json => match serde_json::to_string_pretty(&reports) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                error!("Could not write the report: {}", e);
+                return false;
+            }
+        },
+        InspectFormat::Table => print_table(&reports),
+    }
+    all_read
+}
+
+fn print_table(reports: &[VideoReport]) {
+    let header = [
+        "Video",
+        "Chapters",
+        "Duration",
+        "Resolution",
+        "FPS",
+        "Codec",
+        "Bitrate",
+        "Audio",
+        "Size",
+        "Start",
+        "End",
+        "Camera",
+    ];
+    let rows: Vec<Vec<String>> = reports
+        .iter()
+        .map(|report| {
+            let unknown = || "-".to_string();
+            vec![
+                report.video.to_string(),
+                report.chapters.len().to_string(),
+                format_duration(report.duration_secs),
+                match (report.width, report.height) {
+                    (Some(w), Some(h)) => format!("{}x{}", w, h),
+                    _ => unknown(),
+                },
+                report
+                    .frame_rate
+                    .map_or_else(unknown, |fps| format!("{:.2}", fps)),
+                report.codec.clone().unwrap_or_else(unknown),
+                report.bits_per_second.map_or_else(unknown, |bps| {
+                    format!("{:.1} Mbps", bps as f64 / 1_000_000.0)
+                }),
+                report.audio.as_ref().map_or_else(unknown, |audio| {
+                    format!(
+                        "{} {}ch {}Hz",
+                        audio.codec, audio.channels, audio.sample_rate
+                    )
+                }),
+                HumanBytes(report.bytes).to_string(),
+                report.recording_start.clone().unwrap_or_else(unknown),
+                report.recording_end.clone().unwrap_or_else(unknown),
+                report.camera_model.clone().unwrap_or_else(unknown),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain([header[column].len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let line = |cells: Vec<String>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(header.iter().map(|h| h.to_string()).collect()));
+    for row in rows {
+        println!("{}", line(row));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::VideoMetadata;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+
+    #[test]
+    fn reports_every_chapter_and_the_first_ones_encoding() {
+        let dir = TempDir::new();
+        let video = VideoId {
+            video_number: 1234,
+            camera: None,
+            session: None,
+        };
+        let chapters: Vec<_> = (1..=2)
+            .map(|chapter| {
+                let abs_path = dir.join(&format!("GH{:02}1234.MP4", chapter));
+                write_mp4(&abs_path, &[FixtureTrack::video(60, 30)], Vec::new());
+                GoProChapteredVideoFile {
+                    abs_path,
+                    video_number: 1234,
+                    chapter,
+                    camera: None,
+                    session: None,
+                    metadata: Some(VideoMetadata {
+                        model: Some("HERO9 Black".to_string()),
+                        ..VideoMetadata::default()
+                    }),
+                }
+            })
+            .collect();
+
+        let report = inspect_video(&video, &chapters).unwrap();
+        assert_eq!(report.chapters.len(), 2);
+        assert!(report.chapters.iter().all(|c| c.duration_secs == 2.0));
+        assert_eq!(report.duration_secs, 4.0);
+        let bytes: u64 = report.chapters.iter().map(|c| c.bytes).sum();
+        assert_eq!(report.bytes, bytes);
+        assert_eq!(report.bits_per_second, Some(bytes * 2));
+        assert_eq!((report.width, report.height), (Some(1920), Some(1080)));
+        assert_eq!(report.frame_rate, Some(30.0));
+        assert_eq!(report.codec.as_deref(), Some("H.264"));
+        assert!(report.audio.is_none());
+        assert_eq!(
+            report.recording_start.as_deref(),
+            Some("2024-06-01 00:00:00")
+        );
+        assert_eq!(report.recording_end.as_deref(), Some("2024-06-01 00:00:04"));
+        assert_eq!(report.camera_model.as_deref(), Some("HERO9 Black"));
+
+        assert!(inspect_video(&video, &[]).is_err());
+    }
+
+    #[test]
+    fn durations_are_hours_minutes_and_seconds() {
+        assert_eq!(format_duration(0.4), "0:00:00");
+        assert_eq!(format_duration(3723.0), "1:02:03");
+    }
+}
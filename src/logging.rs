@@ -1,28 +1,106 @@
 extern crate log;
 extern crate xdg;
+use indicatif_log_bridge::LogWrapper;
 use log::*;
 use simplelog::*;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+use crate::cli::CliArgs;
+use crate::events::events_on_stdout;
+use crate::progress::multi_progress;
 
 const PROGRAM_NAME: &str = env!("CARGO_PKG_NAME");
 
-pub fn initialize_logging() {
-    let xdg_dirs = xdg::BaseDirectories::with_prefix(PROGRAM_NAME).unwrap();
-    let log_file_path = xdg_dirs
-        .place_cache_file(format!("{}.log", PROGRAM_NAME))
-        .unwrap();
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        WriteLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            File::create(log_file_path).unwrap(),
-        ),
-    ])
-    .unwrap();
+/// How many per-run log files to keep in the cache directory
+const LOG_RETENTION: usize = 20;
+
+/// Fails if a logger has already been set, which is left as it is
+pub fn initialize_logging(args: &CliArgs) -> Result<(), SetLoggerError> {
+    let terminal_level = match (args.verbose, args.quiet) {
+        (0, 0) => LevelFilter::Info,
+        (1, _) => LevelFilter::Debug,
+        (_, 0) => LevelFilter::Trace,
+        (_, 1) => LevelFilter::Warn,
+        (_, _) => LevelFilter::Error,
+    };
+    // The file always gets at least the info lines, so -q doesn't lose them
+    let file_level = terminal_level.max(LevelFilter::Info);
+
+    let terminal_mode = match events_on_stdout() || args.json_on_stdout() {
+        true => TerminalMode::Stderr,
+        false => TerminalMode::Mixed,
+    };
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        terminal_level,
+        Config::default(),
+        terminal_mode,
+        ColorChoice::Auto,
+    )];
+    // A log file that can't be created shouldn't stop the run, so it's reported once logging is up
+    let mut log_file_error = None;
+    if !args.no_log_file {
+        match open_log_file(args.log_file.clone()) {
+            Ok(file) => loggers.push(WriteLogger::new(file_level, Config::default(), file)),
+            Err(e) => log_file_error = Some(e),
+        }
+    }
+
+    // Log through the progress bars, so lines are printed above them
+    let logger = CombinedLogger::new(loggers);
+    LogWrapper::new(multi_progress().clone(), logger).try_init()?;
+    log::set_max_level(terminal_level.max(file_level));
+
+    if let Some(e) = log_file_error {
+        warn!(
+            "Could not create a log file, logging to the terminal only: {}",
+            e
+        );
+    }
+    Ok(())
+}
+
+/// Opens `log_file` for appending, or creates a new timestamped log file in the cache directory and
+/// prunes the old ones
+fn open_log_file(log_file: Option<PathBuf>) -> std::io::Result<File> {
+    if let Some(log_file) = log_file {
+        return OpenOptions::new().create(true).append(true).open(log_file);
+    }
+
+    let xdg_dirs = xdg::BaseDirectories::with_prefix(PROGRAM_NAME)?;
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    let timestamp = now
+        .format(format_description!(
+            "[year][month][day]-[hour][minute][second]"
+        ))
+        .map_err(std::io::Error::other)?;
+    let log_file_path =
+        xdg_dirs.place_cache_file(format!("logs/{}-{}.log", PROGRAM_NAME, timestamp))?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_file_path)?;
+    if let Some(logs_dir) = log_file_path.parent() {
+        remove_old_log_files(logs_dir);
+    }
+    Ok(file)
+}
+
+/// Keeps the newest `LOG_RETENTION` log files. The timestamps in the names sort chronologically.
+fn remove_old_log_files(logs_dir: &std::path::Path) {
+    let Ok(entries) = fs::read_dir(logs_dir) else {
+        return;
+    };
+    let mut log_files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+        .collect();
+    log_files.sort();
+    let excess = log_files.len().saturating_sub(LOG_RETENTION);
+    for old_log_file in &log_files[..excess] {
+        let _ = fs::remove_file(old_log_file);
+    }
 }
@@ -1,57 +1,243 @@
+mod camera;
+mod chapters;
+mod clean;
 mod cli;
+mod events;
 mod filesystem;
+mod filters;
 mod gopro;
+mod gpmf;
+mod gps;
+mod gyroflow;
+mod hilights;
+mod history;
+mod imu;
+mod inputs;
+mod inspect;
 mod logging;
+mod manifest;
+mod merge_backend;
+mod metadata;
+mod mp4;
 mod multichapter_merging;
+mod organize;
 mod printing;
-use crate::gopro::{gen_output_path, GoProChapteredVideoFile};
+mod progress;
+mod remux;
+mod split;
+mod summary;
+mod telemetry;
+#[cfg(test)]
+mod test_fixtures;
+mod timeline;
+mod trim;
+mod tui;
+use crate::clean::clean_imported_chapters;
+use crate::events::{emit, initialize_events, planned_videos, Event};
+use crate::filters::filter_videos;
+use crate::gopro::{gen_output_path, GoProChapteredVideoFile, VideoId};
+use crate::gps::export_gps_tracks;
+use crate::gyroflow::write_gyroflow_projects;
+use crate::history::ImportHistory;
+use crate::imu::export_imu_telemetry;
+use crate::inputs::{remove_duplicate_chapters, resolve_input_dirs};
+use crate::inspect::print_inspection;
 use crate::logging::initialize_logging;
+use crate::manifest::{hash_file, verify_manifest, write_manifest, ManifestEntry};
 use crate::multichapter_merging::combine_multichapter_videos;
-use crate::printing::{get_confirmation_before_proceeeding, print_expected_output, print_header};
-use std::fs::rename;
+use crate::organize::organized_subdirectories;
+use crate::printing::{
+    get_confirmation_before_proceeeding, print_already_imported, print_duplicate_conflicts,
+    print_expected_output, print_filtered_out, print_header, print_run_summary,
+    print_video_sources,
+};
+use crate::progress::Progress;
+use crate::split::split_videos;
+use crate::summary::{Outcome, RunSummary};
+use crate::timeline::export_timelines;
+use crate::trim::{ambiguous_trims, find_trim, gen_trimmed_path, trim_and_report};
+use crate::tui::{choose_videos, VideoAction};
+use std::fs::hard_link;
 
 use clap::Parser;
-use cli::CliArgs;
+use cli::{
+    AssembleArgs, CliArgs, Command, ExportCommand, PlanArgs, ScanArgs, SingleChapterMode,
+    TrimRange, VideoTrim,
+};
 use colored::Colorize;
-use filesystem::normalize_and_create_if_needed;
+use filesystem::{
+    copy_with_progress, normalize_and_create_if_needed, reflink, rename_new, symlink_file,
+};
 use gopro::parse_gopro_files_directory;
-use log::{error, info};
+use log::{error, info, warn};
 use printing::print_remove_commands;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Instant;
 
 fn main() {
-    initialize_logging();
-    print_header();
     let args = CliArgs::parse();
+    let command = args.command();
+    if let Command::Assemble(AssembleArgs { plan, .. }) | Command::Plan(plan) = &command {
+        if let Some(format) = plan.events {
+            if let Err(e) = initialize_events(format, plan.events_file.as_deref()) {
+                eprintln!("{} {}", "Could not open the events file:".red().bold(), e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = initialize_logging(&args) {
+        eprintln!("{} {}", "Could not set up logging:".red().bold(), e);
+    }
+    if args.quiet == 0 && !args.json_on_stdout() {
+        print_header();
+    }
     // print!("{:#?}", args);
 
-    // Canonicalize input path up front. We don't handle the output path until later to avoid creating the output path if the user cancels the operation.
-    let input_dir = args
-        .input
-        .clone()
-        .unwrap()
-        .canonicalize()
-        .expect("Could not canonicalize input dir path. Does it exist?");
+    process::exit(run(command));
+}
+
+/// Runs a command, returning the exit code
+fn run(command: Command) -> i32 {
+    match command {
+        Command::Assemble(assemble) => assemble_videos(assemble),
+        Command::Plan(plan) => assemble_videos(AssembleArgs {
+            plan,
+            dry_run: true,
+            plan_only: true,
+            ..Default::default()
+        }),
+        Command::Verify { directory } => exit_code(verify_manifest(&directory)),
+        Command::Export(ExportCommand::Gps(export)) => {
+            let (Some(scanned), Some(output_dir)) =
+                (scan_videos(&export.scan), output_dir_or_log(export.output))
+            else {
+                return 1;
+            };
+            exit_code(export_gps_tracks(
+                &scanned.videos,
+                &output_dir,
+                export.format,
+            ))
+        }
+        Command::Export(ExportCommand::Imu(export)) => {
+            let (Some(scanned), Some(output_dir)) =
+                (scan_videos(&export.scan), output_dir_or_log(export.output))
+            else {
+                return 1;
+            };
+            exit_code(export_imu_telemetry(&scanned.videos, &output_dir))
+        }
+        Command::Inspect { scan, format } => match scan_videos(&scan) {
+            Some(scanned) => exit_code(print_inspection(&scanned.videos, format)),
+            None => 1,
+        },
+        Command::Clean {
+            scan,
+            dry_run,
+            auto_confirm_yes,
+        } => match scan_videos(&scan) {
+            Some(scanned) => exit_code(clean_imported_chapters(
+                &scanned.videos,
+                dry_run,
+                auto_confirm_yes,
+            )),
+            None => 1,
+        },
+        Command::Split {
+            inputs,
+            output,
+            max_size,
+            max_duration,
+        } => {
+            let output_dir = match output.map(output_dir_or_log) {
+                Some(None) => return 1,
+                output_dir => output_dir.flatten(),
+            };
+            exit_code(split_videos(
+                &inputs,
+                output_dir.as_deref(),
+                max_size,
+                max_duration,
+            ))
+        }
+        Command::Trim {
+            input,
+            output,
+            start,
+            end,
+        } => {
+            let output = output.unwrap_or_else(|| gen_trimmed_path(&input));
+            match trim_and_report(&input, &output, TrimRange { start, end }) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            }
+        }
+    }
+}
+
+fn exit_code(succeeded: bool) -> i32 {
+    match succeeded {
+        true => 0,
+        false => 1,
+    }
+}
 
-    actually_do_things_with_input_and_output_paths(input_dir, args);
+/// Creates the output directory if needed, logging why if it can't be. We don't do this until
+/// after confirmation, to avoid creating the output path if the user cancels the operation.
+fn output_dir_or_log(output: PathBuf) -> Option<PathBuf> {
+    match normalize_and_create_if_needed(output.clone()) {
+        Ok(output_dir) => Some(output_dir),
+        Err(e) => {
+            error!(
+                "{} {}: {}",
+                "Could not create output directory".red().bold(),
+                output.display(),
+                e
+            );
+            None
+        }
+    }
 }
 
-fn actually_do_things_with_input_and_output_paths(input_dir: PathBuf, args: CliArgs) {
-    let input_files = filesystem::get_files_in_directory(input_dir.to_str().unwrap());
+/// Finds every GoPro video file in the input directories, grouped by video number with chapters in
+/// order. Chapters found in more than one directory are kept in the order the directories were given.
+/// Directories that can't be read are logged and added to `unreadable_dirs` with why, and the rest
+/// are still scanned. Returns None, after logging it, if there are no files at all.
+fn find_gopro_videos(
+    input_dirs: &[PathBuf],
+    unreadable_dirs: &mut Vec<String>,
+) -> Option<HashMap<VideoId, Vec<GoProChapteredVideoFile>>> {
+    let mut input_files = Vec::new();
+    for input_dir in input_dirs {
+        let files = match filesystem::get_files_in_directory(input_dir) {
+            Ok(files) => files,
+            Err(e) => {
+                let message = format!("Could not read {}: {}", input_dir.display(), e);
+                error!("{}", message.red().bold());
+                unreadable_dirs.push(message);
+                continue;
+            }
+        };
+        if files.is_empty() {
+            warn!(
+                "{} {}",
+                "No files found in directory:".yellow().bold(),
+                input_dir.display()
+            );
+        } else {
+            info!(
+                "Found {} files in directory: {}",
+                files.len(),
+                input_dir.as_os_str().to_string_lossy().blue().bold()
+            );
+        }
+        input_files.extend(files);
+    }
     if input_files.is_empty() {
-        error!(
-            "{} {}",
-            "No files found in directory: ".red().bold(),
-            input_dir.display()
-        );
-        process::exit(1);
-    } else {
-        info!(
-            "Found {} files in directory: {}",
-            input_files.len(),
-            input_dir.as_os_str().to_string_lossy().blue().bold()
-        );
+        error!("{}", "No files found in any input directory".red().bold());
+        return None;
     }
 
     // Extract data for each video file
@@ -61,88 +247,656 @@ fn actually_do_things_with_input_and_output_paths(input_dir: PathBuf, args: CliA
     // println!("{:?}", videos);
 
     // Sort the videos by video number, preparing them to be combined by mp4-merge
-    let mut multichapter_videos_sorted = gopro::sort_gopro_files(videos);
+    Some(gopro::sort_gopro_files(videos))
+}
+
+/// Videos left out of a run, each with its chapters and why
+type SkippedVideos = Vec<(Vec<GoProChapteredVideoFile>, String)>;
+
+struct ScannedVideos {
+    input_dirs: Vec<PathBuf>,
+    videos: HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    /// Videos left out, e.g. by the filters
+    skipped: SkippedVideos,
+    /// Why each input directory that couldn't be read was left out
+    unreadable_dirs: Vec<String>,
+}
+
+/// Resolves --input and finds the videos in it, with copies of the same chapter removed and only the
+/// videos that match the filters kept. Returns None, after logging why, if there's nothing to scan.
+fn scan_videos(scan: &ScanArgs) -> Option<ScannedVideos> {
+    let input_dirs = match resolve_input_dirs(&scan.input) {
+        Ok(input_dirs) => input_dirs,
+        Err(e) => {
+            error!("{}", e.red().bold());
+            return None;
+        }
+    };
+    let mut unreadable_dirs = Vec::new();
+    let mut videos = find_gopro_videos(&input_dirs, &mut unreadable_dirs)?;
+
+    // The same card may have been copied to more than one of the inputs
+    let mut skipped = remove_duplicate_chapters(&mut videos);
+    print_duplicate_conflicts(&skipped);
+
+    let filtered_out = filter_videos(&mut videos, scan);
+    print_filtered_out(&filtered_out);
+    skipped.extend(filtered_out);
+    Some(ScannedVideos {
+        input_dirs,
+        videos,
+        skipped,
+        unreadable_dirs,
+    })
+}
+
+/// Returns the exit code
+fn assemble_videos(args: AssembleArgs) -> i32 {
+    let started = Instant::now();
+    let mut summary = RunSummary::default();
+    let Some(ScannedVideos {
+        input_dirs,
+        videos: mut multichapter_videos_sorted,
+        skipped,
+        unreadable_dirs,
+    }) = scan_videos(&args.plan.scan)
+    else {
+        return 1;
+    };
+    let Some(output) = args.plan.output.clone() else {
+        error!("{}", "No output directory given".red().bold());
+        return 1;
+    };
+    let ambiguous = ambiguous_trims(&args.plan.trim, multichapter_videos_sorted.keys());
+    if !ambiguous.is_empty() {
+        for message in ambiguous {
+            error!("{}", message.red().bold());
+        }
+        return 1;
+    }
+    for message in unreadable_dirs {
+        summary.record_error(message);
+    }
+    for (chapters, reason) in skipped {
+        summary.record(
+            chapters[0].video_id(),
+            planned_action(&chapters, &args.plan),
+            Outcome::Skipped(reason),
+        );
+    }
+
+    // Skip anything we've already assembled in a previous run. Timelines reference the chapters
+    // where they are, so exporting them doesn't import anything.
+    let mut history = match args.export_timeline {
+        Some(_) => None,
+        None => match ImportHistory::load() {
+            Ok(history) => Some(history),
+            Err(e) => {
+                warn!("Could not load import history, it won't be updated: {}", e);
+                None
+            }
+        },
+    };
+    if let (Some(history), false) = (&history, args.plan.force) {
+        let already_imported = history.remove_already_imported(&mut multichapter_videos_sorted);
+        print_already_imported(&already_imported);
+        for (video, chapters) in &already_imported {
+            summary.record(
+                video.clone(),
+                planned_action(chapters, &args.plan),
+                Outcome::Skipped("already imported".to_string()),
+            );
+        }
+    }
+
     // Filter out videos that only have one chapter to be renamed separately
     let mut single_chapter_videos = multichapter_videos_sorted.clone();
     single_chapter_videos.retain::<_>(|_k, v| v.len() == 1);
     // And then drop them from the multichapter videos map
     multichapter_videos_sorted.retain::<_>(|_k, v| v.len() > 1);
 
-    // Show expected output for multichapter combinations and single chapter renames
-    print_expected_output(
-        single_chapter_videos.clone(),
-        multichapter_videos_sorted.clone(),
-        args.copy_single_chapter_instead_of_renaming.clone(),
+    // Decide what happens to each video, either interactively or from the flags
+    let mut single_chapter_modes: HashMap<VideoId, SingleChapterMode> = single_chapter_videos
+        .keys()
+        .map(|video| (video.clone(), args.plan.single_chapter_mode()))
+        .collect();
+    let mut output_names = HashMap::new();
+    if args.interactive {
+        let choices = match choose_videos(
+            &multichapter_videos_sorted,
+            &single_chapter_videos,
+            args.plan.single_chapter_mode(),
+        ) {
+            Ok(Some(choices)) => choices,
+            Ok(None) => {
+                info!("Exiting...");
+                return 0;
+            }
+            Err(e) => {
+                error!(
+                    "{} {}",
+                    "Could not start the interactive UI:".red().bold(),
+                    e
+                );
+                return 1;
+            }
+        };
+        for choice in choices {
+            let video = choice.video;
+            match choice.action {
+                VideoAction::Skip => {
+                    let chapters = multichapter_videos_sorted
+                        .remove(&video)
+                        .or_else(|| single_chapter_videos.remove(&video))
+                        .unwrap_or_default();
+                    summary.record(
+                        video.clone(),
+                        planned_action(&chapters, &args.plan),
+                        Outcome::Skipped("deselected".to_string()),
+                    );
+                }
+                VideoAction::Single(mode) => {
+                    single_chapter_modes.insert(video.clone(), mode);
+                }
+                VideoAction::Merge => (),
+            }
+            if choice.output_name != format!("{}.mp4", video) {
+                output_names.insert(video, choice.output_name);
+            }
+        }
+    } else {
+        // Show expected output for multichapter combinations and single chapter renames
+        print_video_sources(
+            &multichapter_videos_sorted,
+            &single_chapter_videos,
+            &input_dirs,
+        );
+        print_expected_output(
+            single_chapter_videos.clone(),
+            multichapter_videos_sorted.clone(),
+            args.plan.single_chapter_mode(),
+        );
+        match get_confirmation_before_proceeeding(args.auto_confirm_yes || args.plan_only) {
+            true => (),
+            false => {
+                info!("Exiting...");
+                return 0;
+            }
+        }
+    }
+
+    let planned_bytes =
+        total_bytes(&multichapter_videos_sorted) + total_bytes(&single_chapter_videos);
+    let action = |video: &VideoId| match single_chapter_modes.get(video) {
+        Some(mode) if !multichapter_videos_sorted.contains_key(video) => mode.as_str(),
+        _ => "merge",
+    };
+    let subdirectories = organized_subdirectories(
+        args.plan.organize.as_deref(),
+        &multichapter_videos_sorted,
+        &single_chapter_videos,
+    );
+    let planned_paths = output_paths(
+        &output,
+        &multichapter_videos_sorted,
+        &single_chapter_videos,
+        &output_names,
+        &subdirectories,
     );
-    match get_confirmation_before_proceeeding(args.auto_confirm_yes) {
-        true => (),
-        false => {
-            info!("Exiting...");
-            process::exit(0);
+    emit(Event::PlanCreated {
+        multichapter_videos: planned_videos(&multichapter_videos_sorted, &planned_paths, action),
+        single_chapter_videos: planned_videos(&single_chapter_videos, &planned_paths, action),
+        single_chapter_action: args.plan.single_chapter_mode().as_str(),
+        total_bytes: planned_bytes,
+        dry_run: args.dry_run,
+    });
+    if args.plan_only {
+        return 0;
+    }
+    let Some(output_dir) = output_dir_or_log(output) else {
+        return 1;
+    };
+    let output_paths = output_paths(
+        &output_dir,
+        &multichapter_videos_sorted,
+        &single_chapter_videos,
+        &output_names,
+        &subdirectories,
+    );
+    if !args.dry_run {
+        // --organize puts outputs in subdirectories, which are created like the output directory
+        let mut output_subdirectories: Vec<&Path> = output_paths
+            .values()
+            .filter_map(|path| path.parent())
+            .filter(|dir| *dir != output_dir)
+            .collect();
+        output_subdirectories.sort();
+        output_subdirectories.dedup();
+        for dir in output_subdirectories {
+            if let Err(e) = normalize_and_create_if_needed(dir.to_path_buf()) {
+                // Writing the video will fail too, and that's reported with the video
+                warn!("Could not create {}: {}", dir.display(), e);
+            }
+        }
+    }
+
+    // Timelines are written where each video would have been assembled, and there's nothing to
+    // merge, rename or record in the import history
+    if let Some(format) = args.export_timeline {
+        let videos: HashMap<_, _> = multichapter_videos_sorted
+            .iter()
+            .chain(&single_chapter_videos)
+            .map(|(video, chapters)| (video.clone(), chapters.clone()))
+            .collect();
+        if args.dry_run {
+            info!("Dry run, skipping timeline export!");
+        } else {
+            let timelines = export_timelines(&videos, &output_paths, format, &mut summary);
+            if !args.no_manifest {
+                write_output_manifest(
+                    &output_dir,
+                    &HashMap::new(),
+                    &HashMap::new(),
+                    &HashSet::new(),
+                    &timelines,
+                    &mut summary,
+                );
+            }
         }
+        let (succeeded, skipped, failed) = summary.counts();
+        emit(Event::RunSummary {
+            multichapter_videos: multichapter_videos_sorted.len(),
+            single_chapter_videos: single_chapter_videos.len(),
+            total_bytes: 0,
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            dry_run: args.dry_run,
+            succeeded,
+            skipped,
+            failed,
+        });
+        print_run_summary(&summary);
+        return summary.exit_code();
     }
-    let output_dir = normalize_and_create_if_needed(args.output.clone().unwrap());
 
-    combine_multichapter_videos(multichapter_videos_sorted.clone(), output_dir.clone());
+    let progress = Progress::new(match args.dry_run {
+        true => 0,
+        false => planned_bytes,
+    });
+    let mut sidecar_files = combine_multichapter_videos(
+        multichapter_videos_sorted.clone(),
+        output_dir.clone(),
+        &output_paths,
+        args.clone(),
+        &progress,
+        &mut summary,
+    );
 
-    if args.copy_single_chapter_instead_of_renaming {
-        print!("Copying single chapter videos instead of renaming");
-        copy_single_chapter_videos(single_chapter_videos, output_dir, args.clone());
-    } else {
-        print!("Renaming single chapter videos");
-        rename_single_chapter_videos(single_chapter_videos, output_dir, args.clone());
+    place_single_chapter_videos(
+        single_chapter_videos.clone(),
+        &single_chapter_modes,
+        &output_paths,
+        args.clone(),
+        &progress,
+        &mut summary,
+    );
+    progress.finish();
+
+    // Only what was actually written goes in the manifest and import history
+    let succeeded = summary.succeeded_videos();
+    multichapter_videos_sorted.retain(|video, _| succeeded.contains(video));
+    single_chapter_videos.retain(|video, _| succeeded.contains(video));
+
+    if args.gyroflow && !args.dry_run {
+        let output_videos: Vec<PathBuf> = multichapter_videos_sorted
+            .keys()
+            .chain(single_chapter_videos.keys())
+            .map(|video| output_paths[video].clone())
+            .collect();
+        sidecar_files.extend(write_gyroflow_projects(&output_videos));
+    }
+
+    let nothing_written = multichapter_videos_sorted.is_empty() && single_chapter_videos.is_empty();
+    let mut output_hashes = HashMap::new();
+    if !args.dry_run && !args.no_manifest && !nothing_written {
+        let written_videos = multichapter_videos_sorted
+            .iter()
+            .chain(&single_chapter_videos)
+            .map(|(video, chapters)| (video.clone(), chapters.clone()))
+            .collect();
+        output_hashes = write_output_manifest(
+            &output_dir,
+            &written_videos,
+            &output_paths,
+            &summary.succeeded_videos_with_action("rename"),
+            &sidecar_files,
+            &mut summary,
+        );
     }
 
+    if let (Some(history), false) = (&mut history, args.dry_run) {
+        record_import_history(
+            history,
+            &multichapter_videos_sorted,
+            &single_chapter_videos,
+            &output_paths,
+            &output_hashes,
+            &args.plan.trim,
+        );
+    }
+
+    let (succeeded, skipped, failed) = summary.counts();
+    emit(Event::RunSummary {
+        multichapter_videos: multichapter_videos_sorted.len(),
+        single_chapter_videos: single_chapter_videos.len(),
+        total_bytes: planned_bytes,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+        dry_run: args.dry_run,
+        succeeded,
+        skipped,
+        failed,
+    });
+    print_run_summary(&summary);
+
     // Only print the remove commands if we combined any multichapter videos
-    if multichapter_videos_sorted.len() > 0 {
+    if !multichapter_videos_sorted.is_empty() {
         print_remove_commands(multichapter_videos_sorted);
     }
+    summary.exit_code()
+}
+
+/// Where each video is written: GoPro_N.mp4, unless it was given another name, in its --organize
+/// subdirectory if it has one
+fn output_paths(
+    output_dir: &Path,
+    multichapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_names: &HashMap<VideoId, String>,
+    subdirectories: &HashMap<VideoId, PathBuf>,
+) -> HashMap<VideoId, PathBuf> {
+    multichapter_videos
+        .keys()
+        .chain(single_chapter_videos.keys())
+        .map(|video| {
+            let dir = match subdirectories.get(video) {
+                Some(subdirectory) => output_dir.join(subdirectory),
+                None => output_dir.to_path_buf(),
+            };
+            let output_path = match output_names.get(video) {
+                Some(name) => dir.join(name),
+                None => gen_output_path(&dir, video, "mp4"),
+            };
+            (video.clone(), output_path)
+        })
+        .collect()
+}
+
+/// What happens to a video with these chapters: a merge, or a rename or copy
+fn planned_action(chapters: &[GoProChapteredVideoFile], args: &PlanArgs) -> &'static str {
+    match chapters.len() > 1 {
+        true => "merge",
+        false => args.single_chapter_mode().as_str(),
+    }
 }
 
-// There's some needless code duplication here. Could be cleaner
+/// The size of every chapter, for the overall progress
+fn total_bytes(videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>) -> u64 {
+    videos
+        .values()
+        .flatten()
+        .filter_map(|chapter| std::fs::metadata(&chapter.abs_path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
 
-fn rename_single_chapter_videos(
-    single_chapter_videos: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
-    output_dir: PathBuf,
-    args: CliArgs,
+/// Renames, copies or links each single chapter video to its output path, as its mode says
+fn place_single_chapter_videos(
+    single_chapter_videos: HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_modes: &HashMap<VideoId, SingleChapterMode>,
+    output_paths: &HashMap<VideoId, PathBuf>,
+    args: AssembleArgs,
+    progress: &Progress,
+    summary: &mut RunSummary,
 ) {
-    for video in single_chapter_videos {
-        let video_number = video.0;
-        let video_path = video.1[0].abs_path.clone();
-        let output_path = gen_output_path(&output_dir, video_number, "mp4");
+    let mut videos: Vec<_> = single_chapter_videos.into_iter().collect();
+    videos.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (video_id, chapters) in videos {
+        let video_path = chapters[0].abs_path.clone();
+        let output_path = output_paths[&video_id].clone();
+        if let Some(trim) = find_trim(&args.plan.trim, &video_id) {
+            let outcome = trim_single_chapter_video(
+                &video_id,
+                &video_path,
+                &output_path,
+                trim,
+                args.dry_run,
+                progress,
+            );
+            summary.record(video_id.clone(), "trim", outcome);
+            continue;
+        }
+        let mode = single_chapter_modes
+            .get(&video_id)
+            .copied()
+            .unwrap_or_default();
+        let action = mode.as_str();
         info!(
-            "Renaming {} to {}",
+            "{} {} to {}",
+            match mode {
+                SingleChapterMode::Rename => "Renaming",
+                SingleChapterMode::Copy => "Copying",
+                SingleChapterMode::Hardlink => "Hard linking",
+                SingleChapterMode::Symlink => "Symlinking",
+                SingleChapterMode::Reflink => "Reflinking",
+            },
             video_path.to_string_lossy().green().bold(),
             output_path.to_string_lossy().blue().bold()
         );
         if args.dry_run {
-            info!("Dry run, skipping rename!");
+            info!("Dry run, skipping {}!", action);
+            summary.record(video_id.clone(), action, dry_run_outcome());
             continue;
-        } else {
-            rename(video_path, output_path).expect("Failed to rename file");
         }
+
+        let bytes = file_size(&video_path);
+        progress.start(&video_path.to_string_lossy(), bytes);
+        let copy = || {
+            copy_with_progress(&video_path, &output_path, &|fraction| {
+                progress.update(fraction)
+            })
+        };
+        let placed = match mode {
+            SingleChapterMode::Rename => rename_new(&video_path, &output_path).map(|()| action),
+            SingleChapterMode::Copy => copy().map(|_| action),
+            SingleChapterMode::Hardlink => hard_link(&video_path, &output_path).map(|()| action),
+            SingleChapterMode::Symlink => symlink_file(&video_path, &output_path).map(|()| action),
+            SingleChapterMode::Reflink => match reflink(&video_path, &output_path) {
+                Ok(()) => Ok(action),
+                // An output that's already there isn't something a copy would fix
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(e),
+                Err(e) => {
+                    info!("Could not reflink ({}), copying instead", e);
+                    copy().map(|_| SingleChapterMode::Copy.as_str())
+                }
+            },
+        };
+        progress.finish_item();
+        let operation = match placed {
+            Ok(operation) => operation,
+            Err(e) => {
+                let outcome = single_chapter_failure(&video_id, &video_path, action, e);
+                summary.record(video_id.clone(), action, outcome);
+                continue;
+            }
+        };
+        summary.record(video_id.clone(), operation, Outcome::Succeeded);
+        emit(Event::FileCopied {
+            video_number: video_id.video_number,
+            camera: video_id.camera.clone(),
+            session: video_id.session.clone(),
+            source: video_path,
+            destination: output_path,
+            operation,
+            bytes,
+        });
     }
 }
 
-fn copy_single_chapter_videos(
-    single_chapter_videos: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
-    output_dir: PathBuf,
-    args: CliArgs,
-) {
-    for video in single_chapter_videos {
-        let video_number = video.0;
-        let video_path = video.1[0].abs_path.clone();
-        let output_path = gen_output_path(&output_dir, video_number, "mp4");
+fn dry_run_outcome() -> Outcome {
+    Outcome::Skipped("dry run".to_string())
+}
+
+fn single_chapter_failure(
+    video: &VideoId,
+    video_path: &Path,
+    action: &str,
+    e: std::io::Error,
+) -> Outcome {
+    let message = format!("Failed to {}: {}", action, e);
+    error!("{} {}", message.red().bold(), video_path.display());
+    emit(Event::Error {
+        video_number: Some(video.video_number),
+        camera: video.camera.clone(),
+        session: video.session.clone(),
+        path: Some(video_path.to_path_buf()),
+        message: message.clone(),
+    });
+    Outcome::Failed(message)
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// Trimming writes a new file, so the original chapter is left where it is
+fn trim_single_chapter_video(
+    video: &VideoId,
+    video_path: &Path,
+    output_path: &Path,
+    range: TrimRange,
+    dry_run: bool,
+    progress: &Progress,
+) -> Outcome {
+    if dry_run {
         info!(
-            "Copying {} to {}",
-            video_path.to_string_lossy().green().bold(),
-            output_path.to_string_lossy().blue().bold()
+            "Dry run, skipping trimming {}!",
+            video_path.to_string_lossy().green().bold()
         );
-        if args.dry_run {
-            info!("Dry run, skipping copy!");
-            continue;
+        return dry_run_outcome();
+    }
+    progress.start(&video_path.to_string_lossy(), file_size(video_path));
+    let trimmed = trim_and_report(video_path, output_path, range);
+    progress.resize_item(file_size(output_path));
+    progress.finish_item();
+    if let Err(e) = trimmed {
+        let message = format!("Failed to trim: {}", e);
+        emit(Event::Error {
+            video_number: Some(video.video_number),
+            camera: video.camera.clone(),
+            session: video.session.clone(),
+            path: Some(video_path.to_path_buf()),
+            message: message.clone(),
+        });
+        return Outcome::Failed(message);
+    }
+    emit(Event::FileCopied {
+        video_number: video.video_number,
+        camera: video.camera.clone(),
+        session: video.session.clone(),
+        source: video_path.to_path_buf(),
+        destination: output_path.to_path_buf(),
+        operation: "trim",
+        bytes: file_size(output_path),
+    });
+    Outcome::Succeeded
+}
+
+/// `output_hashes` are the outputs the manifest already hashed, anything else is hashed here
+fn record_import_history(
+    history: &mut ImportHistory,
+    multichapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_paths: &HashMap<VideoId, PathBuf>,
+    output_hashes: &HashMap<PathBuf, (u64, String)>,
+    trims: &[VideoTrim],
+) {
+    for (video, chapters) in multichapter_videos.iter().chain(single_chapter_videos) {
+        let output_path = &output_paths[video];
+        let recorded = match output_hashes.get(output_path) {
+            Some(hash) => Ok(hash.clone()),
+            None => hash_file(output_path),
+        }
+        .and_then(|hash| {
+            let trimmed = find_trim(trims, video).is_some();
+            history.record(chapters, output_path, hash, trimmed)
+        });
+        if let Err(e) = recorded {
+            warn!("Could not record {} in the import history: {}", video, e);
+        }
+    }
+    match history.save() {
+        Ok(()) => info!(
+            "Updated import history at {}",
+            history.path().to_string_lossy().blue().bold()
+        ),
+        Err(e) => error!("{} {}", "Failed to save import history:".red().bold(), e),
+    }
+}
+
+/// `renamed_videos` were moved rather than copied, so their sources are gone and are recorded by
+/// the output's own hash. Returns the size and hash of each output video.
+fn write_output_manifest(
+    output_dir: &Path,
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_paths: &HashMap<VideoId, PathBuf>,
+    renamed_videos: &HashSet<VideoId>,
+    sidecar_files: &[PathBuf],
+    summary: &mut RunSummary,
+) -> HashMap<PathBuf, (u64, String)> {
+    info!("Hashing output files for the manifest...");
+    let mut entries = Vec::new();
+    let mut output_hashes = HashMap::new();
+    for (video, chapters) in videos {
+        let output_path = &output_paths[video];
+        let entry = if renamed_videos.contains(video) {
+            ManifestEntry::for_renamed(output_dir, output_path, &chapters[0].abs_path)
         } else {
-            std::fs::copy(video_path, output_path).expect("Failed to copy file");
+            let sources: Vec<PathBuf> = chapters.iter().map(|c| c.abs_path.clone()).collect();
+            ManifestEntry::new(output_dir, output_path, &sources)
+        };
+        match entry {
+            Ok(entry) => {
+                output_hashes.insert(output_path.clone(), (entry.size, entry.blake3.clone()));
+                entries.push(entry);
+            }
+            Err(e) => warn!(
+                "Could not hash {} for the manifest: {}",
+                output_path.display(),
+                e
+            ),
+        }
+    }
+
+    // Sidecars are generated from the output videos, so they have no source chapters of their own
+    for sidecar in sidecar_files {
+        match ManifestEntry::new(output_dir, sidecar, &[]) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!(
+                "Could not hash {} for the manifest: {}",
+                sidecar.display(),
+                e
+            ),
+        }
+    }
+
+    match write_manifest(output_dir, entries) {
+        Ok(manifest_path) => info!(
+            "Wrote manifest to {}",
+            manifest_path.to_string_lossy().blue().bold()
+        ),
+        Err(e) => {
+            error!("{} {}", "Failed to write manifest:".red().bold(), e);
+            summary.record_error(format!("Failed to write manifest: {}", e));
         }
     }
+    output_hashes
 }
@@ -0,0 +1,205 @@
+// Every run records what it produced in a BLAKE3 manifest inside the output directory, so an
+// archive can be re-checked long after the source SD card has been wiped.
+//
+// Output paths are stored relative to the manifest, which keeps the archive relocatable. Source
+// paths are stored as they were on disk at assembly time, since they're only there for provenance.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+pub const MANIFEST_FILE_NAME: &str = "gopro-manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub generator: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// A single file written to the output directory, along with the chapters it was built from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub blake3: String,
+    pub sources: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub blake3: String,
+}
+
+impl ManifestEntry {
+    /// Hashes an output file and every source chapter it was assembled from.
+    pub fn new(output_dir: &Path, output: &Path, sources: &[PathBuf]) -> Result<Self, Error> {
+        let mut source_files = Vec::new();
+        for source in sources {
+            let (size, blake3) = hash_file(source)?;
+            source_files.push(SourceFile {
+                path: source.clone(),
+                size,
+                blake3,
+            });
+        }
+        let (size, blake3) = hash_file(output)?;
+        Ok(ManifestEntry {
+            path: relative_output_path(output_dir, output),
+            size,
+            blake3,
+            sources: source_files,
+        })
+    }
+
+    /// Renaming doesn't change the content, so the source hash is the output hash. The original
+    /// file no longer exists, which is why it can't be hashed separately.
+    pub fn for_renamed(output_dir: &Path, output: &Path, original: &Path) -> Result<Self, Error> {
+        let (size, blake3) = hash_file(output)?;
+        Ok(ManifestEntry {
+            path: relative_output_path(output_dir, output),
+            size,
+            blake3: blake3.clone(),
+            sources: vec![SourceFile {
+                path: original.to_path_buf(),
+                size,
+                blake3,
+            }],
+        })
+    }
+}
+
+fn relative_output_path(output_dir: &Path, output: &Path) -> String {
+    output
+        .strip_prefix(output_dir)
+        .unwrap_or(output)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Returns the size and hex-encoded BLAKE3 hash of a file
+pub fn hash_file(path: &Path) -> Result<(u64, String), Error> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(BufReader::new(file))?;
+    Ok((size, hasher.finalize().to_hex().to_string()))
+}
+
+pub fn get_manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE_NAME)
+}
+
+pub fn read_manifest(dir: &Path) -> Result<Manifest, Error> {
+    let file = File::open(get_manifest_path(dir))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(Error::other)
+}
+
+/// Adds entries to the manifest in `output_dir`, replacing any existing entries for the same output
+/// files. Earlier runs into the same directory stay in the manifest.
+pub fn write_manifest(output_dir: &Path, entries: Vec<ManifestEntry>) -> Result<PathBuf, Error> {
+    let mut manifest = match read_manifest(output_dir) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Manifest {
+            generator: String::new(),
+            files: Vec::new(),
+        },
+        Err(e) => return Err(e),
+    };
+    manifest.generator = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    for entry in entries {
+        manifest
+            .files
+            .retain(|existing| existing.path != entry.path);
+        manifest.files.push(entry);
+    }
+    manifest.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Written next to where it goes and renamed into place, like the import history, so a crash
+    // partway through doesn't lose the entries from earlier runs
+    let manifest_path = get_manifest_path(output_dir);
+    let temp_path = manifest_path.with_extension("json.tmp");
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    serde_json::to_writer_pretty(&mut file, &manifest).map_err(Error::other)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&temp_path, &manifest_path)?;
+    Ok(manifest_path)
+}
+
+/// Re-hashes every file listed in the manifest in `dir`. Returns true if everything matches.
+pub fn verify_manifest(dir: &Path) -> bool {
+    let manifest = match read_manifest(dir) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!(
+                "{} {}: {}",
+                "Could not read manifest".red().bold(),
+                get_manifest_path(dir).display(),
+                e
+            );
+            return false;
+        }
+    };
+
+    let mut failures = 0;
+    for entry in &manifest.files {
+        let path = dir.join(&entry.path);
+        match hash_file(&path) {
+            Ok((size, blake3)) if size == entry.size && blake3 == entry.blake3 => {
+                info!("{} {}", "OK".green().bold(), entry.path);
+            }
+            Ok(_) => {
+                failures += 1;
+                error!("{} {}", "MISMATCH".red().bold(), entry.path);
+            }
+            Err(e) => {
+                failures += 1;
+                error!("{} {} ({})", "MISSING".red().bold(), entry.path, e);
+            }
+        }
+    }
+
+    if failures == 0 {
+        info!(
+            "All {} file(s) in {} match the manifest",
+            manifest.files.len().to_string().blue().bold(),
+            dir.display()
+        );
+        true
+    } else {
+        warn!(
+            "{} of {} file(s) failed verification",
+            failures.to_string().red().bold(),
+            manifest.files.len()
+        );
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::TempDir;
+
+    #[test]
+    fn later_runs_add_to_the_manifest_without_leaving_a_temp_file() {
+        let dir = TempDir::new();
+        for (name, contents) in [("GoPro_1.mp4", "one"), ("GoPro_2.mp4", "two")] {
+            let output = dir.join(name);
+            fs::write(&output, contents).unwrap();
+            let entry = ManifestEntry::for_renamed(dir.path(), &output, &output).unwrap();
+            write_manifest(dir.path(), vec![entry]).unwrap();
+        }
+
+        let manifest = read_manifest(dir.path()).unwrap();
+        let paths: Vec<_> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["GoPro_1.mp4", "GoPro_2.mp4"]);
+        assert!(!dir.join("gopro-manifest.json.tmp").exists());
+        assert!(verify_manifest(dir.path()));
+    }
+}
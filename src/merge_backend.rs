@@ -0,0 +1,223 @@
+// The tools that can physically merge chapters into one file. mp4-merge is the default since it's
+// built in. ffmpeg's concat demuxer is the fallback for files mp4-merge can't handle, and needs
+// `ffmpeg` on the PATH.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::cli::MergeBackendKind;
+use crate::mp4::Movie;
+
+pub trait MergeBackend {
+    fn name(&self) -> &'static str;
+
+    /// Joins `chapters` back to back into `output`, calling `progress` with values from 0 to 1
+    fn merge(
+        &self,
+        chapters: &[PathBuf],
+        output: &Path,
+        progress: &dyn Fn(f64),
+    ) -> Result<(), Error>;
+}
+
+pub fn merge_backend(kind: MergeBackendKind) -> Box<dyn MergeBackend> {
+    match kind {
+        MergeBackendKind::Mp4Merge => Box::new(Mp4MergeBackend),
+        MergeBackendKind::Ffmpeg => Box::new(FfmpegBackend),
+    }
+}
+
+pub struct Mp4MergeBackend;
+
+impl MergeBackend for Mp4MergeBackend {
+    fn name(&self) -> &'static str {
+        "mp4-merge"
+    }
+
+    fn merge(
+        &self,
+        chapters: &[PathBuf],
+        output: &Path,
+        progress: &dyn Fn(f64),
+    ) -> Result<(), Error> {
+        mp4_merge::join_files(chapters, &output.to_path_buf(), progress)
+    }
+}
+
+pub struct FfmpegBackend;
+
+/// How much of ffmpeg's stderr ends up in the error message
+const STDERR_TAIL_LINES: usize = 20;
+
+/// A concat demuxer list. Paths are single quoted, so single quotes in them need escaping.
+fn concat_list(chapters: &[PathBuf]) -> String {
+    chapters
+        .iter()
+        .map(|chapter| {
+            format!(
+                "file '{}'\n",
+                chapter.to_string_lossy().replace('\'', r"'\''")
+            )
+        })
+        .collect()
+}
+
+impl MergeBackend for FfmpegBackend {
+    fn name(&self) -> &'static str {
+        "ffmpeg"
+    }
+
+    fn merge(
+        &self,
+        chapters: &[PathBuf],
+        output: &Path,
+        progress: &dyn Fn(f64),
+    ) -> Result<(), Error> {
+        let first_chapter = Movie::read(
+            chapters
+                .first()
+                .ok_or_else(|| Error::other("No chapters"))?,
+        )?;
+        let mut total_duration = 0.0;
+        for chapter in chapters {
+            total_duration += Movie::read(chapter)?.duration_secs();
+        }
+
+        // The list goes in the system temp directory under a name of its own, so it can't replace
+        // anything next to the output
+        let list_path = std::env::temp_dir().join(format!(
+            "{}-{}.concat.txt",
+            env!("CARGO_PKG_NAME"),
+            uuid::Uuid::new_v4()
+        ));
+        fs::write(&list_path, concat_list(chapters))?;
+
+        let mut command = Command::new("ffmpeg");
+        command
+            .args(["-hide_banner", "-nostdin", "-loglevel", "error", "-n"])
+            .args(["-f", "concat", "-safe", "0", "-i"])
+            .arg(&list_path)
+            .args(["-map", "0:v", "-map", "0:a?"]);
+        // ffmpeg won't copy data streams it doesn't recognize unless they're mapped explicitly and
+        // tagged. The timecode track is left out, since ffmpeg writes its own from the metadata.
+        if let Some(index) = first_chapter
+            .tracks()
+            .iter()
+            .position(|t| t.sample_format().as_ref() == Some(b"gpmd"))
+        {
+            command
+                .args(["-map", &format!("0:{}", index)])
+                .args(["-tag:d", "gpmd"]);
+        }
+        command
+            .args(["-c", "copy", "-copy_unknown", "-map_metadata", "0"])
+            .args(["-progress", "pipe:1", "-nostats"])
+            .arg(output)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let result = run_ffmpeg(command, total_duration, progress);
+        let _ = fs::remove_file(&list_path);
+        result
+    }
+}
+
+/// -progress writes key=value lines, including the output position in microseconds. Returns how far
+/// along that is, if `line` is one.
+fn progress_fraction(line: &str, total_duration: f64) -> Option<f64> {
+    let microseconds = line.strip_prefix("out_time_us=")?.parse::<f64>().ok()?;
+    match total_duration > 0.0 {
+        true => Some((microseconds / 1_000_000.0 / total_duration).clamp(0.0, 1.0)),
+        false => None,
+    }
+}
+
+fn run_ffmpeg(
+    mut command: Command,
+    total_duration: f64,
+    progress: &dyn Fn(f64),
+) -> Result<(), Error> {
+    let mut child = command.spawn().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::new(ErrorKind::NotFound, "ffmpeg was not found on the PATH"),
+        _ => e,
+    })?;
+
+    // Nothing else reads stderr while stdout is read below, and ffmpeg blocks once a pipe's buffer
+    // is full, so it's drained on its own thread. Only the end is kept, that's where the error is.
+    let stderr_tail = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            for line in BufReader::new(stderr).split(b'\n').map_while(Result::ok) {
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(String::from_utf8_lossy(&line).to_string());
+            }
+            Vec::from(tail).join("\n")
+        })
+    });
+
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    // Stop ffmpeg before the caller removes the partial output it's writing to
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(e);
+                }
+            };
+            if let Some(fraction) = progress_fraction(&line, total_duration) {
+                progress(fraction);
+            }
+        }
+    }
+
+    let status = child.wait()?;
+    let stderr = stderr_tail
+        .and_then(|tail| tail.join().ok())
+        .unwrap_or_default();
+    if !status.success() {
+        return Err(Error::other(format!(
+            "ffmpeg exited with {}: {}",
+            status,
+            stderr.trim()
+        )));
+    }
+    progress(1.0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concat_list_quotes_every_path() {
+        let chapters = [
+            PathBuf::from("/Volumes/GoPro/DCIM/100GOPRO/GH011234.MP4"),
+            PathBuf::from("/Users/me/Aaron's trip/GH021234.MP4"),
+        ];
+        assert_eq!(
+            concat_list(&chapters),
+            "file '/Volumes/GoPro/DCIM/100GOPRO/GH011234.MP4'\n\
+             file '/Users/me/Aaron'\\''s trip/GH021234.MP4'\n"
+        );
+    }
+
+    #[test]
+    fn reads_progress_from_out_time() {
+        assert_eq!(progress_fraction("out_time_us=30000000", 120.0), Some(0.25));
+        // ffmpeg can report a little past the end, or a negative time before the first packet
+        assert_eq!(progress_fraction("out_time_us=121000000", 120.0), Some(1.0));
+        assert_eq!(progress_fraction("out_time_us=-5000", 120.0), Some(0.0));
+        assert_eq!(progress_fraction("out_time_us=N/A", 120.0), None);
+        assert_eq!(progress_fraction("out_time_ms=30000000", 120.0), None);
+        assert_eq!(progress_fraction("out_time_us=30000000", 0.0), None);
+    }
+}
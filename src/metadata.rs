@@ -0,0 +1,306 @@
+// Which camera shot a video and how it was set up, from the first chapter's metadata:
+//
+// - udta boxes: FIRM (firmware version), CAME (camera ID) and MUID (media unique ID)
+// - the GPMF box in udta, written by HERO8 and newer: MINF (model), VFOV (lens mode), PRTN (ProTune)
+//   and the ProTune settings
+// - DVNM (device name) in the first telemetry sample, for the model on older cameras
+// - the video track, for the resolution and frame rate
+//
+// Everything is optional, since which of these exist depends on the model and firmware.
+
+use std::fs::File;
+use std::io::{BufReader, Error};
+use std::path::Path;
+
+use serde::Serialize;
+use time::PrimitiveDateTime;
+
+use crate::camera::camera_identity;
+use crate::gpmf::{self, KlvEntry};
+use crate::mp4::{read_sample, Movie};
+use crate::telemetry::find_entry;
+
+/// ProTune settings in the udta GPMF box, and what to call them
+const PROTUNE_SETTINGS: [(&[u8; 4], &str); 7] = [
+    (b"PTWB", "white balance"),
+    (b"PTCL", "color"),
+    (b"PTSH", "sharpness"),
+    (b"PTEV", "EV"),
+    (b"EXPT", "shutter"),
+    (b"PIMN", "ISO min"),
+    (b"PIMX", "ISO max"),
+];
+
+/// Firmware versions start with a per-model prefix, which gives the model when nothing names it
+const FIRMWARE_MODELS: [(&str, &str); 8] = [
+    ("HD6.01", "HERO6 Black"),
+    ("HD7.01", "HERO7 Black"),
+    ("HD8.01", "HERO8 Black"),
+    ("HD9.01", "HERO9 Black"),
+    ("H21.01", "HERO10 Black"),
+    ("H22.01", "HERO11 Black"),
+    ("H22.03", "HERO11 Black Mini"),
+    ("H23.01", "HERO12 Black"),
+];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VideoMetadata {
+    /// e.g. "HERO9 Black"
+    pub model: Option<String>,
+    /// e.g. "HD9.01.01.72.00"
+    pub firmware: Option<String>,
+    /// See camera.rs
+    pub serial: Option<String>,
+    /// Unique to each recording, as hex
+    pub media_id: Option<String>,
+    #[serde(skip)]
+    pub recorded: Option<PrimitiveDateTime>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub frame_rate: Option<f64>,
+    /// e.g. "Wide" or "Linear"
+    pub lens_mode: Option<String>,
+    pub protune: Option<bool>,
+    /// The ProTune settings the camera recorded, e.g. ("white balance", "AUTO")
+    pub protune_settings: Vec<(String, String)>,
+}
+
+impl VideoMetadata {
+    pub fn read(path: &Path) -> Result<VideoMetadata, Error> {
+        let movie = Movie::read(path)?;
+        let udta_gpmf = movie
+            .moov
+            .find(&[b"udta", b"GPMF"])
+            .and_then(|gpmf| gpmf::parse(&gpmf.payload).ok())
+            .unwrap_or_default();
+        let udta_string = |typ: &[u8; 4]| {
+            movie
+                .moov
+                .find(&[b"udta", typ])
+                .map(|b| {
+                    String::from_utf8_lossy(&b.payload)
+                        .trim_matches(['\0', ' '])
+                        .to_string()
+                })
+                .filter(|s| !s.is_empty())
+        };
+        let gpmf_string = |key: &[u8; 4]| find_entry(&udta_gpmf, key).and_then(entry_text);
+
+        let firmware = udta_string(b"FIRM").or_else(|| gpmf_string(b"FIRM"));
+        let model = gpmf_string(b"MINF")
+            .or_else(|| first_telemetry_entry(path, &movie, b"DVNM"))
+            .or_else(|| {
+                let firmware = firmware.as_deref()?;
+                FIRMWARE_MODELS
+                    .iter()
+                    .find(|(prefix, _)| firmware.starts_with(prefix))
+                    .map(|(_, model)| model.to_string())
+            });
+        let media_id = movie
+            .moov
+            .find(&[b"udta", b"MUID"])
+            .filter(|muid| muid.payload.iter().any(|byte| *byte != 0))
+            .map(|muid| muid.payload.iter().map(|b| format!("{:02x}", b)).collect());
+        let video_track = movie.video_track();
+        let (width, height) = video_track
+            .and_then(|track| track.dimensions())
+            .map_or((None, None), |(w, h)| (Some(w), Some(h)));
+
+        Ok(VideoMetadata {
+            serial: camera_identity(path, Some(&movie)),
+            recorded: movie.creation_time(),
+            frame_rate: video_track.and_then(|track| track.frame_rate().ok()),
+            lens_mode: gpmf_string(b"VFOV").map(|vfov| lens_mode_name(&vfov)),
+            protune: gpmf_string(b"PRTN").map(|prtn| prtn == "Y"),
+            protune_settings: PROTUNE_SETTINGS
+                .iter()
+                .filter_map(|(key, name)| Some((name.to_string(), gpmf_string(key)?)))
+                .collect(),
+            model,
+            firmware,
+            media_id,
+            width,
+            height,
+        })
+    }
+
+    /// One line for the plan, e.g. "HERO9 Black, 1920x1080 @ 29.97 fps, Wide, ProTune on"
+    pub fn summary(&self) -> String {
+        let mut parts = vec![self.model.clone().unwrap_or("unknown camera".to_string())];
+        if let Some(firmware) = &self.firmware {
+            parts[0] = format!("{} ({})", parts[0], firmware);
+        }
+        match (self.width, self.height, self.frame_rate) {
+            (Some(w), Some(h), Some(fps)) => parts.push(format!("{}x{} @ {:.2} fps", w, h, fps)),
+            (Some(w), Some(h), None) => parts.push(format!("{}x{}", w, h)),
+            _ => (),
+        }
+        if let Some(lens_mode) = &self.lens_mode {
+            parts.push(lens_mode.clone());
+        }
+        match self.protune {
+            Some(true) if !self.protune_settings.is_empty() => parts.push(format!(
+                "ProTune on ({})",
+                self.protune_settings
+                    .iter()
+                    .map(|(name, value)| format!("{} {}", name, value))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+            Some(true) => parts.push("ProTune on".to_string()),
+            Some(false) => parts.push("ProTune off".to_string()),
+            None => (),
+        }
+        parts.join(", ")
+    }
+}
+
+/// What the camera calls its field of view (VFOV), in the names Gyroflow's lens profiles use
+pub fn lens_mode_name(vfov: &str) -> String {
+    match vfov {
+        "W" => "Wide",
+        "S" => "SuperView",
+        "L" => "Linear",
+        "H" => "HyperView",
+        "N" => "Narrow",
+        "M" => "Medium",
+        "X" => "Max SuperView",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Strings as they are, and numbers without a pointless ".0"
+fn entry_text(entry: &KlvEntry) -> Option<String> {
+    let text = match entry.type_char {
+        b'c' => entry.as_string(),
+        _ => {
+            let value = *entry.values(None).first()?.first()?;
+            match value.fract() == 0.0 {
+                true => format!("{}", value as i64),
+                false => format!("{}", value),
+            }
+        }
+    };
+    match text.is_empty() {
+        true => None,
+        false => Some(text),
+    }
+}
+
+fn first_telemetry_entry(path: &Path, movie: &Movie, key: &[u8; 4]) -> Option<String> {
+    let track = movie.track_with_format(b"gpmd")?;
+    let first_sample = track.samples().ok()?.into_iter().next()?;
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let entries = gpmf::parse(&read_sample(&mut reader, &first_sample).ok()?).ok()?;
+    find_entry(&entries, key).and_then(entry_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{klv, mp4_box, write_mp4, FixtureTrack, TempDir};
+
+    fn entry(klv: Vec<u8>) -> KlvEntry {
+        gpmf::parse(&klv).unwrap().remove(0)
+    }
+
+    #[test]
+    fn reads_the_udta_gpmf_settings() {
+        let dir = TempDir::new();
+        let path = dir.join("GX011234.MP4");
+        let udta_gpmf = [
+            klv(b"MINF", b'c', 1, b"HERO10 Black"),
+            klv(b"VFOV", b'c', 1, b"L"),
+            klv(b"PRTN", b'c', 1, b"Y"),
+            klv(b"PTWB", b'c', 1, b"AUTO"),
+            klv(b"PIMX", b'L', 4, &1600u32.to_be_bytes()),
+            klv(b"CASN", b'c', 1, b"C3441325012345"),
+        ]
+        .concat();
+        write_mp4(
+            &path,
+            &[FixtureTrack::video(30, 30)],
+            vec![
+                mp4_box(b"FIRM", b"H21.01.01.46.00\0"),
+                mp4_box(b"MUID", &[0xab, 0, 0, 0x01]),
+                mp4_box(b"GPMF", &udta_gpmf),
+            ],
+        );
+
+        let metadata = VideoMetadata::read(&path).unwrap();
+        assert_eq!(metadata.model.as_deref(), Some("HERO10 Black"));
+        assert_eq!(metadata.firmware.as_deref(), Some("H21.01.01.46.00"));
+        assert_eq!(metadata.serial.as_deref(), Some("C3441325012345"));
+        assert_eq!(metadata.media_id.as_deref(), Some("ab000001"));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.frame_rate, Some(30.0));
+        assert_eq!(metadata.lens_mode.as_deref(), Some("Linear"));
+        assert_eq!(metadata.protune, Some(true));
+        assert_eq!(
+            metadata.protune_settings,
+            [
+                ("white balance".to_string(), "AUTO".to_string()),
+                ("ISO max".to_string(), "1600".to_string()),
+            ]
+        );
+        assert_eq!(
+            metadata.summary(),
+            "HERO10 Black (H21.01.01.46.00), 1920x1080 @ 30.00 fps, Linear, ProTune on \
+             (white balance AUTO, ISO max 1600)"
+        );
+    }
+
+    #[test]
+    fn older_cameras_are_named_by_their_telemetry_or_firmware() {
+        let dir = TempDir::new();
+        let path = dir.join("GH011234.MP4");
+        let dvnm = klv(b"DEVC", 0, 1, &klv(b"DVNM", b'c', 1, b"HERO7 Black"));
+        write_mp4(
+            &path,
+            &[
+                FixtureTrack::video(30, 30),
+                FixtureTrack::telemetry(vec![dvnm]),
+            ],
+            vec![mp4_box(b"FIRM", b"HD6.01.01.61.00")],
+        );
+        assert_eq!(
+            VideoMetadata::read(&path).unwrap().model.as_deref(),
+            Some("HERO7 Black")
+        );
+
+        write_mp4(
+            &path,
+            &[FixtureTrack::video(30, 30)],
+            vec![mp4_box(b"FIRM", b"HD6.01.01.61.00")],
+        );
+        let metadata = VideoMetadata::read(&path).unwrap();
+        assert_eq!(metadata.model.as_deref(), Some("HERO6 Black"));
+        assert_eq!(metadata.media_id, None);
+        assert_eq!(metadata.protune, None);
+
+        write_mp4(
+            &path,
+            &[FixtureTrack::video(30, 30)],
+            vec![mp4_box(b"FIRM", b"XX1.01.01.00.00")],
+        );
+        assert_eq!(VideoMetadata::read(&path).unwrap().model, None);
+    }
+
+    #[test]
+    fn entry_text_drops_pointless_decimals() {
+        assert_eq!(
+            entry_text(&entry(klv(b"PTWB", b'c', 1, b"5500K"))).as_deref(),
+            Some("5500K")
+        );
+        assert_eq!(
+            entry_text(&entry(klv(b"PIMN", b'L', 4, &100u32.to_be_bytes()))).as_deref(),
+            Some("100")
+        );
+        assert_eq!(
+            entry_text(&entry(klv(b"PTEV", b'f', 4, &0.5f32.to_be_bytes()))).as_deref(),
+            Some("0.5")
+        );
+        assert_eq!(entry_text(&entry(klv(b"PTCL", b'c', 1, b""))), None);
+    }
+}
@@ -0,0 +1,919 @@
+// Just enough MP4 parsing to get at the things mp4-merge doesn't expose: track sample tables (for
+// the GPMF telemetry track), movie durations, and the `udta` atoms GoPro writes.
+//
+// The whole `moov` box is read into memory, which is fine since it's a few MB at most. `mdat` is
+// never read here; sample data is read on demand from the offsets in the sample tables.
+//
+// A modified `moov` can be written back with `rewrite_moov`, which never moves existing sample data.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use time::{Duration, PrimitiveDateTime};
+
+pub type FourCC = [u8; 4];
+
+// Boxes whose payload is just a list of child boxes
+const CONTAINER_BOXES: [&FourCC; 10] = [
+    b"moov", b"trak", b"edts", b"mdia", b"minf", b"dinf", b"stbl", b"udta", b"mvex", b"tref",
+];
+
+/// An MP4 box. Container boxes have their children parsed, everything else keeps its raw payload.
+#[derive(Debug, Clone)]
+pub struct Mp4Box {
+    pub typ: FourCC,
+    pub payload: Vec<u8>,
+    pub children: Vec<Mp4Box>,
+}
+
+impl Mp4Box {
+    pub fn new(typ: &FourCC, payload: Vec<u8>) -> Mp4Box {
+        Mp4Box {
+            typ: *typ,
+            payload,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn container(typ: &FourCC, children: Vec<Mp4Box>) -> Mp4Box {
+        Mp4Box {
+            typ: *typ,
+            payload: Vec::new(),
+            children,
+        }
+    }
+
+    pub fn is_container(typ: &FourCC) -> bool {
+        CONTAINER_BOXES.contains(&typ)
+    }
+
+    pub fn child(&self, typ: &FourCC) -> Option<&Mp4Box> {
+        self.children.iter().find(|c| &c.typ == typ)
+    }
+
+    pub fn child_mut(&mut self, typ: &FourCC) -> Option<&mut Mp4Box> {
+        self.children.iter_mut().find(|c| &c.typ == typ)
+    }
+
+    pub fn children_of_type<'a>(&'a self, typ: &'a FourCC) -> impl Iterator<Item = &'a Mp4Box> {
+        self.children.iter().filter(move |c| &c.typ == typ)
+    }
+
+    /// Follows a path of box types down from this box, e.g. `[b"mdia", b"minf", b"stbl"]`
+    pub fn find(&self, path: &[&FourCC]) -> Option<&Mp4Box> {
+        let mut current = self;
+        for typ in path {
+            current = current.child(typ)?;
+        }
+        Some(current)
+    }
+
+    /// Size of the box including its header
+    pub fn size(&self) -> u64 {
+        let content =
+            self.payload.len() as u64 + self.children.iter().map(|c| c.size()).sum::<u64>();
+        match content + 8 > u32::MAX as u64 {
+            true => content + 16,
+            false => content + 8,
+        }
+    }
+
+    /// Serializes the box. A box is written as its payload followed by its children, so boxes built
+    /// by hand can mix the two (e.g. a full box header followed by child boxes).
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as u64 {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(&self.typ);
+            out.extend_from_slice(&size.to_be_bytes());
+        } else {
+            out.extend_from_slice(&(size as u32).to_be_bytes());
+            out.extend_from_slice(&self.typ);
+        }
+        out.extend_from_slice(&self.payload);
+        for child in &self.children {
+            child.write_to(out);
+        }
+    }
+}
+
+fn parse_boxes(data: &[u8]) -> Result<Vec<Mp4Box>, Error> {
+    let mut boxes = Vec::new();
+    let mut reader = ByteReader::new(data);
+    while reader.remaining() >= 8 {
+        let size = reader.read_u32()? as u64;
+        let typ = reader.read_fourcc()?;
+        let (payload_size, header_size) = match size {
+            0 => (reader.remaining() as u64, 8),
+            1 => {
+                let size = reader.read_u64()?;
+                (size.saturating_sub(16), 16)
+            }
+            _ => (size.saturating_sub(8), 8),
+        };
+        if size != 0 && size < header_size {
+            return Err(invalid_data(format!(
+                "Invalid size {} for box {}",
+                size,
+                fourcc_to_string(&typ)
+            )));
+        }
+        let payload = reader.read_bytes(payload_size as usize)?;
+        if Mp4Box::is_container(&typ) {
+            boxes.push(Mp4Box {
+                typ,
+                payload: Vec::new(),
+                children: parse_boxes(payload)?,
+            });
+        } else {
+            boxes.push(Mp4Box {
+                typ,
+                payload: payload.to_vec(),
+                children: Vec::new(),
+            });
+        }
+    }
+    Ok(boxes)
+}
+
+/// A top-level box, as found by walking the file without reading payloads
+#[derive(Debug, Clone, Copy)]
+pub struct TopLevelBox {
+    pub typ: FourCC,
+    pub offset: u64,
+    pub size: u64,
+    pub header_size: u64,
+}
+
+pub fn read_top_level_boxes<R: Read + Seek>(reader: &mut R) -> Result<Vec<TopLevelBox>, Error> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= file_size {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        let typ: FourCC = header[4..8].try_into().unwrap();
+        let (size, header_size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => (file_size - offset, 8),
+            1 => {
+                let mut largesize = [0u8; 8];
+                reader.read_exact(&mut largesize)?;
+                (u64::from_be_bytes(largesize), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_size || offset.checked_add(size).is_none_or(|end| end > file_size) {
+            return Err(invalid_data(format!(
+                "Box {} at offset {} runs past the end of the file",
+                fourcc_to_string(&typ),
+                offset
+            )));
+        }
+        boxes.push(TopLevelBox {
+            typ,
+            offset,
+            size,
+            header_size,
+        });
+        offset += size;
+    }
+    Ok(boxes)
+}
+
+/// Where a file's `moov` is, and where `rewrite_moov` will put anything appended after it
+#[derive(Debug, Clone, Copy)]
+pub struct MoovLocation {
+    pub moov: TopLevelBox,
+    /// Offset the new boxes will be written at: the old moov if it's the last box, the end of
+    /// the file otherwise
+    pub append_offset: u64,
+    is_last: bool,
+}
+
+impl MoovLocation {
+    pub fn find(path: &Path) -> Result<MoovLocation, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let boxes = read_top_level_boxes(&mut reader)?;
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let index = boxes
+            .iter()
+            .position(|b| &b.typ == b"moov")
+            .ok_or_else(|| invalid_data(format!("No moov box in {}", path.display())))?;
+        let is_last = index == boxes.len() - 1;
+        Ok(MoovLocation {
+            moov: boxes[index],
+            append_offset: match is_last {
+                true => boxes[index].offset,
+                false => file_size,
+            },
+            is_last,
+        })
+    }
+
+    /// Offset of the first byte of `mdat_payload` once `rewrite_moov` has written it
+    pub fn appended_data_offset(&self) -> u64 {
+        self.append_offset + 8
+    }
+}
+
+/// Replaces a file's moov, optionally writing new sample data (referenced by the new moov) in an
+/// mdat just before it. Existing sample data never moves, so the other tracks' chunk offsets stay
+/// valid: a moov at the end of the file is overwritten, any other moov is turned into a `free` box.
+pub fn rewrite_moov(
+    path: &Path,
+    location: &MoovLocation,
+    moov: &Mp4Box,
+    mdat_payload: &[u8],
+) -> Result<(), Error> {
+    if mdat_payload.len() as u64 + 8 > u32::MAX as u64 {
+        return Err(invalid_data(
+            "Appended sample data is too large".to_string(),
+        ));
+    }
+    let mut data = Vec::new();
+    if !mdat_payload.is_empty() {
+        data.extend_from_slice(&(mdat_payload.len() as u32 + 8).to_be_bytes());
+        data.extend_from_slice(b"mdat");
+        data.extend_from_slice(mdat_payload);
+    }
+    moov.write_to(&mut data);
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    if location.is_last {
+        file.set_len(location.moov.offset)?;
+    } else {
+        file.seek(SeekFrom::Start(location.moov.offset + 4))?;
+        file.write_all(b"free")?;
+    }
+    file.seek(SeekFrom::Start(location.append_offset))?;
+    file.write_all(&data)?;
+    file.sync_all()
+}
+
+/// What `mvhd` says about the whole movie
+#[derive(Debug, Clone, Copy)]
+pub struct MovieHeader {
+    /// Seconds since 1904-01-01, or 0 if it wasn't set
+    pub creation_time: u64,
+    pub timescale: u32,
+    pub duration: u64,
+}
+
+impl MovieHeader {
+    /// Reads only the mvhd, skipping over the rest of the moov. A long recording's moov is several
+    /// MB of sample tables, which isn't worth parsing for its start and length.
+    pub fn read(path: &Path) -> Result<MovieHeader, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let moov = read_top_level_boxes(&mut reader)?
+            .into_iter()
+            .find(|b| &b.typ == b"moov")
+            .ok_or_else(|| invalid_data(format!("No moov box in {}", path.display())))?;
+        let moov_end = moov.offset + moov.size;
+        let mut offset = moov.offset + moov.header_size;
+        while offset + 8 <= moov_end {
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut header = [0u8; 8];
+            reader.read_exact(&mut header)?;
+            let size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+            if size < 8 || offset + size > moov_end {
+                break;
+            }
+            if &header[4..8] == b"mvhd" {
+                let mut payload = vec![0u8; (size - 8) as usize];
+                reader.read_exact(&mut payload)?;
+                return MovieHeader::parse(&payload);
+            }
+            offset += size;
+        }
+        Err(invalid_data(format!("No mvhd box in {}", path.display())))
+    }
+
+    fn parse(mvhd: &[u8]) -> Result<MovieHeader, Error> {
+        let mut reader = ByteReader::new(mvhd);
+        let version = reader.read_u8()?;
+        reader.skip(3)?;
+        let (creation_time, timescale, duration) = if version == 1 {
+            let creation_time = reader.read_u64()?;
+            reader.skip(8)?;
+            (creation_time, reader.read_u32()?, reader.read_u64()?)
+        } else {
+            let creation_time = reader.read_u32()? as u64;
+            reader.skip(4)?;
+            (creation_time, reader.read_u32()?, reader.read_u32()? as u64)
+        };
+        Ok(MovieHeader {
+            creation_time,
+            timescale,
+            duration,
+        })
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        if self.timescale == 0 {
+            return 0.0;
+        }
+        self.duration as f64 / self.timescale as f64
+    }
+
+    /// The duration as a time::Duration, or None if it's too long for one, which only a corrupt
+    /// mvhd would be
+    pub fn duration(&self) -> Option<Duration> {
+        Duration::checked_seconds_f64(self.duration_secs())
+    }
+
+    /// When recording started. GoPros have no time zone setting, so this is the camera's wall clock
+    /// time rather than UTC.
+    pub fn creation_time(&self) -> Option<PrimitiveDateTime> {
+        if self.creation_time == 0 {
+            return None;
+        }
+        let mp4_epoch = PrimitiveDateTime::new(
+            time::Date::from_calendar_date(1904, time::Month::January, 1).ok()?,
+            time::Time::MIDNIGHT,
+        );
+        mp4_epoch.checked_add(Duration::seconds(self.creation_time as i64))
+    }
+}
+
+/// The parsed `moov` of an MP4 file
+#[derive(Debug, Clone)]
+pub struct Movie {
+    pub moov: Mp4Box,
+    /// Seconds since 1904-01-01, or 0 if it wasn't set
+    pub creation_time: u64,
+    pub timescale: u32,
+    pub duration: u64,
+}
+
+impl Movie {
+    pub fn read(path: &Path) -> Result<Movie, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let moov = read_top_level_boxes(&mut reader)?
+            .into_iter()
+            .find(|b| &b.typ == b"moov")
+            .ok_or_else(|| invalid_data(format!("No moov box in {}", path.display())))?;
+
+        reader.seek(SeekFrom::Start(moov.offset + moov.header_size))?;
+        let mut payload = vec![0u8; (moov.size - moov.header_size) as usize];
+        reader.read_exact(&mut payload)?;
+        Movie::from_moov(Mp4Box {
+            typ: *b"moov",
+            payload: Vec::new(),
+            children: parse_boxes(&payload)?,
+        })
+    }
+
+    pub fn from_moov(moov: Mp4Box) -> Result<Movie, Error> {
+        let mvhd = moov
+            .child(b"mvhd")
+            .ok_or_else(|| invalid_data("No mvhd box".to_string()))?;
+        let header = MovieHeader::parse(&mvhd.payload)?;
+        Ok(Movie {
+            moov,
+            creation_time: header.creation_time,
+            timescale: header.timescale,
+            duration: header.duration,
+        })
+    }
+
+    pub fn header(&self) -> MovieHeader {
+        MovieHeader {
+            creation_time: self.creation_time,
+            timescale: self.timescale,
+            duration: self.duration,
+        }
+    }
+
+    pub fn duration_secs(&self) -> f64 {
+        self.header().duration_secs()
+    }
+
+    /// See MovieHeader::creation_time
+    pub fn creation_time(&self) -> Option<PrimitiveDateTime> {
+        self.header().creation_time()
+    }
+
+    pub fn tracks(&self) -> Vec<Track<'_>> {
+        self.moov
+            .children_of_type(b"trak")
+            .map(|trak| Track { trak })
+            .collect()
+    }
+
+    /// The first video track. GoPro files only ever have one.
+    pub fn video_track(&self) -> Option<Track<'_>> {
+        self.tracks()
+            .into_iter()
+            .find(|t| t.handler_type().as_ref() == Some(b"vide"))
+    }
+
+    /// Finds the first track whose sample description is `format`, e.g. `gpmd` for GoPro telemetry
+    pub fn track_with_format(&self, format: &FourCC) -> Option<Track<'_>> {
+        self.tracks()
+            .into_iter()
+            .find(|t| t.sample_format().as_ref() == Some(format))
+    }
+}
+
+/// A single sample, located in the file and on the track's timeline
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub offset: u64,
+    pub size: u32,
+    /// Decode time, in the track's timescale
+    pub time: u64,
+    pub duration: u32,
+    /// Presentation time minus decode time, from ctts. Only non-zero with B-frames.
+    pub composition_offset: i32,
+    /// Whether decoding can start at this sample, i.e. it's a keyframe
+    pub is_sync: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Track<'a> {
+    pub trak: &'a Mp4Box,
+}
+
+impl<'a> Track<'a> {
+    fn stbl(&self) -> Option<&'a Mp4Box> {
+        self.trak.find(&[b"mdia", b"minf", b"stbl"])
+    }
+
+    pub fn track_id(&self) -> Option<u32> {
+        let tkhd = self.trak.child(b"tkhd")?;
+        let offset = match tkhd.payload.first()? {
+            1 => 20,
+            _ => 12,
+        };
+        Some(u32::from_be_bytes(
+            tkhd.payload.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    }
+
+    pub fn handler_type(&self) -> Option<FourCC> {
+        let hdlr = self.trak.find(&[b"mdia", b"hdlr"])?;
+        hdlr.payload.get(8..12)?.try_into().ok()
+    }
+
+    /// Width and height from a visual sample description (`avc1`, `hvc1`, ...)
+    pub fn dimensions(&self) -> Option<(u16, u16)> {
+        let description = self.sample_description()?;
+        let width = u16::from_be_bytes(description.get(32..34)?.try_into().ok()?);
+        let height = u16::from_be_bytes(description.get(34..36)?.try_into().ok()?);
+        Some((width, height))
+    }
+
+    /// Channel count and sample rate from an audio sample description (`mp4a`, ...)
+    pub fn audio_config(&self) -> Option<(u16, u32)> {
+        let description = self.sample_description()?;
+        let channels = u16::from_be_bytes(description.get(24..26)?.try_into().ok()?);
+        // 16.16 fixed point
+        let sample_rate = u16::from_be_bytes(description.get(32..34)?.try_into().ok()?);
+        Some((channels, sample_rate as u32))
+    }
+
+    /// Average samples per second, which is the frame rate for video tracks
+    pub fn frame_rate(&self) -> Result<f64, Error> {
+        let (timescale, _duration) = self.media_time()?;
+        let stts = self
+            .stbl()
+            .and_then(|stbl| stbl.child(b"stts"))
+            .ok_or_else(|| invalid_data("No stts box".to_string()))?;
+        let mut reader = ByteReader::new(&stts.payload);
+        reader.skip(4)?;
+        let (mut sample_count, mut total_duration) = (0u64, 0u64);
+        for _ in 0..reader.read_u32()? {
+            let count = reader.read_u32()? as u64;
+            sample_count += count;
+            total_duration = total_duration.saturating_add(count * reader.read_u32()? as u64);
+        }
+        if total_duration == 0 {
+            return Ok(0.0);
+        }
+        Ok(sample_count as f64 * timescale as f64 / total_duration as f64)
+    }
+
+    /// (sample delta, timescale) of the first stts entry, e.g. (1001, 30000) for 29.97fps video.
+    /// Unlike `frame_rate` this is exact, for formats that want the frame rate as a fraction.
+    pub fn frame_duration(&self) -> Result<(u32, u32), Error> {
+        let (timescale, _duration) = self.media_time()?;
+        let stts = self
+            .stbl()
+            .and_then(|stbl| stbl.child(b"stts"))
+            .ok_or_else(|| invalid_data("No stts box".to_string()))?;
+        let mut reader = ByteReader::new(&stts.payload);
+        reader.skip(12)?;
+        Ok((reader.read_u32()?, timescale))
+    }
+
+    /// The format of the first sample description, e.g. `avc1`, `hvc1`, `mp4a`, `gpmd`
+    pub fn sample_format(&self) -> Option<FourCC> {
+        self.sample_description()?.get(4..8)?.try_into().ok()
+    }
+
+    /// The raw first entry of `stsd`, including its size and format header
+    pub fn sample_description(&self) -> Option<&'a [u8]> {
+        let stsd = self.stbl()?.child(b"stsd")?;
+        let size = u32::from_be_bytes(stsd.payload.get(8..12)?.try_into().ok()?) as usize;
+        stsd.payload.get(8..8 + size)
+    }
+
+    /// Returns (timescale, duration) from `mdhd`
+    pub fn media_time(&self) -> Result<(u32, u64), Error> {
+        let mdhd = self
+            .trak
+            .find(&[b"mdia", b"mdhd"])
+            .ok_or_else(|| invalid_data("No mdhd box".to_string()))?;
+        let mut reader = ByteReader::new(&mdhd.payload);
+        let version = reader.read_u8()?;
+        reader.skip(3)?;
+        if version == 1 {
+            reader.skip(16)?;
+            Ok((reader.read_u32()?, reader.read_u64()?))
+        } else {
+            reader.skip(8)?;
+            Ok((reader.read_u32()?, reader.read_u32()? as u64))
+        }
+    }
+
+    pub fn samples(&self) -> Result<Vec<Sample>, Error> {
+        let stbl = self
+            .stbl()
+            .ok_or_else(|| invalid_data("No stbl box".to_string()))?;
+        let table = |typ: &FourCC| {
+            stbl.child(typ)
+                .map(|b| b.payload.as_slice())
+                .ok_or_else(|| invalid_data(format!("No {} box", fourcc_to_string(typ))))
+        };
+
+        // Sample sizes. The counts in these tables come straight from the file, so nothing is
+        // allocated for more entries than the table's payload actually holds.
+        let mut stsz = ByteReader::new(table(b"stsz")?);
+        stsz.skip(4)?;
+        let uniform_size = stsz.read_u32()?;
+        let sample_count = stsz.read_u32()? as usize;
+        let mut sizes = Vec::new();
+        if uniform_size == 0 {
+            if sample_count > stsz.remaining() / 4 {
+                return Err(invalid_data(format!(
+                    "stsz lists {} samples but only has room for {}",
+                    sample_count,
+                    stsz.remaining() / 4
+                )));
+            }
+            sizes.reserve_exact(sample_count);
+            for _ in 0..sample_count {
+                sizes.push(stsz.read_u32()?);
+            }
+        }
+        let size_of = |index: usize| match uniform_size {
+            0 => sizes[index],
+            size => size,
+        };
+
+        // Chunk offsets
+        let mut chunk_offsets = Vec::new();
+        if let Some(co64) = stbl.child(b"co64") {
+            let mut reader = ByteReader::new(&co64.payload);
+            reader.skip(4)?;
+            for _ in 0..reader.read_u32()? {
+                chunk_offsets.push(reader.read_u64()?);
+            }
+        } else {
+            let mut reader = ByteReader::new(table(b"stco")?);
+            reader.skip(4)?;
+            for _ in 0..reader.read_u32()? {
+                chunk_offsets.push(reader.read_u32()? as u64);
+            }
+        }
+
+        // Samples per chunk, as runs of (first_chunk, samples_per_chunk)
+        let mut stsc = ByteReader::new(table(b"stsc")?);
+        stsc.skip(4)?;
+        let mut chunk_runs = Vec::new();
+        for _ in 0..stsc.read_u32()? {
+            let first_chunk = stsc.read_u32()?;
+            let samples_per_chunk = stsc.read_u32()?;
+            stsc.skip(4)?;
+            chunk_runs.push((first_chunk, samples_per_chunk));
+        }
+
+        // Sample durations, as runs of (count, delta)
+        let mut stts = ByteReader::new(table(b"stts")?);
+        stts.skip(4)?;
+        let mut duration_runs = Vec::new();
+        for _ in 0..stts.read_u32()? {
+            duration_runs.push((stts.read_u32()?, stts.read_u32()?));
+        }
+        let mut durations = Runs::new(duration_runs);
+
+        // Composition offsets, as runs of (count, offset)
+        let mut composition_offset_runs = Vec::new();
+        if let Some(ctts) = stbl.child(b"ctts") {
+            let mut reader = ByteReader::new(&ctts.payload);
+            reader.skip(4)?;
+            for _ in 0..reader.read_u32()? {
+                composition_offset_runs.push((reader.read_u32()?, reader.read_u32()? as i32));
+            }
+        }
+        let mut composition_offsets = Runs::new(composition_offset_runs);
+
+        // Sync samples (1-based). Without stss, every sample is a sync sample.
+        let mut sync_samples = None;
+        if let Some(stss) = stbl.child(b"stss") {
+            let mut reader = ByteReader::new(&stss.payload);
+            reader.skip(4)?;
+            let mut numbers = Vec::new();
+            for _ in 0..reader.read_u32()? {
+                numbers.push(reader.read_u32()?);
+            }
+            numbers.sort_unstable();
+            sync_samples = Some(numbers);
+        }
+
+        let mut samples = Vec::with_capacity(sizes.len());
+        let mut time = 0;
+        for (run_index, &(first_chunk, samples_per_chunk)) in chunk_runs.iter().enumerate() {
+            let last_chunk = match chunk_runs.get(run_index + 1) {
+                Some(&(next_first_chunk, _)) => next_first_chunk.saturating_sub(1),
+                None => chunk_offsets.len() as u32,
+            };
+            for chunk in first_chunk..=last_chunk {
+                let mut offset = match chunk_offsets.get((chunk as usize).wrapping_sub(1)) {
+                    Some(&offset) => offset,
+                    None => break,
+                };
+                for _ in 0..samples_per_chunk {
+                    let index = samples.len();
+                    if index >= sample_count {
+                        break;
+                    }
+                    let size = size_of(index);
+                    let duration = durations.next().unwrap_or(0);
+                    samples.push(Sample {
+                        offset,
+                        size,
+                        time,
+                        duration,
+                        composition_offset: composition_offsets.next().unwrap_or(0),
+                        is_sync: sync_samples.as_ref().is_none_or(|numbers| {
+                            numbers.binary_search(&(index as u32 + 1)).is_ok()
+                        }),
+                    });
+                    offset = offset.checked_add(size as u64).ok_or_else(|| {
+                        invalid_data("A chunk runs past the largest possible offset".to_string())
+                    })?;
+                    time += duration as u64;
+                }
+            }
+        }
+        Ok(samples)
+    }
+}
+
+/// Steps through a run-length encoded table (stts, ctts) one sample at a time, without expanding
+/// it, since the counts in it can be anything in a corrupt file
+struct Runs<T> {
+    runs: std::vec::IntoIter<(u32, T)>,
+    current: Option<(u32, T)>,
+}
+
+impl<T: Copy> Runs<T> {
+    fn new(runs: Vec<(u32, T)>) -> Self {
+        Runs {
+            runs: runs.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl<T: Copy> Iterator for Runs<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            match &mut self.current {
+                Some((count, value)) if *count > 0 => {
+                    *count -= 1;
+                    return Some(*value);
+                }
+                _ => self.current = Some(self.runs.next()?),
+            }
+        }
+    }
+}
+
+pub fn read_sample<R: Read + Seek>(reader: &mut R, sample: &Sample) -> Result<Vec<u8>, Error> {
+    reader.seek(SeekFrom::Start(sample.offset))?;
+    let mut data = vec![0u8; sample.size as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+pub fn fourcc_to_string(typ: &FourCC) -> String {
+    typ.iter()
+        .map(|&c| match c.is_ascii_graphic() || c == b' ' {
+            true => c as char,
+            false => '?',
+        })
+        .collect()
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Big-endian reads over a byte slice, failing with `InvalidData` instead of panicking on
+/// truncated input
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ByteReader { data, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        if count > self.remaining() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unexpected end of data",
+            ));
+        }
+        let bytes = &self.data[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<(), Error> {
+        self.read_bytes(count).map(|_| ())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_fourcc(&mut self) -> Result<FourCC, Error> {
+        Ok(self.read_bytes(4)?.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir, CREATION_TIME};
+
+    fn stbl_trak(tables: Vec<Mp4Box>) -> Mp4Box {
+        Mp4Box::container(
+            b"trak",
+            vec![Mp4Box::container(
+                b"mdia",
+                vec![Mp4Box::container(
+                    b"minf",
+                    vec![Mp4Box::container(b"stbl", tables)],
+                )],
+            )],
+        )
+    }
+
+    fn table(typ: &FourCC, values: &[u32]) -> Mp4Box {
+        let payload = std::iter::once(0)
+            .chain(values.iter().copied())
+            .flat_map(u32::to_be_bytes)
+            .collect();
+        Mp4Box::new(typ, payload)
+    }
+
+    #[test]
+    fn samples_follow_the_sample_tables() {
+        let trak = stbl_trak(vec![
+            // Two chunks of two samples, then one of one
+            table(b"stsc", &[2, 1, 2, 1, 3, 1, 1]),
+            table(b"stco", &[3, 1000, 2000, 3000]),
+            table(b"stsz", &[0, 5, 10, 20, 30, 40, 50]),
+            table(b"stts", &[2, 3, 100, 2, 200]),
+            table(b"ctts", &[2, 1, 200, 4, 0]),
+            table(b"stss", &[2, 4, 1]),
+        ]);
+        let samples = Track { trak: &trak }.samples().unwrap();
+
+        let fields: Vec<_> = samples
+            .iter()
+            .map(|s| (s.offset, s.size, s.time, s.duration))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (1000, 10, 0, 100),
+                (1010, 20, 100, 100),
+                (2000, 30, 200, 100),
+                (2030, 40, 300, 200),
+                (3000, 50, 500, 200),
+            ]
+        );
+        let offsets: Vec<_> = samples.iter().map(|s| s.composition_offset).collect();
+        assert_eq!(offsets, [200, 0, 0, 0, 0]);
+        let sync: Vec<_> = samples.iter().map(|s| s.is_sync).collect();
+        assert_eq!(sync, [true, false, false, true, false]);
+    }
+
+    #[test]
+    fn samples_rejects_a_sample_count_the_table_cant_hold() {
+        let trak = stbl_trak(vec![
+            table(b"stsc", &[1, 1, 1, 1]),
+            table(b"stco", &[1, 1000]),
+            table(b"stsz", &[0, u32::MAX, 10]),
+            table(b"stts", &[1, 1, 100]),
+        ]);
+        assert!(Track { trak: &trak }.samples().is_err());
+    }
+
+    #[test]
+    fn samples_doesnt_expand_huge_run_counts() {
+        let trak = stbl_trak(vec![
+            table(b"stsc", &[1, 1, 2, 1]),
+            table(b"stco", &[1, 1000]),
+            table(b"stsz", &[10, 2]),
+            table(b"stts", &[1, u32::MAX, 100]),
+            table(b"ctts", &[1, u32::MAX, 5]),
+            table(b"stss", &[u32::MAX]),
+        ]);
+        let samples = Track { trak: &trak }.samples();
+        assert!(samples.is_err());
+
+        let trak = stbl_trak(vec![
+            table(b"stsc", &[1, 1, 2, 1]),
+            table(b"stco", &[1, 1000]),
+            table(b"stsz", &[10, 2]),
+            table(b"stts", &[1, u32::MAX, 100]),
+            table(b"ctts", &[1, u32::MAX, 5]),
+        ]);
+        let samples = Track { trak: &trak }.samples().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[1].offset, samples[1].time), (1010, 100));
+        assert_eq!(samples[1].composition_offset, 5);
+    }
+
+    #[test]
+    fn offsets_past_the_largest_possible_one_are_rejected() {
+        let co64 = [0, 1]
+            .into_iter()
+            .flat_map(u32::to_be_bytes)
+            .chain((u64::MAX - 5).to_be_bytes())
+            .collect();
+        let trak = stbl_trak(vec![
+            table(b"stsc", &[1, 1, 2, 1]),
+            Mp4Box::new(b"co64", co64),
+            table(b"stsz", &[10, 2]),
+            table(b"stts", &[1, 2, 100]),
+        ]);
+        assert!(Track { trak: &trak }.samples().is_err());
+
+        let mut file = 1u32.to_be_bytes().to_vec();
+        file.extend_from_slice(b"mdat");
+        file.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_top_level_boxes(&mut std::io::Cursor::new(file)).is_err());
+    }
+
+    #[test]
+    fn reads_a_movie() {
+        let dir = TempDir::new();
+        let path = dir.join("movie.mp4");
+        write_mp4(&path, &[FixtureTrack::video(60, 30)], Vec::new());
+
+        let header = MovieHeader::read(&path).unwrap();
+        assert_eq!(header.creation_time, CREATION_TIME);
+        assert_eq!(header.duration(), Some(Duration::seconds(2)));
+
+        let movie = Movie::read(&path).unwrap();
+        let video = movie.video_track().unwrap();
+        assert_eq!(video.dimensions(), Some((1920, 1080)));
+        assert_eq!(video.frame_rate().unwrap(), 30.0);
+        let samples = video.samples().unwrap();
+        assert_eq!(samples.len(), 60);
+        let mut reader = File::open(&path).unwrap();
+        assert_eq!(read_sample(&mut reader, &samples[42]).unwrap(), [42; 100]);
+        let keyframes: Vec<_> = (0..60).filter(|&i| samples[i].is_sync).collect();
+        assert_eq!(keyframes, [0, 30]);
+    }
+}
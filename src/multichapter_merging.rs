@@ -1,55 +1,257 @@
-use std::{path::PathBuf, process};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use colored::Colorize;
-use log::{error, info};
-use normpath::PathExt;
+use log::{error, info, warn};
 // use predicates::path;
 
-use crate::gopro::GoProChapteredVideoFile;
+use crate::chapters::{
+    chapter_boundary_markers, markers_in_range, write_chapter_markers, ChapterMarker,
+};
+use crate::cli::AssembleArgs;
+use crate::events::{emit, Event};
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::hilights::{hilights_in_range, read_hilights, write_hilight_sidecar};
+use crate::merge_backend::merge_backend;
+use crate::progress::Progress;
+use crate::summary::{Outcome, RunSummary};
+use crate::trim::{find_trim, trim_chapters};
 
-// Create "concat demux" input files
+// Create "concat demux" input files. Returns any sidecar files written next to the merged videos.
+// A video that fails is recorded in `summary` and the rest are still merged.
 pub fn combine_multichapter_videos(
-    multichapter_videos_sorted: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
+    multichapter_videos_sorted: HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
     output_dir: PathBuf,
-) {
-    if multichapter_videos_sorted.len() == 0 {
+    output_paths: &HashMap<VideoId, PathBuf>,
+    args: AssembleArgs,
+    progress: &Progress,
+    summary: &mut RunSummary,
+) -> Vec<PathBuf> {
+    let mut sidecars = Vec::new();
+    if multichapter_videos_sorted.is_empty() {
         info!("{}", "No multichapter videos to combine".blue().bold());
-        return;
+        return sidecars;
     }
-    // Iterate through multichapter video map, and mp4-merge it.
+    let backend = merge_backend(args.backend);
+    info!("Merging with {}", backend.name().blue().bold());
+    // Iterate through multichapter video map, and merge it.
     for video in multichapter_videos_sorted {
-        let number = video.0;
+        let number = video.0.clone();
         let mut paths_to_chapters = Vec::<PathBuf>::new();
-        // TODO: Accumulate the chapters into a vec, then pass to mp4-merge
-        for chapter in video.1 {
+        for chapter in &video.1 {
             paths_to_chapters.push(chapter.abs_path.clone());
             info!(
-                "Concatenating chapter {:?} of video {}...",
+                "Concatenating chapter {:?} of {}...",
                 chapter.abs_path.to_str(),
                 number
             );
         }
-        let output_filename = generate_merged_chaptered_video_output_file_name(&output_dir, number);
-        mp4_merge::join_files(&paths_to_chapters, &output_filename, |progress| {
-            println!("Merging... {:.2}%", progress * 100.0);
-        })
-        .unwrap();
+        let output_filename = output_paths[&number].clone();
+        if args.dry_run {
+            info!("Dry run, skipping merge!");
+            summary.record(number, "merge", Outcome::Skipped("dry run".to_string()));
+            continue;
+        }
+        // Neither backend is trusted not to replace an existing file, e.g. a video of the same
+        // number assembled from another card
+        if output_filename.exists() {
+            let e = Error::new(ErrorKind::AlreadyExists, "the output already exists");
+            record_failure(summary, &number, &output_filename, "Failed to merge", e);
+            continue;
+        }
+
+        // Trimming happens before merging, so the untrimmed video is never written
+        let trimmed = match find_trim(&args.plan.trim, &number) {
+            Some(range) => {
+                match trim_chapters(&paths_to_chapters, range, &output_dir, &number.to_string()) {
+                    Ok(trimmed) => Some(trimmed),
+                    Err(e) => {
+                        record_failure(summary, &number, &output_filename, "Failed to trim", e);
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+        let kept_range = trimmed.as_ref().map(|t| (t.start, t.end));
+        if let Some((start, end)) = kept_range {
+            info!(
+                "Trimming {} to {:.3}s..{}",
+                number,
+                start,
+                match end.is_finite() {
+                    true => format!("{:.3}s", end),
+                    false => "the end".to_string(),
+                }
+            );
+        }
+
+        let files_to_merge = trimmed.as_ref().map_or(&paths_to_chapters, |t| &t.files);
+        let chapter_bytes = paths_to_chapters
+            .iter()
+            .filter_map(|path| path.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        emit(Event::GroupStarted {
+            video_number: number.video_number,
+            camera: number.camera.clone(),
+            session: number.session.clone(),
+            chapters: paths_to_chapters.clone(),
+            output: output_filename.clone(),
+        });
+        let group_started = Instant::now();
+        progress.start(&output_filename.to_string_lossy(), chapter_bytes);
+        if trimmed.is_some() {
+            progress.resize_item(
+                files_to_merge
+                    .iter()
+                    .filter_map(|path| path.metadata().ok())
+                    .map(|metadata| metadata.len())
+                    .sum(),
+            );
+        }
+        let merged = backend.merge(files_to_merge, &output_filename, &|fraction| {
+            progress.update(fraction)
+        });
+        progress.finish_item();
+        drop(trimmed);
+        if let Err(e) = merged {
+            // Don't leave a partial video behind to be mistaken for a complete one
+            let _ = fs::remove_file(&output_filename);
+            record_failure(summary, &number, &output_filename, "Failed to merge", e);
+            continue;
+        }
+
+        // Boundary markers go first, so they win over a HiLight in the same millisecond
+        let mut markers = Vec::new();
+        if args.boundary_chapters {
+            match chapter_boundary_markers(&video.1) {
+                Ok(boundaries) => match kept_range {
+                    Some((start, end)) => markers.extend(markers_in_range(&boundaries, start, end)),
+                    None => markers.extend(boundaries),
+                },
+                Err(e) => warn!(
+                    "Could not read chapter durations for {}: {}",
+                    output_filename.display(),
+                    e
+                ),
+            }
+        }
+        if args.hilight_chapters || args.hilight_sidecar {
+            if let Some(sidecar) = read_hilights_into_markers(
+                &video.1,
+                &output_filename,
+                &args,
+                kept_range,
+                &mut markers,
+            ) {
+                sidecars.push(sidecar);
+            }
+        }
+        if !markers.is_empty() {
+            if let Err(e) = write_chapter_markers(&output_filename, &markers) {
+                warn!(
+                    "Could not write chapter markers to {}: {}",
+                    output_filename.display(),
+                    e
+                );
+            }
+        }
+        emit(Event::GroupFinished {
+            video_number: number.video_number,
+            camera: number.camera.clone(),
+            session: number.session.clone(),
+            bytes: output_filename.metadata().map_or(0, |m| m.len()),
+            output: output_filename,
+            elapsed_secs: group_started.elapsed().as_secs_f64(),
+        });
+        summary.record(number, "merge", Outcome::Succeeded);
     }
+    sidecars
 }
 
-fn generate_merged_chaptered_video_output_file_name(
-    output_dir: &PathBuf,
-    video_number: u16,
-) -> PathBuf {
-    let mut output_file_name = match PathBuf::from(output_dir.clone()).normalize() {
-        Ok(path) => path,
+fn record_failure(
+    summary: &mut RunSummary,
+    video: &VideoId,
+    output: &Path,
+    what: &str,
+    e: impl Display,
+) {
+    error!("{} {}: {}", what.red().bold(), output.display(), e);
+    emit(Event::Error {
+        video_number: Some(video.video_number),
+        camera: video.camera.clone(),
+        session: video.session.clone(),
+        path: Some(output.to_path_buf()),
+        message: format!("{}: {}", what, e),
+    });
+    summary.record(
+        video.clone(),
+        "merge",
+        Outcome::Failed(format!("{}: {}", what, e)),
+    );
+}
+
+/// Carries the HiLight tags of every chapter over to the merged video, as chapter markers and/or a
+/// sidecar. `kept_range` is the part of the untrimmed video that was kept, if it was trimmed.
+/// Returns the sidecar's path if one was written.
+fn read_hilights_into_markers(
+    chapters: &[GoProChapteredVideoFile],
+    merged_video: &Path,
+    args: &AssembleArgs,
+    kept_range: Option<(f64, f64)>,
+    markers: &mut Vec<ChapterMarker>,
+) -> Option<PathBuf> {
+    let hilights = match read_hilights(chapters) {
+        Ok(hilights) => match kept_range {
+            Some((start, end)) => hilights_in_range(&hilights, start, end),
+            None => hilights,
+        },
         Err(e) => {
-            error!("Could not normalize output directory path: {}", e);
-            process::exit(1);
+            warn!(
+                "Could not read HiLights for {}: {}",
+                merged_video.display(),
+                e
+            );
+            return None;
         }
     };
-    output_file_name.push(format!("GoPro_{}", video_number.to_string()));
-    let mut output_file_name = output_file_name.as_path().to_path_buf();
-    output_file_name.set_extension("mp4");
-    output_file_name
+    info!(
+        "Found {} HiLights for {}",
+        hilights.len(),
+        merged_video.to_string_lossy().blue().bold()
+    );
+
+    if args.hilight_chapters {
+        markers.extend(
+            hilights
+                .iter()
+                .enumerate()
+                .map(|(i, hilight)| hilight.to_chapter_marker(i + 1)),
+        );
+    }
+
+    if !args.hilight_sidecar {
+        return None;
+    }
+    let sidecar = merged_video.with_extension("hilights.json");
+    match write_hilight_sidecar(&sidecar, merged_video, &hilights) {
+        Ok(()) => {
+            info!(
+                "Wrote HiLights to {}",
+                sidecar.to_string_lossy().blue().bold()
+            );
+            Some(sidecar)
+        }
+        Err(e) => {
+            warn!("Failed to write {}: {}", sidecar.display(), e);
+            None
+        }
+    }
 }
@@ -0,0 +1,171 @@
+// With `--organize`, outputs go in subdirectories of the output directory instead of all landing in
+// it directly. The subdirectories come from a template like `{year}/{year}-{month}-{day}/{camera}/`,
+// filled in from each video's first chapter, so assembled footage goes straight into an archive.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use time::PrimitiveDateTime;
+
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+
+/// What can go between braces in an --organize template
+pub const LAYOUT_PLACEHOLDERS: [&str; 7] =
+    ["year", "month", "day", "hour", "minute", "camera", "serial"];
+
+/// Used for anything that can't be read from the video, so it still ends up somewhere predictable
+const UNKNOWN: &str = "unknown";
+
+/// Checks a template for unknown placeholders and paths that would leave the output directory
+pub fn validate_layout(template: &str) -> Result<(), String> {
+    if Path::new(template).is_absolute()
+        || Path::new(template)
+            .components()
+            .any(|c| c == Component::ParentDir)
+    {
+        return Err(format!(
+            "'{}' must stay inside the output directory",
+            template
+        ));
+    }
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}') else {
+            return Err(format!("unclosed '{{' in '{}'", template));
+        };
+        let placeholder = &rest[open + 1..open + close];
+        if !LAYOUT_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder {{{}}}, expected one of {}",
+                placeholder,
+                LAYOUT_PLACEHOLDERS
+                    .iter()
+                    .map(|p| format!("{{{}}}", p))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        rest = &rest[open + close + 1..];
+    }
+    Ok(())
+}
+
+/// The subdirectory of the output directory each video goes in. Empty without a template.
+pub fn organized_subdirectories(
+    template: Option<&str>,
+    multichapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+) -> HashMap<VideoId, PathBuf> {
+    let Some(template) = template else {
+        return HashMap::new();
+    };
+    multichapter_videos
+        .iter()
+        .chain(single_chapter_videos)
+        .filter_map(|(video, chapters)| {
+            Some((video.clone(), subdirectory(template, chapters.first()?)))
+        })
+        .collect()
+}
+
+fn subdirectory(template: &str, first_chapter: &GoProChapteredVideoFile) -> PathBuf {
+    let metadata = first_chapter.metadata.clone().unwrap_or_default();
+    let recorded = metadata.recorded;
+    let date_part = |value: fn(&PrimitiveDateTime) -> String| recorded.as_ref().map(value);
+    let expand = |placeholder: &str| {
+        let value = match placeholder {
+            "year" => date_part(|t| t.year().to_string()),
+            "month" => date_part(|t| format!("{:02}", t.month() as u8)),
+            "day" => date_part(|t| format!("{:02}", t.day())),
+            "hour" => date_part(|t| format!("{:02}", t.hour())),
+            "minute" => date_part(|t| format!("{:02}", t.minute())),
+            "camera" => metadata.model.clone(),
+            "serial" => metadata.serial.clone(),
+            _ => None,
+        };
+        // Values come from the camera, so they can't be trusted to be a single path component
+        value
+            .filter(|value| !value.trim().is_empty())
+            .unwrap_or_else(|| UNKNOWN.to_string())
+            .replace(['/', '\\', ':'], "-")
+    };
+
+    // One pass, so a value that looks like a placeholder isn't expanded again
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..open]);
+        expanded.push_str(&expand(&rest[open + 1..open + close]));
+        rest = &rest[open + close + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::VideoMetadata;
+    use time::macros::datetime;
+
+    fn chapter(metadata: Option<VideoMetadata>) -> GoProChapteredVideoFile {
+        GoProChapteredVideoFile {
+            abs_path: PathBuf::from("/card/DCIM/100GOPRO/GH017322.MP4"),
+            video_number: 7322,
+            chapter: 1,
+            camera: None,
+            session: None,
+            metadata,
+        }
+    }
+
+    #[test]
+    fn validates_templates() {
+        assert!(validate_layout("{year}/{year}-{month}-{day}/{camera}/").is_ok());
+        assert!(validate_layout("archive").is_ok());
+        assert!(validate_layout("/{year}").is_err());
+        assert!(validate_layout("{year}/../{camera}").is_err());
+        assert!(validate_layout("{yaer}").is_err());
+        assert!(validate_layout("{year").is_err());
+    }
+
+    #[test]
+    fn fills_in_templates_from_the_first_chapter() {
+        let metadata = VideoMetadata {
+            model: Some("HERO9 Black".to_string()),
+            serial: Some("C3441325012345".to_string()),
+            recorded: Some(datetime!(2024-06-01 14:30:05)),
+            ..Default::default()
+        };
+        assert_eq!(
+            subdirectory(
+                "{year}/{year}-{month}-{day}/{camera} {serial}/{hour}h{minute}",
+                &chapter(Some(metadata))
+            ),
+            Path::new("2024/2024-06-01/HERO9 Black C3441325012345/14h30")
+        );
+        assert_eq!(
+            subdirectory("{year}/{camera}", &chapter(None)),
+            Path::new("unknown/unknown")
+        );
+    }
+
+    #[test]
+    fn camera_values_stay_one_path_component() {
+        let metadata = VideoMetadata {
+            model: Some("../HERO/{serial}".to_string()),
+            serial: Some("..".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            subdirectory("{camera}/{serial}/./videos", &chapter(Some(metadata))),
+            Path::new("..-HERO-{serial}/videos")
+        );
+    }
+}
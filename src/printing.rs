@@ -1,25 +1,49 @@
+use std::collections::HashMap;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
-use log::info;
+use log::{info, warn};
 
-use crate::gopro::GoProChapteredVideoFile;
+use crate::cli::SingleChapterMode;
+use crate::events::events_on_stdout;
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::summary::RunSummary;
+
+/// Prints output meant for humans, which goes to stderr when stdout is taken by the event stream
+fn print_for_humans(text: &str) {
+    match events_on_stdout() {
+        true => eprintln!("{}", text),
+        false => println!("{}", text),
+    }
+}
+
+/// e.g. 1:02:03, for `inspect` and the --interactive video list
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
 
 // This code sucks! Can't handle any multiline inputs, and looks seriously clunky.
 pub fn print_box_header(text: String) {
     let mut header: String = "╔".to_string();
     for _ in 0..text.len() + 2 {
-        header.push_str("═");
+        header.push('═');
     }
-    header.push_str("╗");
+    header.push('╗');
     header.push_str("\n║ ");
     header.push_str(&text);
     header.push_str(" ║\n╚");
     for _ in 0..text.len() + 2 {
-        header.push_str("═");
+        header.push('═');
     }
-    header.push_str("╝");
-    println!("{}", header.blue().bold());
+    header.push('╝');
+    print_for_humans(&header.blue().bold().to_string());
 }
 
 pub fn print_header() {
@@ -32,20 +56,27 @@ pub fn get_confirmation_before_proceeeding(skip_confirmation: bool) -> bool {
         return true;
     }
     let mut input = String::new();
-    print!("{} ", "Proceed? (y/n)".yellow().bold());
-    std::io::stdout().flush().unwrap();
-    std::io::stdin().read_line(&mut input).unwrap();
-    if input.trim().to_string().to_lowercase().starts_with("y") {
-        return true;
-    } else {
+    match events_on_stdout() {
+        true => {
+            eprint!("{} ", "Proceed? (y/n)".yellow().bold());
+            let _ = std::io::stderr().flush();
+        }
+        false => {
+            print!("{} ", "Proceed? (y/n)".yellow().bold());
+            let _ = std::io::stdout().flush();
+        }
+    }
+    // Nothing to read, e.g. stdin is closed, isn't a yes
+    if std::io::stdin().read_line(&mut input).is_err() {
         return false;
     }
+    input.trim().to_lowercase().starts_with('y')
 }
 
 pub fn print_expected_output(
-    single_chapter_videos: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
-    multichapter_videos_sorted: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
-    copy_single_chapter_instead_of_renaming: bool,
+    single_chapter_videos: std::collections::HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    multichapter_videos_sorted: std::collections::HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_mode: SingleChapterMode,
 ) {
     let mut total_chapters_to_combine = 0;
     let total_videos_to_output = multichapter_videos_sorted.len();
@@ -57,34 +88,159 @@ pub fn print_expected_output(
         total_videos_to_output.to_string().blue().bold(),
         total_chapters_to_combine.to_string().blue().bold()
     );
-    if total_videos_to_output > 0 {
-        info!("{:#?}", multichapter_videos_sorted);
-    }
-    if copy_single_chapter_instead_of_renaming {
-        info!(
-            "And {} single chapter video(s) to copy",
-            single_chapter_videos.len().to_string().blue().bold()
-        );
-    } else {
+    print_planned_videos(&multichapter_videos_sorted);
+    info!(
+        "And {} single chapter video(s) to {}",
+        single_chapter_videos.len().to_string().blue().bold(),
+        single_chapter_mode.as_str()
+    );
+    print_planned_videos(&single_chapter_videos);
+}
+
+/// Each video's chapters, and what the camera recorded about how it was shot
+fn print_planned_videos(videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>) {
+    let mut videos: Vec<_> = videos.iter().collect();
+    videos.sort_by_key(|(video, _)| *video);
+    for (video, chapters) in videos {
         info!(
-            "And {} single chapter video(s) to rename",
-            single_chapter_videos.len().to_string().blue().bold()
+            "  {}: {}",
+            video.to_string().bold(),
+            chapters
+                .iter()
+                .map(|c| c.abs_path.file_name().unwrap_or_default().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
         );
+        if let Some(metadata) = chapters.first().and_then(|c| c.metadata.as_ref()) {
+            info!("    {}", metadata.summary().dimmed());
+        }
     }
 }
 
 pub fn print_remove_commands(
-    multichapter_videos: std::collections::HashMap<u16, Vec<GoProChapteredVideoFile>>,
+    multichapter_videos: std::collections::HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
 ) {
-    println!(
-        "{}",
-        "Run the following command(s) to remove the merged chapters"
+    print_for_humans(
+        &"Run the following command(s) to remove the merged chapters"
             .yellow()
             .bold()
+            .to_string(),
     );
     for (_key, chapters) in multichapter_videos {
         for chapter in chapters {
-            println!("rm '{}'", chapter.abs_path.to_str().unwrap().blue().bold());
+            print_for_humans(&format!(
+                "rm {}",
+                shell_quote(&chapter.abs_path).blue().bold()
+            ));
         }
     }
 }
+
+/// Single quotes a path for a shell. A path that isn't UTF-8 is shown lossily, so its command
+/// won't match anything rather than removing the wrong file.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
+
+pub fn print_already_imported(
+    already_imported: &std::collections::HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+) {
+    if already_imported.is_empty() {
+        return;
+    }
+    let mut video_ids: Vec<&VideoId> = already_imported.keys().collect();
+    video_ids.sort();
+    info!(
+        "Skipping {} video(s) that were already imported (use --force to process them again): {}",
+        already_imported.len().to_string().yellow().bold(),
+        video_ids
+            .iter()
+            .map(|video| video.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+pub fn print_run_summary(summary: &RunSummary) {
+    let (succeeded, skipped, failed) = summary.counts();
+    if succeeded + skipped + failed == 0 && summary.errors().is_empty() {
+        return;
+    }
+    print_for_humans(&format!(
+        "{} {} succeeded, {} skipped, {} failed",
+        "Summary:".bold(),
+        succeeded.to_string().green().bold(),
+        skipped.to_string().yellow().bold(),
+        failed.to_string().red().bold()
+    ));
+    for row in summary.table() {
+        print_for_humans(&format!("  {}", row));
+    }
+    for error in summary.errors() {
+        print_for_humans(&format!("  {} {}", "error".red().bold(), error));
+    }
+}
+
+pub fn print_filtered_out(filtered_out: &[(Vec<GoProChapteredVideoFile>, String)]) {
+    if filtered_out.is_empty() {
+        return;
+    }
+    info!(
+        "Skipping {} video(s) that don't match the filters",
+        filtered_out.len().to_string().yellow().bold()
+    );
+    for (chapters, reason) in filtered_out {
+        if let Some(chapter) = chapters.first() {
+            info!("  {}: {}", chapter.video_id(), reason);
+        }
+    }
+}
+
+pub fn print_duplicate_conflicts(conflicting: &[(Vec<GoProChapteredVideoFile>, String)]) {
+    if conflicting.is_empty() {
+        return;
+    }
+    warn!(
+        "Skipping {} video(s) with chapters that differ between inputs",
+        conflicting.len().to_string().yellow().bold()
+    );
+    for (chapters, reason) in conflicting {
+        if let Some(chapter) = chapters.first() {
+            warn!("  {}: {}", chapter.video_id(), reason);
+        }
+    }
+}
+
+/// With more than one input directory, shows which one(s) each video's chapters came from
+pub fn print_video_sources(
+    multichapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    input_dirs: &[PathBuf],
+) {
+    if input_dirs.len() < 2 {
+        return;
+    }
+    let mut videos: Vec<(&VideoId, &Vec<GoProChapteredVideoFile>)> = multichapter_videos
+        .iter()
+        .chain(single_chapter_videos.iter())
+        .collect();
+    if videos.is_empty() {
+        return;
+    }
+    videos.sort_by_key(|(video, _)| *video);
+    info!("Chapters came from:");
+    for (video, chapters) in videos {
+        let sources: Vec<String> = chapters
+            .iter()
+            .map(|chapter| {
+                let source = input_dirs
+                    .iter()
+                    .filter(|dir| chapter.abs_path.starts_with(dir))
+                    .max_by_key(|dir| dir.components().count())
+                    .unwrap_or(&chapter.abs_path);
+                format!("{} ({})", chapter.chapter, source.display())
+            })
+            .collect();
+        info!("  {}: {}", video, sources.join(", "));
+    }
+}
@@ -0,0 +1,160 @@
+// Progress for everything a run writes: merges, copies, trims and renames. On a terminal, this
+// draws a bar for the current video and one for the whole run, with the bytes done, throughput and
+// ETA. When stderr isn't a terminal (e.g. piped to a file or run from cron), the bars are hidden and
+// a progress line is logged every few seconds instead.
+//
+// Log lines go through the same MultiProgress (see logging.rs), so they're printed above the bars
+// instead of tearing them.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use log::info;
+
+use crate::events::{emit, Event};
+
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn multi_progress() -> &'static MultiProgress {
+    static MULTI_PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+    MULTI_PROGRESS.get_or_init(MultiProgress::new)
+}
+
+struct State {
+    /// Bytes of the videos that are already done
+    completed_bytes: u64,
+    item_bytes: u64,
+    label: String,
+    last_logged: Instant,
+    last_event: Option<Instant>,
+}
+
+pub struct Progress {
+    overall: ProgressBar,
+    current: ProgressBar,
+    /// Whether to log progress lines, since there are no bars to show it
+    log_lines: bool,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Progress {
+    /// Progress across `total_bytes` of videos. Nothing is shown if there are no bytes to process.
+    pub fn new(total_bytes: u64) -> Self {
+        let multi = multi_progress();
+        let (overall, current) = match total_bytes > 0 && !multi.is_hidden() {
+            true => (
+                multi.add(ProgressBar::new(total_bytes).with_style(style(
+                    "{prefix:>8.bold} [{bar:30.cyan/blue}] {bytes}/{total_bytes} \
+                     ({binary_bytes_per_sec}, ETA {eta})",
+                ))),
+                multi.add(ProgressBar::new(0).with_style(style(
+                    "{prefix:>8.bold} [{bar:30.green/white}] {bytes}/{total_bytes} {wide_msg}",
+                ))),
+            ),
+            false => (ProgressBar::hidden(), ProgressBar::hidden()),
+        };
+        overall.set_length(total_bytes);
+        overall.set_prefix("Overall");
+        current.set_prefix("Video");
+        Self {
+            overall,
+            current,
+            log_lines: total_bytes > 0 && multi.is_hidden(),
+            started: Instant::now(),
+            state: Mutex::new(State {
+                completed_bytes: 0,
+                item_bytes: 0,
+                label: String::new(),
+                last_logged: Instant::now(),
+                last_event: None,
+            }),
+        }
+    }
+
+    /// Starts on the next video, which is `bytes` big
+    pub fn start(&self, label: &str, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.item_bytes = bytes;
+        state.label = label.to_string();
+        self.current.set_length(bytes);
+        self.current.set_position(0);
+        self.current.set_message(label.to_string());
+    }
+
+    /// How far into the current video we are, from 0 to 1
+    pub fn update(&self, fraction: f64) {
+        let mut state = self.state.lock().unwrap();
+        let item_position = (state.item_bytes as f64 * fraction.clamp(0.0, 1.0)) as u64;
+        self.current.set_position(item_position);
+        self.overall
+            .set_position(state.completed_bytes + item_position);
+
+        if state
+            .last_event
+            .is_none_or(|last_event| last_event.elapsed() >= EVENT_INTERVAL)
+        {
+            state.last_event = Some(Instant::now());
+            emit(Event::Progress {
+                current: state.label.clone(),
+                fraction,
+                bytes_done: self.overall.position(),
+                total_bytes: self.overall.length().unwrap_or_default(),
+            });
+        }
+        if self.log_lines && state.last_logged.elapsed() >= LOG_INTERVAL {
+            state.last_logged = Instant::now();
+            info!(
+                "{}: {:.1}% done, {} of {} overall ({}/s, ETA {})",
+                state.label,
+                fraction * 100.0,
+                HumanBytes(self.overall.position()),
+                HumanBytes(self.overall.length().unwrap_or_default()),
+                HumanBytes(self.overall.per_sec() as u64),
+                HumanDuration(self.overall.eta())
+            );
+        }
+    }
+
+    /// The current video turned out to be `bytes` big rather than what it was started with, e.g.
+    /// because only part of it was kept. The overall total changes to match.
+    pub fn resize_item(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let total = self.overall.length().unwrap_or_default();
+        self.overall
+            .set_length((total + bytes).saturating_sub(state.item_bytes));
+        state.item_bytes = bytes;
+        self.current.set_length(bytes);
+    }
+
+    /// Marks the current video as done
+    pub fn finish_item(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.completed_bytes += state.item_bytes;
+        state.item_bytes = 0;
+        self.current
+            .set_position(self.current.length().unwrap_or_default());
+        self.overall.set_position(state.completed_bytes);
+    }
+
+    pub fn finish(&self) {
+        self.current.finish_and_clear();
+        self.overall.finish_and_clear();
+        let completed_bytes = self.state.lock().unwrap().completed_bytes;
+        if completed_bytes > 0 {
+            info!(
+                "Processed {} in {}",
+                HumanBytes(completed_bytes),
+                HumanDuration(self.started.elapsed())
+            );
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ")
+}
@@ -0,0 +1,604 @@
+// Lossless cutting: copies the samples of every track that fall in a time range into a new,
+// standalone MP4. Nothing is re-encoded, so ranges should start on a video keyframe.
+//
+// The new file is laid out like GoPro's own: ftyp, mdat, then moov. Samples are written in the order
+// they appear in the source, so tracks stay interleaved the same way. The source moov is kept,
+// except for the per-sample tables and durations, which are rebuilt for the range.
+//
+// Timecode and chapter tracks have a few long samples rather than many short ones, so the sample
+// covering the start of the range is kept (shortened) rather than dropped. Timecode samples are
+// rewritten to the timecode at the start of the range.
+
+use std::fs::{remove_file, File};
+use std::io::{BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::chapters::{build_chpl, markers_in_range, read_chpl};
+use crate::filesystem::create_new;
+use crate::mp4::{read_top_level_boxes, FourCC, Movie, Mp4Box, Sample, Track};
+
+// Upper bound on the bytes each sample adds to the sample tables (stsz, stts, ctts, stss, co64)
+const TABLE_BYTES_PER_SAMPLE: u64 = 32;
+
+struct SourceTrack {
+    /// Index of the trak in moov's children
+    box_index: usize,
+    timescale: u32,
+    samples: Vec<Sample>,
+    /// Running total of sample sizes, for estimating the size of a range
+    size_prefix_sums: Vec<u64>,
+    is_video: bool,
+    /// Timecode or chapter track, with samples that span long stretches of the video
+    sparse: bool,
+    has_stss: bool,
+    /// The version of the source's ctts, if it has one. Version 1 allows negative offsets.
+    ctts_version: Option<u8>,
+}
+
+impl SourceTrack {
+    fn to_track_time(&self, seconds: f64) -> u64 {
+        match seconds.is_finite() {
+            true => (seconds * self.timescale as f64).round() as u64,
+            false => u64::MAX,
+        }
+    }
+
+    /// Indexes of the samples that start in [start, end)
+    fn sample_range(&self, start: f64, end: f64) -> (usize, usize) {
+        let (start, end) = (self.to_track_time(start), self.to_track_time(end));
+        (
+            self.samples.partition_point(|s| s.time < start),
+            self.samples.partition_point(|s| s.time < end),
+        )
+    }
+}
+
+enum SampleData {
+    /// (offset, size) in the source file
+    Source(u64, u32),
+    Owned(Vec<u8>),
+}
+
+struct OutputSample {
+    track: usize,
+    data: SampleData,
+    /// Where the sample was in the source, to keep the source's interleaving
+    source_offset: u64,
+    duration: u32,
+    composition_offset: i32,
+    is_sync: bool,
+}
+
+impl OutputSample {
+    fn size(&self) -> u64 {
+        match &self.data {
+            SampleData::Source(_, size) => *size as u64,
+            SampleData::Owned(data) => data.len() as u64,
+        }
+    }
+}
+
+/// An MP4 opened for cutting
+pub struct RemuxSource {
+    path: PathBuf,
+    movie: Movie,
+    ftyp: Vec<u8>,
+    tracks: Vec<SourceTrack>,
+    moov_size: u64,
+}
+
+impl RemuxSource {
+    pub fn open(path: &Path) -> Result<RemuxSource, Error> {
+        let movie = Movie::read(path)?;
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut ftyp = Vec::new();
+        if let Some(ftyp_box) = read_top_level_boxes(&mut reader)?
+            .into_iter()
+            .find(|b| &b.typ == b"ftyp")
+        {
+            reader.seek(SeekFrom::Start(ftyp_box.offset))?;
+            ftyp = vec![0; ftyp_box.size as usize];
+            reader.read_exact(&mut ftyp)?;
+        }
+
+        let mut tracks = Vec::new();
+        for (box_index, trak) in movie.moov.children.iter().enumerate() {
+            if &trak.typ != b"trak" {
+                continue;
+            }
+            let track = Track { trak };
+            let stbl = trak.find(&[b"mdia", b"minf", b"stbl"]);
+            let samples = track.samples()?;
+            let size_prefix_sums = std::iter::once(0)
+                .chain(samples.iter().scan(0, |total, s| {
+                    *total += s.size as u64;
+                    Some(*total)
+                }))
+                .collect();
+            tracks.push(SourceTrack {
+                box_index,
+                timescale: track.media_time()?.0.max(1),
+                samples,
+                size_prefix_sums,
+                is_video: track.handler_type().as_ref() == Some(b"vide"),
+                sparse: matches!(&track.handler_type(), Some(h) if h == b"tmcd" || h == b"text"),
+                has_stss: stbl.is_some_and(|stbl| stbl.child(b"stss").is_some()),
+                ctts_version: stbl
+                    .and_then(|stbl| stbl.child(b"ctts"))
+                    .map(|ctts| ctts.payload.first().copied().unwrap_or(0)),
+            });
+        }
+
+        Ok(RemuxSource {
+            path: path.to_path_buf(),
+            moov_size: movie.moov.size(),
+            movie,
+            ftyp,
+            tracks,
+        })
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.movie.duration_secs()
+    }
+
+    /// Decode times of the video track's keyframes, in seconds. Ranges should start on one of these.
+    pub fn keyframe_times(&self) -> Result<Vec<f64>, Error> {
+        let track = self
+            .tracks
+            .iter()
+            .find(|t| t.is_video)
+            .ok_or_else(|| Error::other(format!("{} has no video", self.path.display())))?;
+        Ok(track
+            .samples
+            .iter()
+            .filter(|s| s.is_sync)
+            .map(|s| s.time as f64 / track.timescale as f64)
+            .collect())
+    }
+
+    /// The keyframe nearest to `time`
+    pub fn snap_to_keyframe(&self, time: f64) -> Result<f64, Error> {
+        Ok(self
+            .keyframe_times()?
+            .into_iter()
+            .min_by(|a, b| (a - time).abs().total_cmp(&(b - time).abs()))
+            .unwrap_or(0.0))
+    }
+
+    /// An upper bound on the size of the file `write_range` would write
+    pub fn estimated_size(&self, start: f64, end: f64) -> u64 {
+        let mut size = self.ftyp.len() as u64 + 16 + self.moov_size;
+        for track in &self.tracks {
+            let (first, last) = track.sample_range(start, end);
+            size += track.size_prefix_sums[last] - track.size_prefix_sums[first];
+            size += (last - first) as u64 * TABLE_BYTES_PER_SAMPLE;
+        }
+        size
+    }
+
+    fn select_samples(&self, track_index: usize, start: f64, end: f64) -> Vec<OutputSample> {
+        let track = &self.tracks[track_index];
+        let (mut first, last) = track.sample_range(start, end);
+        let (start_time, end_time) = (track.to_track_time(start), track.to_track_time(end));
+        // Keep a long sample that started before the range but is still running at its start
+        let covering = track.sparse
+            && first > 0
+            && track.samples[first - 1].time + track.samples[first - 1].duration as u64
+                > start_time;
+        if covering {
+            first -= 1;
+        }
+
+        track.samples[first..last]
+            .iter()
+            .map(|sample| {
+                let mut data = SampleData::Source(sample.offset, sample.size);
+                let mut duration = sample.duration;
+                if track.sparse {
+                    let sample_end = (sample.time + sample.duration as u64).min(end_time);
+                    duration = sample_end.saturating_sub(sample.time.max(start_time)) as u32;
+                    if sample.time < start_time {
+                        if let Some(timecode) = self.advance_timecode(track, sample, start_time) {
+                            data = SampleData::Owned(timecode);
+                        }
+                    }
+                }
+                OutputSample {
+                    track: track_index,
+                    data,
+                    source_offset: sample.offset,
+                    duration,
+                    composition_offset: sample.composition_offset,
+                    is_sync: sample.is_sync,
+                }
+            })
+            .collect()
+    }
+
+    /// A tmcd sample holds the frame number its timecode starts at. Returns a new one starting at
+    /// `start_time` instead.
+    fn advance_timecode(
+        &self,
+        track: &SourceTrack,
+        sample: &Sample,
+        start_time: u64,
+    ) -> Option<Vec<u8>> {
+        let trak = &self.movie.moov.children[track.box_index];
+        let description = Track { trak }.sample_description()?;
+        if description.get(4..8)? != b"tmcd" {
+            return None;
+        }
+        let timescale = u32::from_be_bytes(description.get(24..28)?.try_into().ok()?) as f64;
+        let frame_duration = u32::from_be_bytes(description.get(28..32)?.try_into().ok()?) as f64;
+        let mut file = File::open(&self.path).ok()?;
+        file.seek(SeekFrom::Start(sample.offset)).ok()?;
+        let mut frame = [0u8; 4];
+        file.read_exact(&mut frame).ok()?;
+
+        let elapsed = (start_time - sample.time) as f64 / track.timescale as f64;
+        let frames = (elapsed * timescale / frame_duration.max(1.0)).round() as u32;
+        Some(
+            u32::from_be_bytes(frame)
+                .wrapping_add(frames)
+                .to_be_bytes()
+                .to_vec(),
+        )
+    }
+
+    /// Writes the samples of every track that start in [start, end) to `output`. Pass
+    /// `f64::INFINITY` as `end` to go to the end of the file. Never overwrites `output`, and removes
+    /// it if writing fails partway.
+    pub fn write_range(&self, start: f64, end: f64, output: &Path) -> Result<(), Error> {
+        let mut samples: Vec<OutputSample> = (0..self.tracks.len())
+            .flat_map(|track_index| self.select_samples(track_index, start, end))
+            .collect();
+        samples.sort_by_key(|s| (s.source_offset, s.track));
+
+        let mut ftyp = self.ftyp.clone();
+        if ftyp.is_empty() {
+            Mp4Box::new(b"ftyp", b"isom\0\0\x02\0isomiso2mp41".to_vec()).write_to(&mut ftyp);
+        }
+        let data_size: u64 = samples.iter().map(|s| s.size()).sum();
+        let mut mdat_header = Vec::new();
+        if data_size + 8 > u32::MAX as u64 {
+            mdat_header.extend_from_slice(&1u32.to_be_bytes());
+            mdat_header.extend_from_slice(b"mdat");
+            mdat_header.extend_from_slice(&(data_size + 16).to_be_bytes());
+        } else {
+            mdat_header.extend_from_slice(&(data_size as u32 + 8).to_be_bytes());
+            mdat_header.extend_from_slice(b"mdat");
+        }
+
+        let data_start = (ftyp.len() + mdat_header.len()) as u64;
+        let moov = self.build_moov(&samples, data_start, start, end);
+
+        let mut out = BufWriter::new(create_new(output)?);
+        let written = self.write_file(&mut out, &ftyp, &mdat_header, &samples, moov);
+        if written.is_err() {
+            let _ = remove_file(output);
+        }
+        written
+    }
+
+    fn write_file(
+        &self,
+        out: &mut BufWriter<File>,
+        ftyp: &[u8],
+        mdat_header: &[u8],
+        samples: &[OutputSample],
+        moov: Mp4Box,
+    ) -> Result<(), Error> {
+        out.write_all(ftyp)?;
+        out.write_all(mdat_header)?;
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut position = 0;
+        let mut buffer = Vec::new();
+        for sample in samples {
+            match &sample.data {
+                SampleData::Source(offset, size) => {
+                    // Samples are mostly contiguous in the source, so this rarely seeks
+                    if *offset != position {
+                        reader.seek(SeekFrom::Start(*offset))?;
+                    }
+                    buffer.resize(*size as usize, 0);
+                    reader.read_exact(&mut buffer)?;
+                    out.write_all(&buffer)?;
+                    position = offset + *size as u64;
+                }
+                SampleData::Owned(data) => out.write_all(data)?,
+            }
+        }
+        let mut moov_bytes = Vec::new();
+        moov.write_to(&mut moov_bytes);
+        out.write_all(&moov_bytes)?;
+        out.flush()
+    }
+
+    fn build_moov(
+        &self,
+        samples: &[OutputSample],
+        data_start: u64,
+        start: f64,
+        end: f64,
+    ) -> Mp4Box {
+        let mut moov = self.movie.moov.clone();
+        let movie_timescale = self.movie.timescale.max(1) as u64;
+
+        let mut offset = data_start;
+        let mut tables: Vec<SampleTables> = vec![SampleTables::default(); self.tracks.len()];
+        let mut previous_track = None;
+        for sample in samples {
+            tables[sample.track].push(sample, offset, previous_track != Some(sample.track));
+            offset += sample.size();
+            previous_track = Some(sample.track);
+        }
+
+        let mut movie_duration = 0;
+        for (track, tables) in self.tracks.iter().zip(tables) {
+            let media_duration = tables.duration;
+            let duration = media_duration * movie_timescale / track.timescale as u64;
+            movie_duration = movie_duration.max(duration);
+            let trak = &mut moov.children[track.box_index];
+            set_duration(trak.child_mut(b"tkhd"), 20, 28, duration);
+            update_edit_list(trak, duration);
+            if let Some(mdia) = trak.child_mut(b"mdia") {
+                set_duration(mdia.child_mut(b"mdhd"), 16, 24, media_duration);
+                if let Some(stbl) = mdia
+                    .child_mut(b"minf")
+                    .and_then(|minf| minf.child_mut(b"stbl"))
+                {
+                    tables.replace_in(stbl, track.has_stss, track.ctts_version);
+                }
+            }
+        }
+        set_duration(moov.child_mut(b"mvhd"), 16, 24, movie_duration);
+
+        // Chapter times are absolute, so keep and shift the ones in range
+        if let Some(udta) = moov.child_mut(b"udta") {
+            if let Some(chpl) = udta.child_mut(b"chpl") {
+                *chpl = build_chpl(&markers_in_range(&read_chpl(chpl), start, end));
+            }
+        }
+        moov
+    }
+}
+
+/// The rebuilt sample tables of one track
+#[derive(Debug, Clone, Default)]
+struct SampleTables {
+    /// (count, delta)
+    durations: Vec<(u32, u32)>,
+    /// (count, offset)
+    composition_offsets: Vec<(u32, i32)>,
+    sizes: Vec<u32>,
+    /// 1-based
+    sync_samples: Vec<u32>,
+    chunk_offsets: Vec<u64>,
+    /// (first chunk, samples per chunk)
+    chunk_runs: Vec<(u32, u32)>,
+    samples_in_last_chunk: u32,
+    duration: u64,
+}
+
+impl SampleTables {
+    fn push(&mut self, sample: &OutputSample, offset: u64, new_chunk: bool) {
+        if new_chunk {
+            self.close_chunk();
+            self.chunk_offsets.push(offset);
+        }
+        self.samples_in_last_chunk += 1;
+        self.sizes.push(sample.size() as u32);
+        if sample.is_sync {
+            self.sync_samples.push(self.sizes.len() as u32);
+        }
+        match self.durations.last_mut() {
+            Some((count, delta)) if *delta == sample.duration => *count += 1,
+            _ => self.durations.push((1, sample.duration)),
+        }
+        match self.composition_offsets.last_mut() {
+            Some((count, offset)) if *offset == sample.composition_offset => *count += 1,
+            _ => self
+                .composition_offsets
+                .push((1, sample.composition_offset)),
+        }
+        self.duration += sample.duration as u64;
+    }
+
+    fn close_chunk(&mut self) {
+        if self.samples_in_last_chunk == 0 {
+            return;
+        }
+        let chunk = self.chunk_offsets.len() as u32;
+        if self.chunk_runs.last().map(|run| run.1) != Some(self.samples_in_last_chunk) {
+            self.chunk_runs.push((chunk, self.samples_in_last_chunk));
+        }
+        self.samples_in_last_chunk = 0;
+    }
+
+    /// Swaps the per-sample boxes of stbl for the rebuilt ones. Boxes that describe individual
+    /// samples but aren't rebuilt (sdtp, sample groups) are dropped.
+    fn replace_in(mut self, stbl: &mut Mp4Box, has_stss: bool, ctts_version: Option<u8>) {
+        self.close_chunk();
+        let stsd = stbl.child(b"stsd").cloned();
+        stbl.children.clear();
+        stbl.children.extend(stsd);
+
+        let entries = |count: usize| (count as u32).to_be_bytes().to_vec();
+        let mut stts = entries(self.durations.len());
+        for (count, delta) in &self.durations {
+            stts.extend_from_slice(&count.to_be_bytes());
+            stts.extend_from_slice(&delta.to_be_bytes());
+        }
+        stbl.children.push(full_box(b"stts", 0, &stts));
+
+        if let Some(version) = ctts_version {
+            let mut ctts = entries(self.composition_offsets.len());
+            for (count, offset) in &self.composition_offsets {
+                ctts.extend_from_slice(&count.to_be_bytes());
+                ctts.extend_from_slice(&offset.to_be_bytes());
+            }
+            stbl.children.push(full_box(b"ctts", version, &ctts));
+        }
+
+        if has_stss {
+            let mut stss = entries(self.sync_samples.len());
+            stss.extend(self.sync_samples.iter().flat_map(|s| s.to_be_bytes()));
+            stbl.children.push(full_box(b"stss", 0, &stss));
+        }
+
+        let mut stsc = entries(self.chunk_runs.len());
+        for (first_chunk, samples_per_chunk) in &self.chunk_runs {
+            stsc.extend_from_slice(&first_chunk.to_be_bytes());
+            stsc.extend_from_slice(&samples_per_chunk.to_be_bytes());
+            stsc.extend_from_slice(&1u32.to_be_bytes());
+        }
+        stbl.children.push(full_box(b"stsc", 0, &stsc));
+
+        let mut stsz = 0u32.to_be_bytes().to_vec();
+        stsz.extend(entries(self.sizes.len()));
+        stsz.extend(self.sizes.iter().flat_map(|s| s.to_be_bytes()));
+        stbl.children.push(full_box(b"stsz", 0, &stsz));
+
+        let mut chunk_offsets = entries(self.chunk_offsets.len());
+        if self
+            .chunk_offsets
+            .last()
+            .is_some_and(|&o| o > u32::MAX as u64)
+        {
+            chunk_offsets.extend(self.chunk_offsets.iter().flat_map(|o| o.to_be_bytes()));
+            stbl.children.push(full_box(b"co64", 0, &chunk_offsets));
+        } else {
+            chunk_offsets.extend(
+                self.chunk_offsets
+                    .iter()
+                    .flat_map(|&o| (o as u32).to_be_bytes()),
+            );
+            stbl.children.push(full_box(b"stco", 0, &chunk_offsets));
+        }
+    }
+}
+
+/// A full box with no flags set
+fn full_box(typ: &FourCC, version: u8, body: &[u8]) -> Mp4Box {
+    let mut payload = vec![version, 0, 0, 0];
+    payload.extend_from_slice(body);
+    Mp4Box::new(typ, payload)
+}
+
+/// Sets the duration field of a full box (mvhd, tkhd, mdhd), which is at a different offset (and
+/// size) in version 0 and version 1
+fn set_duration(full_box: Option<&mut Mp4Box>, v0_offset: usize, v1_offset: usize, duration: u64) {
+    let payload = match full_box {
+        Some(full_box) => &mut full_box.payload,
+        None => return,
+    };
+    match payload.first() {
+        Some(1) if payload.len() >= v1_offset + 8 => {
+            payload[v1_offset..v1_offset + 8].copy_from_slice(&duration.to_be_bytes())
+        }
+        Some(0) if payload.len() >= v0_offset + 4 => payload[v0_offset..v0_offset + 4]
+            .copy_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes()),
+        _ => (),
+    }
+}
+
+/// Keeps a single-entry edit list (e.g. skipping audio priming), with its duration updated. Longer
+/// edit lists can't be carried over to part of the track, so they're dropped.
+fn update_edit_list(trak: &mut Mp4Box, duration: u64) {
+    let single_entry = trak
+        .find(&[b"edts", b"elst"])
+        .and_then(|elst| elst.payload.get(4..8))
+        .is_some_and(|count| count == 1u32.to_be_bytes());
+    if !single_entry || duration == 0 {
+        trak.children.retain(|c| &c.typ != b"edts");
+        return;
+    }
+    let elst = trak
+        .child_mut(b"edts")
+        .and_then(|edts| edts.child_mut(b"elst"));
+    // The segment duration follows the entry count, sized like the version's durations
+    set_duration(elst, 8, 8, duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::read_sample;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+
+    #[test]
+    fn write_range_copies_the_samples_in_range() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("in.mp4"), dir.join("out.mp4"));
+        write_mp4(&input, &[FixtureTrack::video(90, 30)], Vec::new());
+
+        let source = RemuxSource::open(&input).unwrap();
+        assert_eq!(source.duration(), 3.0);
+        let start = source.snap_to_keyframe(1.5).unwrap();
+        assert_eq!(start, 1.0);
+        source.write_range(start, 2.5, &output).unwrap();
+
+        let movie = Movie::read(&output).unwrap();
+        let video = movie.video_track().unwrap();
+        let samples = video.samples().unwrap();
+        assert_eq!(samples.len(), 45);
+        assert_eq!(samples[0].time, 0);
+        assert!(samples[0].is_sync && !samples[1].is_sync);
+        // Chunk offsets point at the copied data, not where it was in the source
+        let mut reader = File::open(&output).unwrap();
+        for (index, sample) in samples.iter().enumerate() {
+            assert_eq!(
+                read_sample(&mut reader, sample).unwrap(),
+                [30 + index as u8; 100]
+            );
+        }
+        assert_eq!(movie.header().duration_secs(), 1.5);
+    }
+
+    #[test]
+    fn write_range_never_overwrites_the_output() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("in.mp4"), dir.join("out.mp4"));
+        write_mp4(&input, &[FixtureTrack::video(30, 30)], Vec::new());
+        std::fs::write(&output, b"another video").unwrap();
+
+        let written = RemuxSource::open(&input)
+            .unwrap()
+            .write_range(0.0, f64::INFINITY, &output);
+        assert_eq!(
+            written.unwrap_err().kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), b"another video");
+    }
+
+    #[test]
+    fn write_range_keeps_the_ctts_version() {
+        let dir = TempDir::new();
+        let (input, output) = (dir.join("in.mp4"), dir.join("out.mp4"));
+        let mut video = FixtureTrack::video(4, 2);
+        video.composition_offsets = Some((1, vec![0, 2000, -1000, -1000]));
+        write_mp4(&input, &[video], Vec::new());
+
+        RemuxSource::open(&input)
+            .unwrap()
+            .write_range(0.0, f64::INFINITY, &output)
+            .unwrap();
+
+        let movie = Movie::read(&output).unwrap();
+        let video = movie.video_track().unwrap();
+        let ctts = video
+            .trak
+            .find(&[b"mdia", b"minf", b"stbl", b"ctts"])
+            .unwrap();
+        assert_eq!(ctts.payload[0], 1);
+        let offsets: Vec<_> = video
+            .samples()
+            .unwrap()
+            .iter()
+            .map(|s| s.composition_offset)
+            .collect();
+        assert_eq!(offsets, [0, 2000, -1000, -1000]);
+    }
+}
@@ -0,0 +1,210 @@
+// Splits videos into standalone parts at keyframes, e.g. to fit them on a FAT32 drive, which can't
+// hold files of 4GB or more. Every part keeps all of the tracks, including the GPMF telemetry.
+
+use std::fs::remove_file;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{info, warn};
+
+use crate::remux::RemuxSource;
+
+/// Picks part boundaries greedily: each part runs to the last keyframe that keeps it under the
+/// limits. The last part runs to `f64::INFINITY`, i.e. the end of the file.
+fn plan_parts(
+    source: &RemuxSource,
+    max_size: Option<u64>,
+    max_duration: Option<f64>,
+) -> Result<Vec<(f64, f64)>, Error> {
+    let duration = source.duration();
+    let fits = |start: f64, end: f64| {
+        max_size.is_none_or(|max| source.estimated_size(start, end) <= max)
+            && max_duration.is_none_or(|max| end.min(duration) - start <= max + 0.001)
+    };
+    let too_big = |start: f64| {
+        Error::other(format!(
+            "the keyframes after {:.3}s are too far apart to make a part that small",
+            start
+        ))
+    };
+
+    let mut parts = Vec::new();
+    let mut start = 0.0;
+    let mut last_fit = None;
+    let candidates = source.keyframe_times()?.into_iter().filter(|&t| t > 0.0);
+    for end in candidates.chain([f64::INFINITY]) {
+        if fits(start, end) {
+            last_fit = Some(end);
+            continue;
+        }
+        let cut = last_fit.ok_or_else(|| too_big(start))?;
+        parts.push((start, cut));
+        start = cut;
+        if !fits(start, end) {
+            return Err(too_big(start));
+        }
+        last_fit = Some(end);
+    }
+    parts.push((start, f64::INFINITY));
+    Ok(parts)
+}
+
+/// e.g. GoPro_1.mp4 -> GoPro_1_part2.mp4
+fn gen_part_path(output_dir: &Path, input: &Path, part: usize) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = input
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "mp4".to_string());
+    output_dir.join(format!("{}_part{}.{}", stem, part, extension))
+}
+
+/// Splits one video. Returns the parts written, which is none if the video is already within the
+/// limits. Fails without writing anything if any of the parts already exists.
+pub fn split_video(
+    input: &Path,
+    output_dir: &Path,
+    max_size: Option<u64>,
+    max_duration: Option<f64>,
+) -> Result<Vec<PathBuf>, Error> {
+    let source = RemuxSource::open(input)?;
+    let parts = plan_parts(&source, max_size, max_duration)?;
+    if parts.len() == 1 {
+        info!(
+            "{} is already within the limits, not splitting it",
+            input.to_string_lossy().blue().bold()
+        );
+        return Ok(Vec::new());
+    }
+
+    info!(
+        "Splitting {} into {} parts",
+        input.to_string_lossy().blue().bold(),
+        parts.len()
+    );
+    let part_paths: Vec<_> = (1..=parts.len())
+        .map(|part| gen_part_path(output_dir, input, part))
+        .collect();
+    // Checked up front, so a rerun doesn't leave a mix of old and new parts
+    if let Some(existing) = part_paths.iter().find(|path| path.exists()) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", existing.display()),
+        ));
+    }
+    let mut written = Vec::new();
+    for ((start, end), part_path) in parts.into_iter().zip(part_paths) {
+        if let Err(e) = source.write_range(start, end, &part_path) {
+            // A partial set of parts is no use, and would get in the way of the next attempt
+            for part in &written {
+                let _ = remove_file(part);
+            }
+            return Err(e);
+        }
+        info!(
+            "Wrote {:.3}s to {:.3}s to {}",
+            start,
+            end.min(source.duration()),
+            part_path.to_string_lossy().green().bold()
+        );
+        written.push(part_path);
+    }
+    Ok(written)
+}
+
+/// Splits every video, writing parts to `output_dir` or next to each video. Returns false if any
+/// video couldn't be split.
+pub fn split_videos(
+    inputs: &[PathBuf],
+    output_dir: Option<&Path>,
+    max_size: Option<u64>,
+    max_duration: Option<f64>,
+) -> bool {
+    let mut all_split = true;
+    for input in inputs {
+        let output_dir = match output_dir {
+            Some(output_dir) => output_dir,
+            None => input.parent().unwrap_or(Path::new(".")),
+        };
+        if let Err(e) = split_video(input, output_dir, max_size, max_duration) {
+            warn!("Could not split {}: {}", input.display(), e);
+            all_split = false;
+        }
+    }
+    all_split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::{read_sample, Movie};
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+    use std::fs::File;
+
+    #[test]
+    fn parts_end_on_the_last_keyframe_that_fits() {
+        let dir = TempDir::new();
+        let input = dir.join("GoPro_1.mp4");
+        write_mp4(&input, &[FixtureTrack::video(150, 30)], Vec::new());
+        let source = RemuxSource::open(&input).unwrap();
+
+        assert_eq!(
+            plan_parts(&source, None, Some(2.5)).unwrap(),
+            [(0.0, 2.0), (2.0, 4.0), (4.0, f64::INFINITY)]
+        );
+        assert_eq!(
+            plan_parts(&source, None, Some(5.0)).unwrap(),
+            [(0.0, f64::INFINITY)]
+        );
+        assert!(plan_parts(&source, None, Some(0.5)).is_err());
+        assert!(plan_parts(&source, Some(1000), None).is_err());
+    }
+
+    #[test]
+    fn parts_hold_every_frame_once() {
+        let dir = TempDir::new();
+        let input = dir.join("GoPro_1.mp4");
+        write_mp4(&input, &[FixtureTrack::video(90, 30)], Vec::new());
+
+        let parts = split_video(&input, dir.path(), None, Some(1.0)).unwrap();
+        assert_eq!(
+            parts,
+            (1..=3)
+                .map(|part| dir.join(&format!("GoPro_1_part{}.mp4", part)))
+                .collect::<Vec<_>>()
+        );
+        let mut frames = Vec::new();
+        for part in &parts {
+            let movie = Movie::read(part).unwrap();
+            assert_eq!(movie.duration_secs(), 1.0);
+            let samples = movie.video_track().unwrap().samples().unwrap();
+            assert!(samples[0].is_sync);
+            let mut reader = File::open(part).unwrap();
+            for sample in &samples {
+                frames.push(read_sample(&mut reader, sample).unwrap()[0]);
+            }
+        }
+        assert_eq!(frames, (0..90).collect::<Vec<u8>>());
+
+        assert!(split_video(&input, dir.path(), None, Some(3.0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn existing_parts_are_left_alone() {
+        let dir = TempDir::new();
+        let input = dir.join("GoPro_1.mp4");
+        write_mp4(&input, &[FixtureTrack::video(90, 30)], Vec::new());
+        std::fs::write(dir.join("GoPro_1_part3.mp4"), b"an earlier split").unwrap();
+
+        let split = split_video(&input, dir.path(), None, Some(1.0));
+        assert_eq!(split.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(!dir.join("GoPro_1_part1.mp4").exists());
+        assert_eq!(
+            std::fs::read(dir.join("GoPro_1_part3.mp4")).unwrap(),
+            b"an earlier split"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// The outcome of every video in a run. Videos are processed independently, so one that fails is
+// recorded here and the run moves on to the next. At the end, this becomes the summary table and
+// the exit code.
+
+use std::collections::HashSet;
+
+use colored::Colorize;
+
+use crate::gopro::VideoId;
+
+/// Some videos were assembled, but others failed
+pub const EXIT_PARTIAL_FAILURE: i32 = 3;
+/// Every video that was attempted failed
+pub const EXIT_NOTHING_DONE: i32 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Succeeded,
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct VideoOutcome {
+    video: VideoId,
+    /// e.g. "merge" or "copy"
+    action: &'static str,
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunSummary {
+    outcomes: Vec<VideoOutcome>,
+    /// Failures that aren't any one video's, like an input directory that couldn't be read
+    errors: Vec<String>,
+}
+
+impl RunSummary {
+    pub fn record(&mut self, video: VideoId, action: &'static str, outcome: Outcome) {
+        self.outcomes.push(VideoOutcome {
+            video,
+            action,
+            outcome,
+        });
+    }
+
+    pub fn record_error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    pub fn succeeded_videos(&self) -> HashSet<VideoId> {
+        self.outcomes
+            .iter()
+            .filter(|o| o.outcome == Outcome::Succeeded)
+            .map(|o| o.video.clone())
+            .collect()
+    }
+
+    pub fn succeeded_videos_with_action(&self, action: &str) -> HashSet<VideoId> {
+        self.outcomes
+            .iter()
+            .filter(|o| o.outcome == Outcome::Succeeded && o.action == action)
+            .map(|o| o.video.clone())
+            .collect()
+    }
+
+    /// (succeeded, skipped, failed)
+    pub fn counts(&self) -> (usize, usize, usize) {
+        self.outcomes
+            .iter()
+            .fold((0, 0, 0), |(ok, skipped, failed), o| match o.outcome {
+                Outcome::Succeeded => (ok + 1, skipped, failed),
+                Outcome::Skipped(_) => (ok, skipped + 1, failed),
+                Outcome::Failed(_) => (ok, skipped, failed + 1),
+            })
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match (self.counts(), self.errors.is_empty()) {
+            ((_, _, 0), true) => 0,
+            ((0, _, failed), _) if failed > 0 => EXIT_NOTHING_DONE,
+            _ => EXIT_PARTIAL_FAILURE,
+        }
+    }
+
+    /// One row per video, in order
+    pub fn table(&self) -> Vec<String> {
+        let mut outcomes = self.outcomes.clone();
+        outcomes.sort_by(|a, b| a.video.cmp(&b.video));
+        outcomes
+            .iter()
+            .map(|o| {
+                // Padded before colouring, since the escape codes would count towards the width
+                let (status, reason) = match &o.outcome {
+                    Outcome::Succeeded => (format!("{:<7}", "ok").green().bold(), ""),
+                    Outcome::Skipped(reason) => {
+                        (format!("{:<7}", "skipped").yellow().bold(), reason.as_str())
+                    }
+                    Outcome::Failed(reason) => {
+                        (format!("{:<7}", "failed").red().bold(), reason.as_str())
+                    }
+                };
+                format!(
+                    "{:<12} {:<8} {} {}",
+                    o.video.to_string(),
+                    o.action,
+                    status,
+                    reason
+                )
+                .trim_end()
+                .to_string()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(video_number: u16) -> VideoId {
+        VideoId {
+            video_number,
+            camera: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn exit_code_reflects_failures() {
+        let mut summary = RunSummary::default();
+        summary.record(video(1), "merge", Outcome::Skipped("deselected".into()));
+        assert_eq!(summary.exit_code(), 0);
+        summary.record(video(2), "merge", Outcome::Failed("bad chapter".into()));
+        assert_eq!(summary.exit_code(), EXIT_NOTHING_DONE);
+        summary.record(video(3), "copy", Outcome::Succeeded);
+        assert_eq!(summary.exit_code(), EXIT_PARTIAL_FAILURE);
+    }
+
+    #[test]
+    fn errors_outside_a_video_are_a_partial_failure() {
+        let mut summary = RunSummary::default();
+        summary.record(video(1), "merge", Outcome::Succeeded);
+        summary.record_error("Could not read /media/card: Permission denied".into());
+        assert_eq!(summary.exit_code(), EXIT_PARTIAL_FAILURE);
+    }
+}
@@ -0,0 +1,100 @@
+// Reads the GPMF telemetry track out of every chapter of a video and lays it out on the assembled
+// video's timeline, so chapter 2's first payload starts where chapter 1 ended.
+
+use std::fs::File;
+use std::io::{BufReader, Error};
+use std::path::Path;
+
+use crate::gopro::GoProChapteredVideoFile;
+use crate::gpmf::{self, KlvEntry, Stream};
+use crate::mp4::{read_sample, FourCC, Movie};
+
+/// One sample of the `gpmd` track, usually about a second of telemetry
+#[derive(Debug, Clone)]
+pub struct Payload {
+    /// Seconds from the start of the assembled video
+    pub time: f64,
+    pub duration: f64,
+    pub entries: Vec<KlvEntry>,
+}
+
+impl Payload {
+    /// Sensor samples aren't individually timestamped, so they're spread evenly across the payload
+    pub fn sample_time(&self, index: usize, count: usize) -> f64 {
+        if count == 0 {
+            return self.time;
+        }
+        self.time + self.duration * index as f64 / count as f64
+    }
+}
+
+pub fn read_payloads(chapters: &[GoProChapteredVideoFile]) -> Result<Vec<Payload>, Error> {
+    let paths: Vec<&Path> = chapters.iter().map(|c| c.abs_path.as_path()).collect();
+    read_payloads_from_files(&paths)
+}
+
+/// Reads payloads from a list of files that play back to back, e.g. the chapters of a video
+pub fn read_payloads_from_files(files: &[&Path]) -> Result<Vec<Payload>, Error> {
+    let mut payloads = Vec::new();
+    let mut file_start = 0.0;
+    for &file in files {
+        let movie = Movie::read(file)?;
+        if let Some(track) = movie.track_with_format(b"gpmd") {
+            let (timescale, _duration) = track.media_time()?;
+            let timescale = timescale.max(1) as f64;
+            let mut reader = BufReader::new(File::open(file)?);
+            for sample in track.samples()? {
+                let data = read_sample(&mut reader, &sample)?;
+                payloads.push(Payload {
+                    time: file_start + sample.time as f64 / timescale,
+                    duration: sample.duration as f64 / timescale,
+                    entries: gpmf::parse(&data)?,
+                });
+            }
+        }
+        file_start += movie.duration_secs();
+    }
+    Ok(payloads)
+}
+
+/// Searches every DEVC and STRM of a payload for an entry, e.g. DVNM or VFOV
+pub fn find_entry<'a>(entries: &'a [KlvEntry], key: &FourCC) -> Option<&'a KlvEntry> {
+    entries.iter().find_map(|entry| match &entry.key == key {
+        true => Some(entry),
+        false => find_entry(&entry.children, key),
+    })
+}
+
+/// Every sample of one sensor stream across all payloads, with SCAL applied
+#[derive(Debug, Clone, Default)]
+pub struct StreamSamples {
+    pub name: Option<String>,
+    /// Axis order for IMU streams, e.g. "ZXY"
+    pub orientation: Option<String>,
+    pub units: Vec<String>,
+    /// (seconds from the start of the assembled video, values)
+    pub samples: Vec<(f64, Vec<f64>)>,
+}
+
+pub fn collect_stream(payloads: &[Payload], key: &FourCC) -> StreamSamples {
+    let mut collected = StreamSamples::default();
+    for payload in payloads {
+        let stream = match Stream::find(&payload.entries, key) {
+            Some(stream) => stream,
+            None => continue,
+        };
+        if collected.name.is_none() {
+            collected.name = stream.metadata(b"STNM").map(|name| name.as_string());
+            collected.orientation = stream.metadata(b"ORIN").map(|orin| orin.as_string());
+            collected.units = stream.units();
+        }
+        let rows = stream.scaled_values();
+        let count = rows.len();
+        for (index, row) in rows.into_iter().enumerate() {
+            collected
+                .samples
+                .push((payload.sample_time(index, count), row));
+        }
+    }
+    collected
+}
@@ -0,0 +1,282 @@
+// Small MP4 files for the unit tests. The sample videos in tests/data are stored with Git LFS, so
+// tests that need an MP4 write their own: an ftyp, an mdat with made up sample data, and a moov with
+// real sample tables pointing into it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::mp4::FourCC;
+
+/// 2024-06-01 00:00:00, in seconds since 1904
+pub const CREATION_TIME: u64 = 3_800_044_800;
+
+/// mvhd's timescale
+pub const MOVIE_TIMESCALE: u32 = 1000;
+
+#[derive(Debug, Clone)]
+pub struct FixtureTrack {
+    pub handler: FourCC,
+    pub format: FourCC,
+    pub timescale: u32,
+    pub sample_duration: u32,
+    pub samples: Vec<Vec<u8>>,
+    /// Every nth sample is a keyframe. None leaves out stss, making every sample one.
+    pub keyframe_interval: Option<usize>,
+    /// The ctts version, and each sample's composition offset
+    pub composition_offsets: Option<(u8, Vec<i32>)>,
+}
+
+impl FixtureTrack {
+    /// 30 fps 1920x1080 video, with a keyframe every `keyframe_interval` frames. Each frame is 100
+    /// bytes of its own frame number, so frames can be told apart in the output.
+    pub fn video(frames: usize, keyframe_interval: usize) -> FixtureTrack {
+        FixtureTrack {
+            handler: *b"vide",
+            format: *b"avc1",
+            timescale: 30_000,
+            sample_duration: 1000,
+            samples: (0..frames).map(|frame| vec![frame as u8; 100]).collect(),
+            keyframe_interval: Some(keyframe_interval),
+            composition_offsets: None,
+        }
+    }
+
+    /// 30 fps non-drop frame timecode, starting at `start_frame`, for a video of `frames` frames
+    pub fn timecode(start_frame: u32, frames: u32) -> FixtureTrack {
+        FixtureTrack {
+            handler: *b"tmcd",
+            format: *b"tmcd",
+            timescale: 30_000,
+            sample_duration: frames * 1000,
+            samples: vec![start_frame.to_be_bytes().to_vec()],
+            keyframe_interval: None,
+            composition_offsets: None,
+        }
+    }
+
+    /// GPMF telemetry, one payload a second
+    pub fn telemetry(payloads: Vec<Vec<u8>>) -> FixtureTrack {
+        FixtureTrack {
+            handler: *b"meta",
+            format: *b"gpmd",
+            timescale: 1000,
+            sample_duration: 1000,
+            samples: payloads,
+            keyframe_interval: None,
+            composition_offsets: None,
+        }
+    }
+
+    fn duration(&self) -> u64 {
+        self.samples.len() as u64 * self.sample_duration as u64
+    }
+}
+
+/// A box holding `payload`. Written out by hand rather than with `mp4::Mp4Box`, so the fixtures
+/// don't depend on the writer they test.
+pub fn mp4_box(typ: &FourCC, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(typ);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+fn container(typ: &FourCC, children: Vec<Vec<u8>>) -> Vec<u8> {
+    mp4_box(typ, &children.concat())
+}
+
+fn full_box(typ: &FourCC, version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut payload = vec![version];
+    payload.extend_from_slice(&flags.to_be_bytes()[1..]);
+    payload.extend_from_slice(body);
+    mp4_box(typ, &payload)
+}
+
+fn be_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+const IDENTITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+fn movie_duration(track: &FixtureTrack) -> u32 {
+    (track.duration() * MOVIE_TIMESCALE as u64 / track.timescale as u64) as u32
+}
+
+/// A sample description entry, laid out like a visual one: width and height at 32..36. For
+/// timecode, the flags are at 20..24, the timescale and frame duration at 24..32 and the frames per
+/// second at 32.
+fn sample_entry(track: &FixtureTrack) -> Vec<u8> {
+    let mut entry = vec![0; 86];
+    entry[0..4].copy_from_slice(&86u32.to_be_bytes());
+    entry[4..8].copy_from_slice(&track.format);
+    entry[15] = 1; // data reference index
+    match &track.handler {
+        b"tmcd" => {
+            entry[24..28].copy_from_slice(&30_000u32.to_be_bytes());
+            entry[28..32].copy_from_slice(&1000u32.to_be_bytes());
+            entry[32] = 30;
+        }
+        _ => {
+            entry[32..34].copy_from_slice(&1920u16.to_be_bytes());
+            entry[34..36].copy_from_slice(&1080u16.to_be_bytes());
+        }
+    }
+    entry
+}
+
+fn build_trak(track_id: u32, track: &FixtureTrack, chunk_offsets: &[u32]) -> Vec<u8> {
+    let mut tkhd = be_bytes(&[0, 0, track_id, 0, movie_duration(track), 0, 0, 0, 0]);
+    tkhd.extend_from_slice(&be_bytes(&IDENTITY_MATRIX));
+    tkhd.extend_from_slice(&be_bytes(&[1920 << 16, 1080 << 16]));
+
+    let mut mdhd = be_bytes(&[0, 0, track.timescale, track.duration() as u32]);
+    mdhd.extend_from_slice(&[0x55, 0xc4, 0, 0]);
+
+    let mut hdlr = be_bytes(&[0]);
+    hdlr.extend_from_slice(&track.handler);
+    hdlr.extend_from_slice(&[0; 13]);
+
+    let mut stsd = be_bytes(&[1]);
+    stsd.extend_from_slice(&sample_entry(track));
+
+    let sample_count = track.samples.len() as u32;
+    let mut stbl = vec![
+        full_box(b"stsd", 0, 0, &stsd),
+        full_box(
+            b"stts",
+            0,
+            0,
+            &be_bytes(&[1, sample_count, track.sample_duration]),
+        ),
+    ];
+    if let Some((version, offsets)) = &track.composition_offsets {
+        let mut ctts = be_bytes(&[offsets.len() as u32]);
+        for offset in offsets {
+            ctts.extend_from_slice(&be_bytes(&[1, *offset as u32]));
+        }
+        stbl.push(full_box(b"ctts", *version, 0, &ctts));
+    }
+    if let Some(interval) = track.keyframe_interval {
+        let keyframes: Vec<u32> = (1..=sample_count).step_by(interval).collect();
+        let mut stss = be_bytes(&[keyframes.len() as u32]);
+        stss.extend_from_slice(&be_bytes(&keyframes));
+        stbl.push(full_box(b"stss", 0, 0, &stss));
+    }
+    stbl.push(full_box(b"stsc", 0, 0, &be_bytes(&[1, 1, 1, 1])));
+    let mut stsz = be_bytes(&[0, sample_count]);
+    stsz.extend(
+        track
+            .samples
+            .iter()
+            .flat_map(|s| (s.len() as u32).to_be_bytes()),
+    );
+    stbl.push(full_box(b"stsz", 0, 0, &stsz));
+    let mut stco = be_bytes(&[chunk_offsets.len() as u32]);
+    stco.extend_from_slice(&be_bytes(chunk_offsets));
+    stbl.push(full_box(b"stco", 0, 0, &stco));
+
+    container(
+        b"trak",
+        vec![
+            full_box(b"tkhd", 0, 3, &tkhd),
+            container(
+                b"mdia",
+                vec![
+                    full_box(b"mdhd", 0, 0, &mdhd),
+                    full_box(b"hdlr", 0, 0, &hdlr),
+                    container(b"minf", vec![container(b"stbl", stbl)]),
+                ],
+            ),
+        ],
+    )
+}
+
+/// Writes an MP4 with `tracks`, each sample in a chunk of its own, followed by `udta` if given
+pub fn write_mp4(path: &Path, tracks: &[FixtureTrack], udta: Vec<Vec<u8>>) {
+    let mut file = Vec::new();
+    let mut ftyp = b"isom".to_vec();
+    ftyp.extend_from_slice(&be_bytes(&[0x200]));
+    ftyp.extend_from_slice(b"isommp41");
+    file.extend(mp4_box(b"ftyp", &ftyp));
+
+    let mdat_payload: Vec<u8> = tracks.iter().flat_map(|t| t.samples.concat()).collect();
+    let mut offset = file.len() as u32 + 8;
+    file.extend(mp4_box(b"mdat", &mdat_payload));
+
+    let mut mvhd = be_bytes(&[
+        CREATION_TIME as u32,
+        CREATION_TIME as u32,
+        MOVIE_TIMESCALE,
+        tracks.iter().map(movie_duration).max().unwrap_or(0),
+        0x10000,
+    ]);
+    mvhd.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    mvhd.extend_from_slice(&be_bytes(&IDENTITY_MATRIX));
+    mvhd.extend_from_slice(&[0; 24]);
+    mvhd.extend_from_slice(&be_bytes(&[tracks.len() as u32 + 1]));
+    let mut moov = vec![full_box(b"mvhd", 0, 0, &mvhd)];
+    for (index, track) in tracks.iter().enumerate() {
+        let chunk_offsets: Vec<u32> = track
+            .samples
+            .iter()
+            .map(|sample| {
+                let chunk_offset = offset;
+                offset += sample.len() as u32;
+                chunk_offset
+            })
+            .collect();
+        moov.push(build_trak(index as u32 + 1, track, &chunk_offsets));
+    }
+    if !udta.is_empty() {
+        moov.push(container(b"udta", udta));
+    }
+    file.extend(container(b"moov", moov));
+    fs::write(path, file).unwrap();
+}
+
+/// A GPMF entry holding `data`, `struct_size` bytes per sample. Type 0 nests other entries, whose
+/// bytes are their data.
+pub fn klv(key: &FourCC, type_char: u8, struct_size: u8, data: &[u8]) -> Vec<u8> {
+    let mut entry = key.to_vec();
+    entry.push(type_char);
+    entry.push(struct_size);
+    entry.extend_from_slice(&((data.len() / struct_size as usize) as u16).to_be_bytes());
+    entry.extend_from_slice(data);
+    entry.resize(8 + data.len().div_ceil(4) * 4, 0);
+    entry
+}
+
+/// A GPMF entry of big-endian i32s ('l'), `per_sample` to a sample
+pub fn klv_i32(key: &FourCC, per_sample: u8, values: &[i32]) -> Vec<u8> {
+    let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+    klv(key, b'l', 4 * per_sample, &data)
+}
+
+/// A directory of its own under the system temp directory, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-{}",
+            env!("CARGO_PKG_NAME"),
+            uuid::Uuid::new_v4()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,499 @@
+// Writes an editing timeline for each video instead of merging it: the chapters are referenced in
+// place, back to back, so an NLE sees one continuous clip without any footage being duplicated.
+//
+// Everything is measured in frames of the first chapter's video track. Source timecodes come from
+// the `tmcd` track GoPro writes, so clips line up with what the NLE shows when importing the
+// chapters directly. Without one, the chapters are assumed to start at 00:00:00:00.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{info, warn};
+use serde_json::json;
+
+use crate::cli::TimelineFormat;
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::mp4::{read_sample, Movie};
+use crate::summary::{Outcome, RunSummary};
+
+#[derive(Debug, Clone)]
+struct TimelineClip {
+    path: PathBuf,
+    name: String,
+    /// Timecode of the clip's first frame, as a frame count
+    start_frame: u64,
+    frames: u64,
+    has_audio: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Timeline {
+    name: String,
+    /// (numerator, denominator) of a frame's duration in seconds, e.g. (1001, 30000)
+    frame_duration: (u32, u32),
+    /// Frames per second as counted by timecode, e.g. 30 for 29.97
+    timecode_fps: u32,
+    drop_frame: bool,
+    width: u16,
+    height: u16,
+    clips: Vec<TimelineClip>,
+}
+
+impl Timeline {
+    fn frame_rate(&self) -> f64 {
+        self.frame_duration.1 as f64 / self.frame_duration.0 as f64
+    }
+
+    fn total_frames(&self) -> u64 {
+        self.clips.iter().map(|c| c.frames).sum()
+    }
+
+    /// A rational time in seconds, the way FCPXML wants it
+    fn fcpxml_time(&self, frames: u64) -> String {
+        match frames {
+            0 => "0s".to_string(),
+            _ => format!(
+                "{}/{}s",
+                frames * self.frame_duration.0 as u64,
+                self.frame_duration.1
+            ),
+        }
+    }
+
+    fn timecode(&self, frames: u64) -> String {
+        format_timecode(frames, self.timecode_fps, self.drop_frame)
+    }
+}
+
+/// Timecode (start frame, counting fps, drop frame) from the first sample of the `tmcd` track
+fn read_timecode(path: &Path, movie: &Movie) -> Result<Option<(u64, u32, bool)>, Error> {
+    let track = match movie.track_with_format(b"tmcd") {
+        Some(track) => track,
+        None => return Ok(None),
+    };
+    // After the sample entry header: reserved u32, flags u32, timescale, frame duration, fps
+    let description = track.sample_description().unwrap_or_default();
+    let (flags, fps) = match (description.get(20..24), description.get(32)) {
+        (Some(flags), Some(&fps)) => (u32::from_be_bytes(flags.try_into().unwrap()), fps),
+        _ => return Ok(None),
+    };
+    let sample = match track.samples()?.first() {
+        Some(sample) => *sample,
+        None => return Ok(None),
+    };
+    let data = read_sample(&mut BufReader::new(File::open(path)?), &sample)?;
+    let start_frame = match data.get(0..4) {
+        Some(frame) => u32::from_be_bytes(frame.try_into().unwrap()) as u64,
+        None => return Ok(None),
+    };
+    Ok(Some((start_frame, fps as u32, flags & 1 != 0)))
+}
+
+fn read_timeline(video: &VideoId, chapters: &[GoProChapteredVideoFile]) -> Result<Timeline, Error> {
+    let mut timeline: Option<Timeline> = None;
+    for chapter in chapters {
+        let movie = Movie::read(&chapter.abs_path)?;
+        let video_track = movie
+            .video_track()
+            .ok_or_else(|| Error::other(format!("{} has no video", chapter.abs_path.display())))?;
+        let timecode = read_timecode(&chapter.abs_path, &movie)?;
+        // The first chapter sets the frame rate and timecode format for the whole timeline
+        if timeline.is_none() {
+            let frame_duration = video_track.frame_duration().unwrap_or((1, 30));
+            let (width, height) = video_track.dimensions().unwrap_or_default();
+            timeline = Some(Timeline {
+                name: video.to_string(),
+                frame_duration,
+                timecode_fps: timecode
+                    .map(|(_, fps, _)| fps)
+                    .unwrap_or((frame_duration.1 as f64 / frame_duration.0 as f64).round() as u32)
+                    .max(1),
+                drop_frame: timecode.is_some_and(|(_, _, drop_frame)| drop_frame),
+                width,
+                height,
+                clips: Vec::new(),
+            });
+        }
+        let timeline = timeline.as_mut().unwrap();
+        timeline.clips.push(TimelineClip {
+            path: chapter.abs_path.clone(),
+            name: chapter
+                .abs_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            start_frame: timecode.map(|(frame, _, _)| frame).unwrap_or(0),
+            frames: video_track.samples()?.len() as u64,
+            has_audio: movie
+                .tracks()
+                .iter()
+                .any(|t| t.handler_type().as_ref() == Some(b"soun")),
+        });
+    }
+    timeline.ok_or_else(|| Error::other("No chapters"))
+}
+
+/// HH:MM:SS:FF, or HH:MM:SS;FF for drop frame, where frame numbers 0 and 1 (0 to 3 at 60fps) are
+/// skipped at the start of every minute that isn't a multiple of ten
+fn format_timecode(frames: u64, fps: u32, drop_frame: bool) -> String {
+    let fps = fps.max(1) as u64;
+    let mut frames = frames;
+    let mut separator = ':';
+    if drop_frame && fps.is_multiple_of(30) {
+        let dropped = fps / 15;
+        let frames_per_minute = fps * 60 - dropped;
+        let frames_per_ten_minutes = fps * 600 - dropped * 9;
+        let tens = frames / frames_per_ten_minutes;
+        let rest = frames % frames_per_ten_minutes;
+        frames += dropped * 9 * tens;
+        if rest > dropped {
+            frames += dropped * ((rest - dropped) / frames_per_minute);
+        }
+        separator = ';';
+    }
+    format!(
+        "{:02}:{:02}:{:02}{}{:02}",
+        frames / (fps * 3600) % 24,
+        frames / (fps * 60) % 60,
+        frames / fps % 60,
+        separator,
+        frames % fps
+    )
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A file:// URL, percent-encoding everything but unreserved characters and path separators
+fn file_url(path: &Path) -> String {
+    let mut url = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+fn write_fcpxml(path: &Path, timeline: &Timeline) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let tc_format = match timeline.drop_frame {
+        true => "DF",
+        false => "NDF",
+    };
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, "<!DOCTYPE fcpxml>")?;
+    // media-rep, which points the assets at the chapters, is only in FCPXML 1.10 and newer
+    writeln!(out, r#"<fcpxml version="1.10">"#)?;
+    writeln!(out, "  <resources>")?;
+    writeln!(
+        out,
+        r#"    <format id="r0" frameDuration="{}" width="{}" height="{}"/>"#,
+        timeline.fcpxml_time(1),
+        timeline.width,
+        timeline.height
+    )?;
+    for (i, clip) in timeline.clips.iter().enumerate() {
+        writeln!(
+            out,
+            r#"    <asset id="r{}" name="{}" start="{}" duration="{}" hasVideo="1" hasAudio="{}" format="r0">"#,
+            i + 1,
+            xml_escape(&clip.name),
+            timeline.fcpxml_time(clip.start_frame),
+            timeline.fcpxml_time(clip.frames),
+            clip.has_audio as u8
+        )?;
+        writeln!(
+            out,
+            r#"      <media-rep kind="original-media" src="{}"/>"#,
+            xml_escape(&file_url(&clip.path))
+        )?;
+        writeln!(out, "    </asset>")?;
+    }
+    writeln!(out, "  </resources>")?;
+    writeln!(out, "  <library>")?;
+    writeln!(out, r#"    <event name="{}">"#, xml_escape(&timeline.name))?;
+    writeln!(
+        out,
+        r#"      <project name="{}">"#,
+        xml_escape(&timeline.name)
+    )?;
+    writeln!(
+        out,
+        r#"        <sequence format="r0" duration="{}" tcStart="0s" tcFormat="{}">"#,
+        timeline.fcpxml_time(timeline.total_frames()),
+        tc_format
+    )?;
+    writeln!(out, "          <spine>")?;
+    let mut offset = 0;
+    for (i, clip) in timeline.clips.iter().enumerate() {
+        writeln!(
+            out,
+            r#"            <asset-clip ref="r{}" name="{}" offset="{}" start="{}" duration="{}" tcFormat="{}"/>"#,
+            i + 1,
+            xml_escape(&clip.name),
+            timeline.fcpxml_time(offset),
+            timeline.fcpxml_time(clip.start_frame),
+            timeline.fcpxml_time(clip.frames),
+            tc_format
+        )?;
+        offset += clip.frames;
+    }
+    writeln!(out, "          </spine>")?;
+    writeln!(out, "        </sequence>")?;
+    writeln!(out, "      </project>")?;
+    writeln!(out, "    </event>")?;
+    writeln!(out, "  </library>")?;
+    writeln!(out, "</fcpxml>")?;
+    out.flush()
+}
+
+/// CMX 3600. Reel names are limited to 8 characters, so each event names its file in comments.
+fn write_edl(path: &Path, timeline: &Timeline) -> Result<(), Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "TITLE: {}", timeline.name)?;
+    match timeline.drop_frame {
+        true => writeln!(out, "FCM: DROP FRAME\n")?,
+        false => writeln!(out, "FCM: NON-DROP FRAME\n")?,
+    }
+    let mut record_start = 0;
+    for (i, clip) in timeline.clips.iter().enumerate() {
+        let tracks = match clip.has_audio {
+            true => "AA/V",
+            false => "V",
+        };
+        let reel: String = Path::new(&clip.name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().chars().take(8).collect())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "{:03}  {:<8} {:<5} C        {} {} {} {}",
+            i + 1,
+            reel,
+            tracks,
+            timeline.timecode(clip.start_frame),
+            timeline.timecode(clip.start_frame + clip.frames),
+            timeline.timecode(record_start),
+            timeline.timecode(record_start + clip.frames)
+        )?;
+        writeln!(out, "* FROM CLIP NAME: {}", clip.name)?;
+        writeln!(out, "* SOURCE FILE: {}\n", clip.path.display())?;
+        record_start += clip.frames;
+    }
+    out.flush()
+}
+
+fn otio_time(timeline: &Timeline, frames: u64) -> serde_json::Value {
+    json!({
+        "OTIO_SCHEMA": "RationalTime.1",
+        "rate": timeline.frame_rate(),
+        "value": frames as f64,
+    })
+}
+
+fn otio_range(timeline: &Timeline, start: u64, frames: u64) -> serde_json::Value {
+    json!({
+        "OTIO_SCHEMA": "TimeRange.1",
+        "start_time": otio_time(timeline, start),
+        "duration": otio_time(timeline, frames),
+    })
+}
+
+fn otio_track(timeline: &Timeline, kind: &str, clips: &[&TimelineClip]) -> serde_json::Value {
+    let clips: Vec<serde_json::Value> = clips
+        .iter()
+        .map(|clip| {
+            json!({
+                "OTIO_SCHEMA": "Clip.1",
+                "name": clip.name,
+                "source_range": otio_range(timeline, clip.start_frame, clip.frames),
+                "media_reference": {
+                    "OTIO_SCHEMA": "ExternalReference.1",
+                    "name": clip.name,
+                    "target_url": file_url(&clip.path),
+                    "available_range": otio_range(timeline, clip.start_frame, clip.frames),
+                    "metadata": {},
+                },
+                "effects": [],
+                "markers": [],
+                "enabled": true,
+                "metadata": {},
+            })
+        })
+        .collect();
+    json!({
+        "OTIO_SCHEMA": "Track.1",
+        "name": kind,
+        "kind": kind,
+        "children": clips,
+        "source_range": null,
+        "effects": [],
+        "markers": [],
+        "enabled": true,
+        "metadata": {},
+    })
+}
+
+fn write_otio(path: &Path, timeline: &Timeline) -> Result<(), Error> {
+    let all_clips: Vec<&TimelineClip> = timeline.clips.iter().collect();
+    let mut tracks = vec![otio_track(timeline, "Video", &all_clips)];
+    if timeline.clips.iter().all(|c| c.has_audio) {
+        tracks.push(otio_track(timeline, "Audio", &all_clips));
+    }
+    let otio = json!({
+        "OTIO_SCHEMA": "Timeline.1",
+        "name": timeline.name,
+        "global_start_time": otio_time(timeline, 0),
+        "tracks": {
+            "OTIO_SCHEMA": "Stack.1",
+            "name": "tracks",
+            "children": tracks,
+            "source_range": null,
+            "effects": [],
+            "markers": [],
+            "enabled": true,
+            "metadata": {},
+        },
+        "metadata": {},
+    });
+    serde_json::to_writer_pretty(File::create(path)?, &otio).map_err(Error::other)
+}
+
+/// Writes a timeline for every video where it would have been assembled: its planned output path,
+/// with an .fcpxml, .edl or .otio extension. Returns the files written.
+pub fn export_timelines(
+    videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    output_paths: &HashMap<VideoId, PathBuf>,
+    format: TimelineFormat,
+    summary: &mut RunSummary,
+) -> Vec<PathBuf> {
+    let mut written = Vec::new();
+    let mut video_ids: Vec<&VideoId> = videos.keys().collect();
+    video_ids.sort();
+    for video in video_ids {
+        let timeline = match read_timeline(video, &videos[video]) {
+            Ok(timeline) => timeline,
+            Err(e) => {
+                warn!("Could not read {}: {}", video, e);
+                summary.record(video.clone(), "timeline", Outcome::Failed(e.to_string()));
+                continue;
+            }
+        };
+
+        let extension = match format {
+            TimelineFormat::Fcpxml => "fcpxml",
+            TimelineFormat::Edl => "edl",
+            TimelineFormat::Otio => "otio",
+        };
+        let output_path = output_paths[video].with_extension(extension);
+        let result = match format {
+            TimelineFormat::Fcpxml => write_fcpxml(&output_path, &timeline),
+            TimelineFormat::Edl => write_edl(&output_path, &timeline),
+            TimelineFormat::Otio => write_otio(&output_path, &timeline),
+        };
+        match result {
+            Ok(()) => {
+                info!(
+                    "Wrote a {} chapter timeline to {}",
+                    timeline.clips.len(),
+                    output_path.to_string_lossy().blue().bold()
+                );
+                written.push(output_path);
+                summary.record(video.clone(), "timeline", Outcome::Succeeded);
+            }
+            Err(e) => {
+                warn!("Failed to write {}: {}", output_path.display(), e);
+                summary.record(video.clone(), "timeline", Outcome::Failed(e.to_string()));
+            }
+        }
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+    use std::fs;
+
+    #[test]
+    fn formats_timecode() {
+        assert_eq!(format_timecode(108_000 + 59, 30, false), "01:00:01:29");
+        // 29.97 drop frame skips frames 0 and 1 of every minute but every tenth
+        assert_eq!(format_timecode(1799, 30, true), "00:00:59;29");
+        assert_eq!(format_timecode(1800, 30, true), "00:01:00;02");
+        assert_eq!(format_timecode(17_982, 30, true), "00:10:00;00");
+        assert_eq!(format_timecode(3596, 60, true), "00:00:59;56");
+        assert_eq!(format_timecode(3600, 60, true), "00:01:00;04");
+    }
+
+    #[test]
+    fn chapters_play_back_to_back_from_their_timecode() {
+        let dir = TempDir::new();
+        let mut chapters = Vec::new();
+        for (chapter, start_frame) in [(1, 108_000), (2, 108_060)] {
+            let path = dir.join(&format!("GH0{}1234.MP4", chapter));
+            write_mp4(
+                &path,
+                &[
+                    FixtureTrack::video(60, 30),
+                    FixtureTrack::timecode(start_frame, 60),
+                ],
+                Vec::new(),
+            );
+            chapters.push(GoProChapteredVideoFile {
+                abs_path: path,
+                video_number: 1234,
+                chapter,
+                camera: None,
+                session: None,
+                metadata: None,
+            });
+        }
+        let video = chapters[0].video_id();
+        let timeline = read_timeline(&video, &chapters).unwrap();
+        assert_eq!((timeline.width, timeline.height), (1920, 1080));
+        assert_eq!(timeline.total_frames(), 120);
+
+        let edl = dir.join("GoPro_1234.edl");
+        write_edl(&edl, &timeline).unwrap();
+        let events: Vec<String> = fs::read_to_string(&edl)
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with('0'))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "001  GH011234 V     C        01:00:00:00 01:00:02:00 00:00:00:00 00:00:02:00",
+                "002  GH021234 V     C        01:00:02:00 01:00:04:00 00:00:02:00 00:00:04:00",
+            ]
+        );
+
+        let fcpxml = dir.join("GoPro_1234.fcpxml");
+        write_fcpxml(&fcpxml, &timeline).unwrap();
+        let fcpxml = fs::read_to_string(&fcpxml).unwrap();
+        let second_clip = fcpxml
+            .lines()
+            .find(|line| line.contains(r#"<asset-clip ref="r2""#))
+            .unwrap();
+        assert!(second_clip.contains(&format!(
+            r#"offset="{}" start="{}" duration="{}""#,
+            timeline.fcpxml_time(60),
+            timeline.fcpxml_time(108_060),
+            timeline.fcpxml_time(60)
+        )));
+    }
+}
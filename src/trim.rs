@@ -0,0 +1,370 @@
+// Lossless trimming of videos to a start and end time, snapped to the nearest keyframes so nothing
+// has to be re-encoded.
+//
+// When merging, only the first and last chapters that are kept get trimmed (to temporary files
+// next to the output, removed once they're merged or anything fails), and the result is merged as
+// usual. The untrimmed video is never written.
+
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use log::{error, info};
+
+use crate::cli::{TrimRange, VideoTrim};
+use crate::gopro::VideoId;
+use crate::mp4::Movie;
+use crate::remux::RemuxSource;
+
+/// The files to merge for a trimmed video, and the part of the untrimmed timeline they cover
+#[derive(Debug, Clone)]
+pub struct TrimmedChapters {
+    pub files: Vec<PathBuf>,
+    /// Seconds from the start of the untrimmed video, after snapping to keyframes
+    pub start: f64,
+    /// `f64::INFINITY` if the end wasn't trimmed
+    pub end: f64,
+    temporary_files: Vec<PathBuf>,
+}
+
+impl Drop for TrimmedChapters {
+    fn drop(&mut self) {
+        for file in &self.temporary_files {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+/// The --trim for a video. A trim that names the video applies only to it, one that only gives the
+/// number applies to every video with that number (see ambiguous_trims).
+pub fn find_trim(trims: &[VideoTrim], video: &VideoId) -> Option<TrimRange> {
+    trims
+        .iter()
+        .find(|trim| match &trim.name {
+            Some(name) => *name == video.to_string(),
+            None => trim.video_number == video.video_number,
+        })
+        .map(|trim| trim.range)
+}
+
+/// Describes each --trim given by number when more than one camera or recording used that number,
+/// since it would otherwise cut all of them the same way
+pub fn ambiguous_trims<'a>(
+    trims: &[VideoTrim],
+    videos: impl Iterator<Item = &'a VideoId> + Clone,
+) -> Vec<String> {
+    trims
+        .iter()
+        .filter(|trim| trim.name.is_none())
+        .filter_map(|trim| {
+            let mut matching: Vec<String> = videos
+                .clone()
+                .filter(|video| video.video_number == trim.video_number)
+                .map(|video| video.to_string())
+                .collect();
+            if matching.len() < 2 {
+                return None;
+            }
+            matching.sort();
+            Some(format!(
+                "--trim {} matches {}, give the name of the one to trim instead, e.g. --trim {}=...",
+                trim.video_number,
+                matching.join(", "),
+                matching[0]
+            ))
+        })
+        .collect()
+}
+
+/// Trims the chapters of a video, as if they were already merged. Chapters entirely outside the
+/// range are dropped, and the chapters the range starts and ends in are cut to temporary files in
+/// `temp_dir`.
+pub fn trim_chapters(
+    chapters: &[PathBuf],
+    range: TrimRange,
+    temp_dir: &Path,
+    name: &str,
+) -> Result<TrimmedChapters, Error> {
+    let start = range.start.unwrap_or(0.0);
+    let end = range.end.unwrap_or(f64::INFINITY);
+    let mut trimmed = TrimmedChapters {
+        files: Vec::new(),
+        start: f64::INFINITY,
+        end: f64::INFINITY,
+        temporary_files: Vec::new(),
+    };
+
+    // Where the last file kept ends, on the untrimmed timeline
+    let mut kept_end = 0.0;
+    let mut chapter_start = 0.0;
+    for (index, chapter) in chapters.iter().enumerate() {
+        let chapter_duration = Movie::read(chapter)?.duration_secs();
+        let chapter_end = chapter_start + chapter_duration;
+        let offset = chapter_start;
+        chapter_start = chapter_end;
+        if chapter_end <= start || offset >= end {
+            continue;
+        }
+
+        let local_start = (start - offset).max(0.0);
+        let local_end = match end < chapter_end {
+            true => end - offset,
+            false => f64::INFINITY,
+        };
+        if local_start == 0.0 && local_end.is_infinite() {
+            trimmed.start = trimmed.start.min(offset);
+            trimmed.files.push(chapter.clone());
+            kept_end = chapter_end;
+            continue;
+        }
+
+        let source = RemuxSource::open(chapter)?;
+        let cut_start = match local_start > 0.0 {
+            true => source.snap_to_keyframe(local_start)?,
+            false => 0.0,
+        };
+        let cut_end = match local_end.is_finite() {
+            true => source.snap_to_keyframe(local_end)?,
+            false => f64::INFINITY,
+        };
+        if cut_end <= cut_start {
+            continue;
+        }
+        // Unique, so a cut left behind by an interrupted run can't get in the way
+        let temp_file = temp_dir.join(format!(
+            ".{}_trim{}-{}.mp4",
+            name,
+            index + 1,
+            uuid::Uuid::new_v4()
+        ));
+        // Returning early drops `trimmed`, which removes the cuts written so far
+        source.write_range(cut_start, cut_end, &temp_file)?;
+        trimmed.temporary_files.push(temp_file.clone());
+        trimmed.start = trimmed.start.min(offset + cut_start);
+        kept_end = match cut_end.is_finite() {
+            true => offset + cut_end,
+            false => chapter_end,
+        };
+        trimmed.files.push(temp_file);
+    }
+
+    if trimmed.files.is_empty() {
+        return Err(Error::other("nothing is left after trimming"));
+    }
+    // The end can land on a chapter boundary, dropping the chapters after it without cutting any
+    if kept_end < chapter_start {
+        trimmed.end = kept_end;
+    }
+    Ok(trimmed)
+}
+
+/// Trims a single file to `output`. Returns the (start, end) that was kept, after snapping to
+/// keyframes, with an end of `f64::INFINITY` if the end wasn't trimmed.
+pub fn trim_video(input: &Path, output: &Path, range: TrimRange) -> Result<(f64, f64), Error> {
+    let source = RemuxSource::open(input)?;
+    if range.start.is_some_and(|start| start >= source.duration()) {
+        return Err(Error::other(format!(
+            "the video is only {:.3}s long",
+            source.duration()
+        )));
+    }
+    let start = match range.start {
+        Some(start) => source.snap_to_keyframe(start)?,
+        None => 0.0,
+    };
+    let end = match range.end {
+        Some(end) if end < source.duration() => source.snap_to_keyframe(end)?,
+        _ => f64::INFINITY,
+    };
+    if end <= start {
+        return Err(Error::other("nothing is left after trimming"));
+    }
+    source.write_range(start, end, output)?;
+    Ok((start, end))
+}
+
+/// e.g. GoPro_1.mp4 -> GoPro_1_trimmed.mp4
+pub fn gen_trimmed_path(input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = input
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "mp4".to_string());
+    input.with_file_name(format!("{}_trimmed.{}", stem, extension))
+}
+
+/// Trims `input` to `output`, logging what was kept or why it couldn't be trimmed
+pub fn trim_and_report(input: &Path, output: &Path, range: TrimRange) -> Result<(), Error> {
+    match trim_video(input, output, range) {
+        Ok((start, end)) => {
+            info!(
+                "Trimmed {} to {} (kept {:.3}s to {})",
+                input.to_string_lossy().green().bold(),
+                output.to_string_lossy().blue().bold(),
+                start,
+                match end.is_finite() {
+                    true => format!("{:.3}s", end),
+                    false => "the end".to_string(),
+                }
+            );
+            Ok(())
+        }
+        Err(e) => {
+            error!(
+                "{} {}: {}",
+                "Failed to trim".red().bold(),
+                input.display(),
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::read_sample;
+    use crate::test_fixtures::{write_mp4, FixtureTrack, TempDir};
+    use std::fs::File;
+
+    fn video(camera: Option<&str>) -> VideoId {
+        VideoId {
+            video_number: 7322,
+            camera: camera.map(str::to_string),
+            session: None,
+        }
+    }
+
+    fn trim(name: Option<&str>, start: f64) -> VideoTrim {
+        VideoTrim {
+            video_number: 7322,
+            name: name.map(str::to_string),
+            range: TrimRange {
+                start: Some(start),
+                end: None,
+            },
+        }
+    }
+
+    #[test]
+    fn named_trims_only_apply_to_that_video() {
+        let trims = [trim(Some("GoPro_7322_A"), 10.0)];
+        assert_eq!(
+            find_trim(&trims, &video(Some("A"))).unwrap().start,
+            Some(10.0)
+        );
+        assert_eq!(find_trim(&trims, &video(Some("B"))), None);
+        assert_eq!(find_trim(&trims, &video(None)), None);
+    }
+
+    #[test]
+    fn numbered_trims_are_ambiguous_across_cameras() {
+        let trims = [trim(None, 10.0)];
+        let one_camera = [video(None)];
+        assert!(ambiguous_trims(&trims, one_camera.iter()).is_empty());
+        assert_eq!(find_trim(&trims, &one_camera[0]).unwrap().start, Some(10.0));
+
+        let two_cameras = [video(Some("B")), video(Some("A"))];
+        let ambiguous = ambiguous_trims(&trims, two_cameras.iter());
+        assert_eq!(ambiguous.len(), 1);
+        assert!(ambiguous[0].contains("GoPro_7322_A, GoPro_7322_B"));
+
+        let named = [trim(Some("GoPro_7322_A"), 10.0)];
+        assert!(ambiguous_trims(&named, two_cameras.iter()).is_empty());
+    }
+
+    /// Three 2 second chapters with a keyframe every second. Trims snap to the nearest one.
+    fn write_chapters(dir: &TempDir) -> Vec<PathBuf> {
+        (1..=3)
+            .map(|chapter| {
+                let path = dir.join(&format!("GH0{}7322.MP4", chapter));
+                write_mp4(&path, &[FixtureTrack::video(60, 30)], Vec::new());
+                path
+            })
+            .collect()
+    }
+
+    /// The first byte of every frame, which the fixtures set to the frame number
+    fn frames(path: &Path) -> Vec<u8> {
+        let movie = Movie::read(path).unwrap();
+        let mut reader = File::open(path).unwrap();
+        movie
+            .video_track()
+            .unwrap()
+            .samples()
+            .unwrap()
+            .iter()
+            .map(|sample| read_sample(&mut reader, sample).unwrap()[0])
+            .collect()
+    }
+
+    fn range(start: Option<f64>, end: Option<f64>) -> TrimRange {
+        TrimRange { start, end }
+    }
+
+    #[test]
+    fn trims_the_first_and_last_chapters_kept() {
+        let dir = TempDir::new();
+        let chapters = write_chapters(&dir);
+        let trimmed = trim_chapters(
+            &chapters,
+            range(Some(1.4), Some(3.4)),
+            dir.path(),
+            "GoPro_7322",
+        )
+        .unwrap();
+        assert_eq!((trimmed.start, trimmed.end), (1.0, 3.0));
+        assert_eq!(trimmed.files.len(), 2);
+        assert_eq!(frames(&trimmed.files[0]), (30..60).collect::<Vec<u8>>());
+        assert_eq!(frames(&trimmed.files[1]), (0..30).collect::<Vec<u8>>());
+
+        let temporary_files = trimmed.files.clone();
+        drop(trimmed);
+        assert!(temporary_files.iter().all(|file| !file.exists()));
+        assert!(chapters.iter().all(|chapter| chapter.exists()));
+    }
+
+    #[test]
+    fn untouched_chapters_are_used_as_they_are() {
+        let dir = TempDir::new();
+        let chapters = write_chapters(&dir);
+
+        let trimmed =
+            trim_chapters(&chapters, range(Some(1.0), None), dir.path(), "GoPro_7322").unwrap();
+        assert_eq!((trimmed.start, trimmed.end), (1.0, f64::INFINITY));
+        assert_eq!(trimmed.files[1..], chapters[1..]);
+
+        // Ending on a chapter boundary drops the chapters after it without cutting anything
+        let trimmed =
+            trim_chapters(&chapters, range(None, Some(4.0)), dir.path(), "GoPro_7322").unwrap();
+        assert_eq!((trimmed.start, trimmed.end), (0.0, 4.0));
+        assert_eq!(trimmed.files, chapters[..2]);
+        let trimmed =
+            trim_chapters(&chapters, range(None, Some(4.3)), dir.path(), "GoPro_7322").unwrap();
+        assert_eq!((trimmed.start, trimmed.end), (0.0, 4.0));
+        assert_eq!(trimmed.files, chapters[..2]);
+
+        assert!(
+            trim_chapters(&chapters, range(Some(9.0), None), dir.path(), "GoPro_7322").is_err()
+        );
+    }
+
+    #[test]
+    fn trims_a_single_video_to_keyframes() {
+        let dir = TempDir::new();
+        let input = dir.join("GoPro_7322.mp4");
+        let output = gen_trimmed_path(&input);
+        assert_eq!(output, dir.join("GoPro_7322_trimmed.mp4"));
+        write_mp4(&input, &[FixtureTrack::video(90, 30)], Vec::new());
+
+        assert_eq!(
+            trim_video(&input, &output, range(Some(1.4), Some(2.4))).unwrap(),
+            (1.0, 2.0)
+        );
+        assert_eq!(frames(&output), (30..60).collect::<Vec<u8>>());
+        assert!(trim_video(&input, &dir.join("late.mp4"), range(Some(3.0), None)).is_err());
+        assert!(trim_video(&input, &dir.join("short.mp4"), range(Some(1.2), Some(1.4))).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+// An interactive alternative to the all-or-nothing "Proceed? (y/n)": lists every video with its
+// chapter count, duration, size and date, and lets you pick which ones to assemble, what to do with
+// each, and what to name the output.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, IsTerminal, Stderr};
+
+use indicatif::HumanBytes;
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use time::macros::format_description;
+use time::PrimitiveDateTime;
+
+use crate::cli::SingleChapterMode;
+use crate::gopro::{GoProChapteredVideoFile, VideoId};
+use crate::mp4::MovieHeader;
+use crate::printing::format_duration;
+
+/// Drawn on stderr, since stdout may be carrying --events
+type StderrTerminal = Terminal<CrosstermBackend<Stderr>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoAction {
+    Merge,
+    /// Rename, copy or link a single chapter video
+    Single(SingleChapterMode),
+    Skip,
+}
+
+impl VideoAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VideoAction::Merge => "merge",
+            VideoAction::Single(mode) => mode.as_str(),
+            VideoAction::Skip => "skip",
+        }
+    }
+}
+
+/// What to do with one video, as chosen in the TUI
+#[derive(Debug, Clone)]
+pub struct VideoChoice {
+    pub video: VideoId,
+    pub action: VideoAction,
+    /// The output's file name, e.g. GoPro_7322.mp4
+    pub output_name: String,
+}
+
+struct VideoRow {
+    choice: VideoChoice,
+    /// The action to go back to when a skipped video is selected again
+    last_action: VideoAction,
+    chapters: usize,
+    duration: Option<f64>,
+    bytes: u64,
+    date: String,
+}
+
+impl VideoRow {
+    fn actions(&self) -> &'static [VideoAction] {
+        match self.chapters > 1 {
+            true => &[VideoAction::Merge, VideoAction::Skip],
+            false => &[
+                VideoAction::Single(SingleChapterMode::Rename),
+                VideoAction::Single(SingleChapterMode::Copy),
+                VideoAction::Single(SingleChapterMode::Hardlink),
+                VideoAction::Single(SingleChapterMode::Symlink),
+                VideoAction::Single(SingleChapterMode::Reflink),
+                VideoAction::Skip,
+            ],
+        }
+    }
+
+    fn set_action(&mut self, action: VideoAction) {
+        if action != VideoAction::Skip {
+            self.last_action = action;
+        }
+        self.choice.action = action;
+    }
+}
+
+enum Mode {
+    Browsing,
+    /// Editing the selected video's output name
+    Editing(String),
+}
+
+struct App {
+    rows: Vec<VideoRow>,
+    state: TableState,
+    mode: Mode,
+    message: Option<String>,
+}
+
+/// Shows the TUI. Returns what to do with every video, or None if it was cancelled.
+pub fn choose_videos(
+    multichapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_videos: &HashMap<VideoId, Vec<GoProChapteredVideoFile>>,
+    single_chapter_mode: SingleChapterMode,
+) -> io::Result<Option<Vec<VideoChoice>>> {
+    if !io::stderr().is_terminal() {
+        return Err(io::Error::other("--interactive needs a terminal"));
+    }
+    let single_chapter_action = VideoAction::Single(single_chapter_mode);
+    let mut rows: Vec<VideoRow> = multichapter_videos
+        .iter()
+        .map(|(video, chapters)| video_row(video, chapters, VideoAction::Merge))
+        .chain(
+            single_chapter_videos
+                .iter()
+                .map(|(video, chapters)| video_row(video, chapters, single_chapter_action)),
+        )
+        .collect();
+    rows.sort_by(|a, b| a.choice.video.cmp(&b.choice.video));
+
+    let mut app = App {
+        state: TableState::default().with_selected(match rows.is_empty() {
+            true => None,
+            false => Some(0),
+        }),
+        rows,
+        mode: Mode::Browsing,
+        message: None,
+    };
+    let mut terminal = init_terminal()?;
+    let result = run(&mut terminal, &mut app);
+    restore_terminal();
+    Ok(result?.then(|| app.rows.into_iter().map(|row| row.choice).collect()))
+}
+
+/// Like ratatui::init, on stderr
+fn init_terminal() -> io::Result<StderrTerminal> {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        hook(info);
+    }));
+    enable_raw_mode()?;
+    execute!(io::stderr(), EnterAlternateScreen)?;
+    Terminal::new(CrosstermBackend::new(io::stderr()))
+}
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stderr(), LeaveAlternateScreen);
+}
+
+fn video_row(
+    video: &VideoId,
+    chapters: &[GoProChapteredVideoFile],
+    action: VideoAction,
+) -> VideoRow {
+    let duration = chapters
+        .iter()
+        .map(|chapter| MovieHeader::read(&chapter.abs_path).map(|header| header.duration_secs()))
+        .sum::<Result<f64, _>>()
+        .ok();
+    let bytes = chapters
+        .iter()
+        .filter_map(|chapter| chapter.abs_path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    // When recording started, since copying a card changes the files' modification times
+    let date = chapters
+        .first()
+        .and_then(|chapter| chapter.metadata.as_ref())
+        .and_then(|metadata| metadata.recorded)
+        .map(format_date)
+        .unwrap_or_default();
+    VideoRow {
+        choice: VideoChoice {
+            video: video.clone(),
+            action,
+            output_name: format!("{}.mp4", video),
+        },
+        last_action: action,
+        chapters: chapters.len(),
+        duration,
+        bytes,
+        date,
+    }
+}
+
+/// Camera wall clock time, like the rest of the tool
+fn format_date(time: PrimitiveDateTime) -> String {
+    time.format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+        .unwrap_or_default()
+}
+
+/// Returns whether the choices were confirmed
+fn run(terminal: &mut StderrTerminal, app: &mut App) -> io::Result<bool> {
+    loop {
+        terminal.draw(|frame| draw(frame, app))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        app.message = None;
+        let selected = app.state.selected();
+
+        if let Mode::Editing(name) = &mut app.mode {
+            match key.code {
+                KeyCode::Char(c) => name.push(c),
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Esc => app.mode = Mode::Browsing,
+                KeyCode::Enter => {
+                    let Some(index) = selected else {
+                        continue;
+                    };
+                    let name = match output_name(name) {
+                        Ok(name) => name,
+                        Err(e) => {
+                            app.message = Some(e.to_string());
+                            continue;
+                        }
+                    };
+                    let row = &mut app.rows[index];
+                    row.choice.output_name =
+                        name.unwrap_or_else(|| format!("{}.mp4", row.choice.video));
+                    app.mode = Mode::Browsing;
+                }
+                _ => (),
+            }
+            continue;
+        }
+
+        match (key.code, selected) {
+            (KeyCode::Char('q') | KeyCode::Esc, _) => return Ok(false),
+            (KeyCode::Up | KeyCode::Char('k'), _) => app.state.select_previous(),
+            (KeyCode::Down | KeyCode::Char('j'), _) => app.state.select_next(),
+            (KeyCode::Char(' '), Some(index)) => {
+                let row = &mut app.rows[index];
+                match row.choice.action {
+                    VideoAction::Skip => row.set_action(row.last_action),
+                    _ => row.set_action(VideoAction::Skip),
+                }
+            }
+            (KeyCode::Char('a') | KeyCode::Tab, Some(index)) => {
+                let row = &mut app.rows[index];
+                let actions = row.actions();
+                let current = actions
+                    .iter()
+                    .position(|a| *a == row.choice.action)
+                    .unwrap_or_default();
+                row.set_action(actions[(current + 1) % actions.len()]);
+            }
+            (KeyCode::Char('A'), _) => {
+                let select = app
+                    .rows
+                    .iter()
+                    .any(|row| row.choice.action == VideoAction::Skip);
+                for row in &mut app.rows {
+                    match select {
+                        true => row.set_action(row.last_action),
+                        false => row.set_action(VideoAction::Skip),
+                    }
+                }
+            }
+            (KeyCode::Char('e'), Some(index)) => {
+                app.mode = Mode::Editing(app.rows[index].choice.output_name.clone());
+            }
+            (KeyCode::Enter, _) => match duplicate_output_name(&app.rows) {
+                Some(name) => {
+                    app.message = Some(format!("More than one video would be written to {}", name))
+                }
+                None => return Ok(true),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Cleans up an edited output name, adding .mp4 if there's no extension. Ok(None) means it was
+/// cleared, so the default name should be used. Errors say why the name can't be used.
+fn output_name(name: &str) -> Result<Option<String>, &'static str> {
+    let name = name.trim();
+    if name.contains(['/', '\\']) {
+        return Err("Output names can't contain a path separator");
+    }
+    if name == "." || name == ".." {
+        return Err("Output names can't be . or ..");
+    }
+    if name.chars().any(char::is_control) {
+        return Err("Output names can't contain control characters");
+    }
+    Ok(match (name.is_empty(), name.contains('.')) {
+        (true, _) => None,
+        (false, true) => Some(name.to_string()),
+        (false, false) => Some(format!("{}.mp4", name)),
+    })
+}
+
+fn duplicate_output_name(rows: &[VideoRow]) -> Option<String> {
+    let mut seen = HashSet::new();
+    rows.iter()
+        .filter(|row| row.choice.action != VideoAction::Skip)
+        .map(|row| row.choice.output_name.to_lowercase())
+        .find(|name| !seen.insert(name.clone()))
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [table_area, footer_area] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+
+    let selected_count = app
+        .rows
+        .iter()
+        .filter(|row| row.choice.action != VideoAction::Skip)
+        .count();
+    let header = Row::new([
+        "", "Video", "Chapters", "Duration", "Size", "Date", "Action", "Output",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let rows = app.rows.iter().map(|row| {
+        let skipped = row.choice.action == VideoAction::Skip;
+        Row::new([
+            match skipped {
+                true => "[ ]".to_string(),
+                false => "[x]".to_string(),
+            },
+            row.choice.video.to_string(),
+            row.chapters.to_string(),
+            row.duration.map(format_duration).unwrap_or("?".to_string()),
+            HumanBytes(row.bytes).to_string(),
+            row.date.clone(),
+            row.choice.action.as_str().to_string(),
+            row.choice.output_name.clone(),
+        ])
+        .style(match skipped {
+            true => Style::new().fg(Color::DarkGray),
+            false => Style::new(),
+        })
+    });
+    // Names get longer when a camera or recording has to be told apart
+    let video_width = app
+        .rows
+        .iter()
+        .map(|row| row.choice.video.to_string().len())
+        .max()
+        .unwrap_or_default()
+        .max(5);
+    let widths = [
+        Constraint::Length(3),
+        Constraint::Length(video_width as u16),
+        Constraint::Length(8),
+        Constraint::Length(9),
+        Constraint::Length(11),
+        Constraint::Length(16),
+        Constraint::Length(8),
+        Constraint::Fill(1),
+    ];
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(format!(
+            " Select videos to assemble ({} of {}) ",
+            selected_count,
+            app.rows.len()
+        )))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, table_area, &mut app.state);
+
+    let footer = match (&app.mode, &app.message) {
+        (Mode::Editing(name), _) => Line::from(format!("Output name: {}_", name)),
+        (_, Some(message)) => Line::from(message.clone()).red(),
+        _ => Line::from(
+            "↑/↓ move  space select  a action  e rename output  A all/none  enter assemble  q quit",
+        ),
+    };
+    let footer_hint = match app.mode {
+        Mode::Editing(_) => " enter save  esc cancel ",
+        Mode::Browsing => "",
+    };
+    frame.render_widget(
+        Paragraph::new(footer).block(Block::bordered().title(footer_hint)),
+        footer_area,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_names_get_an_extension() {
+        assert_eq!(
+            output_name("  Ski day "),
+            Ok(Some("Ski day.mp4".to_string()))
+        );
+        assert_eq!(output_name("ski.mov"), Ok(Some("ski.mov".to_string())));
+        assert_eq!(output_name(""), Ok(None));
+    }
+
+    #[test]
+    fn output_names_stay_in_the_output_directory() {
+        assert!(output_name("../ski").is_err());
+        assert!(output_name("a\\b").is_err());
+        assert!(output_name(".").is_err());
+        assert!(output_name("..").is_err());
+        assert!(output_name("ski\nday").is_err());
+    }
+}
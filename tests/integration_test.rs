@@ -1,3 +1,6 @@
+// The helpers from before clippy ran on the tests are kept as they were
+#![allow(clippy::ptr_arg, clippy::let_and_return, clippy::useless_vec)]
+
 use assert_cmd::prelude::*;
 use merkle_hash::{Algorithm, Encodable, MerkleTree};
 use std::path::PathBuf;
@@ -45,16 +48,36 @@ pub(crate) fn teardown() {
     }
 }
 
+// The other tests get a directory of their own outside actual_output, since tests run in parallel
+pub(crate) fn get_path_to_test_dir(test_name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "{}-{}-{}",
+        env!("CARGO_PKG_NAME"),
+        test_name,
+        std::process::id()
+    ))
+}
+
+// Keeps the import history and log file out of the real XDG directories
+pub(crate) fn assembler_command(test_name: &str) -> Command {
+    let xdg_home = get_path_to_test_dir(test_name).join("xdg");
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    cmd.env("XDG_DATA_HOME", xdg_home.join("data"))
+        .env("XDG_CACHE_HOME", xdg_home.join("cache"));
+    cmd
+}
+
 #[test]
 fn test_run_on_dir() {
     self::setup();
-    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!(env!("CARGO_PKG_NAME")));
+    let mut cmd = assembler_command("test_run_on_dir");
     cmd.arg("--input")
         .arg(get_path_to_source_videos())
         .arg("--output")
         .arg(get_path_to_test_output())
         .arg("--yes")
-        .arg("--copy-single-chapter-instead-of-rename");
+        .arg("--copy-single-chapter-instead-of-rename")
+        .arg("--no-manifest");
     let output = cmd.unwrap();
     print!("{:#?}", output);
     // Check that actual_output matches expected_output
@@ -68,3 +91,67 @@ fn test_run_on_dir() {
 
     self::teardown();
 }
+
+#[test]
+fn test_manifest_verifies_output() {
+    let test_name = "test_manifest_verifies_output";
+    let test_output = get_path_to_test_dir(test_name).join("output");
+    fs::create_dir_all(&test_output).unwrap();
+    let mut cmd = assembler_command(test_name);
+    cmd.arg("--input")
+        .arg(get_path_to_source_videos())
+        .arg("--output")
+        .arg(&test_output)
+        .arg("--yes")
+        .arg("--copy-single-chapter-instead-of-rename");
+    cmd.unwrap();
+    assert!(test_output.join("gopro-manifest.json").exists());
+
+    let mut verify = assembler_command(test_name);
+    verify.arg("verify-manifest").arg(&test_output);
+    verify.assert().success();
+
+    // Corrupt one of the outputs and make sure verification catches it
+    fs::write(test_output.join("GoPro_2352.mp4"), b"corrupted").unwrap();
+    let mut verify = assembler_command(test_name);
+    verify.arg("verify-manifest").arg(&test_output);
+    verify.assert().failure();
+
+    let _ = fs::remove_dir_all(get_path_to_test_dir(test_name));
+}
+
+#[test]
+fn test_already_imported_videos_are_skipped() {
+    let test_name = "test_already_imported_videos_are_skipped";
+    let test_output = get_path_to_test_dir(test_name).join("output");
+    fs::create_dir_all(&test_output).unwrap();
+    let run = |force: bool| {
+        let mut cmd = assembler_command(test_name);
+        cmd.arg("--input")
+            .arg(get_path_to_source_videos())
+            .arg("--output")
+            .arg(&test_output)
+            .arg("--yes")
+            .arg("--copy-single-chapter-instead-of-rename")
+            .arg("--no-manifest");
+        if force {
+            cmd.arg("--force");
+        }
+        cmd.unwrap();
+    };
+
+    run(false);
+    assert!(test_output.join("GoPro_7322.mp4").exists());
+    fs::remove_dir_all(&test_output).unwrap();
+
+    // Everything is in the import history now, so nothing should be written
+    run(false);
+    assert!(!test_output.join("GoPro_7322.mp4").exists());
+    assert!(!test_output.join("GoPro_2352.mp4").exists());
+
+    run(true);
+    assert!(test_output.join("GoPro_7322.mp4").exists());
+    assert!(test_output.join("GoPro_2352.mp4").exists());
+
+    let _ = fs::remove_dir_all(get_path_to_test_dir(test_name));
+}